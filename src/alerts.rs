@@ -0,0 +1,662 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::push::{self, Auth};
+use crate::snapshot::{self, Snapshot};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookKind {
+    #[default]
+    Generic,
+    Ntfy,
+    Gotify,
+    Slack,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    url: String,
+    #[serde(default)]
+    kind: WebhookKind,
+    /// Topic for ntfy
+    #[serde(default)]
+    topic: Option<String>,
+    /// Application token for Gotify
+    #[serde(default)]
+    token: Option<String>,
+    /// Body with `{{variable}}` placeholders, replacing the default of the kind
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "condition", rename_all = "snake_case")]
+pub enum Condition {
+    BatteryFull {
+        #[serde(default = "default_full")]
+        threshold: f64,
+    },
+    BatteryLow {
+        threshold: f64,
+    },
+    DeviceOffline {
+        #[serde(default = "default_offline_timeout")]
+        timeout: u64,
+    },
+    SolarProduction {
+        #[serde(default = "default_solar_threshold")]
+        threshold: f64,
+    },
+    LoginFailed,
+}
+
+fn default_full() -> f64 {
+    100.0
+}
+
+fn default_offline_timeout() -> u64 {
+    900
+}
+
+fn default_solar_threshold() -> f64 {
+    10.0
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleConfig {
+    name: String,
+    #[serde(flatten)]
+    condition: Condition,
+    /// Distance from the threshold the value has to move back before the
+    /// condition clears
+    #[serde(default)]
+    hysteresis: f64,
+    /// Minimum seconds between notifications of the rule for the same device
+    #[serde(default = "default_cooldown")]
+    cooldown: u64,
+    /// Also notify when the condition clears
+    #[serde(default)]
+    resolve: bool,
+    /// Message with `{{variable}}` placeholders, replacing the default
+    #[serde(default)]
+    message: Option<String>,
+    webhooks: Vec<String>,
+}
+
+fn default_cooldown() -> u64 {
+    3600
+}
+
+#[derive(Debug)]
+struct Event<'a> {
+    rule: &'a RuleConfig,
+    firing: bool,
+    site_id: &'a str,
+    device_sn: Option<&'a str>,
+    value: Option<f64>,
+}
+
+impl Event<'_> {
+    fn state(&self) -> &'static str {
+        match self.firing {
+            true => "firing",
+            false => "resolved",
+        }
+    }
+
+    fn default_message(&self) -> String {
+        let value = self.value.unwrap_or_default();
+        let device = self.device_sn.unwrap_or_default();
+
+        match (&self.rule.condition, self.firing) {
+            (Condition::BatteryFull { .. }, true) => {
+                format!("Battery of {device} is full ({value}%)")
+            }
+            (Condition::BatteryFull { .. }, false) => {
+                format!("Battery of {device} is no longer full ({value}%)")
+            }
+            (Condition::BatteryLow { .. }, true) => {
+                format!("Battery of {device} is low ({value}%)")
+            }
+            (Condition::BatteryLow { .. }, false) => {
+                format!("Battery of {device} recovered ({value}%)")
+            }
+            (Condition::DeviceOffline { .. }, true) => format!("Device {device} is offline"),
+            (Condition::DeviceOffline { .. }, false) => format!("Device {device} is back online"),
+            (Condition::SolarProduction { .. }, true) => {
+                format!("Solar production started ({value} W)")
+            }
+            (Condition::SolarProduction { .. }, false) => {
+                format!("Solar production stopped ({value} W)")
+            }
+            (Condition::LoginFailed, true) => "Login to the Anker cloud is failing".into(),
+            (Condition::LoginFailed, false) => "Login to the Anker cloud succeeds again".into(),
+        }
+    }
+
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut variables = vec![
+            ("rule", self.rule.name.clone()),
+            ("state", self.state().into()),
+            ("site_id", self.site_id.into()),
+            ("device_sn", self.device_sn.unwrap_or_default().into()),
+            (
+                "value",
+                self.value.map(|v| v.to_string()).unwrap_or_default(),
+            ),
+        ];
+
+        let message = match &self.rule.message {
+            Some(template) => render(template, &variables, false),
+            None => self.default_message(),
+        };
+        variables.push(("message", message));
+
+        variables
+    }
+}
+
+/// Replaces `{{name}}` placeholders, escaping the values for JSON string literals
+fn render(template: &str, variables: &[(&str, String)], json: bool) -> String {
+    variables
+        .iter()
+        .fold(template.to_string(), |rendered, (name, value)| {
+            let value = match json {
+                true => {
+                    let quoted = serde_json::Value::from(value.as_str()).to_string();
+                    quoted[1..quoted.len() - 1].to_string()
+                }
+                false => value.clone(),
+            };
+
+            rendered.replace(&format!("{{{{{name}}}}}"), &value)
+        })
+}
+
+impl WebhookConfig {
+    fn body(&self, variables: &[(&str, String)]) -> String {
+        if let Some(template) = &self.template {
+            return render(template, variables, true);
+        }
+
+        let get = |name: &str| {
+            variables
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or_default()
+        };
+
+        let body = match self.kind {
+            WebhookKind::Generic => serde_json::Value::Object(
+                variables
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.as_str().into()))
+                    .collect(),
+            ),
+            WebhookKind::Ntfy => serde_json::json!({
+                "topic": self.topic.as_deref().unwrap_or_default(),
+                "title": get("rule"),
+                "message": get("message"),
+            }),
+            WebhookKind::Gotify => serde_json::json!({
+                "title": get("rule"),
+                "message": get("message"),
+            }),
+            WebhookKind::Slack => serde_json::json!({ "text": get("message") }),
+        };
+
+        body.to_string()
+    }
+
+    fn send(&self, body: String) -> Result<(), push::Error> {
+        let mut request = push::agent(Duration::from_secs(10))
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "anker-solix-exporter");
+
+        if let (WebhookKind::Gotify, Some(token)) = (self.kind, &self.token) {
+            request = request.header("X-Gotify-Key", token);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+
+        push::check_response(self.auth.apply(request).send(body))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    active: bool,
+    notified_at: Option<u64>,
+}
+
+/// Evaluates the rules on every snapshot and notifies webhooks on transitions.
+/// Threshold conditions start from the first observed value without notifying, so
+/// restarts don't repeat notifications.
+pub struct Alerts {
+    rules: Vec<RuleConfig>,
+    webhooks: HashMap<String, WebhookConfig>,
+    /// By rule index, site id and device serial number
+    states: HashMap<(usize, String, Option<String>), State>,
+    /// Time each device was last seen per site, kept while snapshots are cleared
+    /// on reload
+    last_seen: HashMap<String, BTreeMap<String, u64>>,
+}
+
+impl Alerts {
+    pub fn new(
+        rules: &[RuleConfig],
+        webhooks: &HashMap<String, WebhookConfig>,
+    ) -> Result<Self, String> {
+        for rule in rules {
+            if let Some(name) = rule.webhooks.iter().find(|w| !webhooks.contains_key(*w)) {
+                return Err(format!("Rule {} uses unknown webhook {name}", rule.name));
+            }
+        }
+
+        Ok(Self {
+            rules: rules.to_vec(),
+            webhooks: webhooks.clone(),
+            states: HashMap::new(),
+            last_seen: HashMap::new(),
+        })
+    }
+
+    pub fn evaluate(&mut self, site_id: &str, snapshot: &Snapshot) {
+        let solarbanks = snapshot.scen_info.solarbanks();
+        let present: BTreeSet<String> = solarbanks
+            .iter()
+            .map(|solarbank| solarbank.device_sn.clone())
+            .collect();
+        let last_seen = self.last_seen.entry(site_id.into()).or_default();
+        for device_sn in &present {
+            last_seen.insert(device_sn.clone(), snapshot.fetched_at);
+        }
+        let known: Vec<String> = last_seen.keys().cloned().collect();
+
+        for index in 0..self.rules.len() {
+            match self.rules[index].condition {
+                Condition::BatteryFull { threshold } => {
+                    for solarbank in solarbanks {
+                        let soc = solarbank.battery_power as f64;
+                        let device_sn = Some(solarbank.device_sn.as_str());
+                        self.threshold(index, site_id, device_sn, soc, |active, h| match active {
+                            true => soc > threshold - h,
+                            false => soc >= threshold,
+                        });
+                    }
+                }
+                Condition::BatteryLow { threshold } => {
+                    for solarbank in solarbanks {
+                        let soc = solarbank.battery_power as f64;
+                        let device_sn = Some(solarbank.device_sn.as_str());
+                        self.threshold(index, site_id, device_sn, soc, |active, h| match active {
+                            true => soc < threshold + h,
+                            false => soc <= threshold,
+                        });
+                    }
+                }
+                Condition::SolarProduction { threshold } => {
+                    let Some(info) = &snapshot.scen_info.solarbank_info else {
+                        continue;
+                    };
+                    let power = info.total_photovoltaic_power;
+                    self.threshold(index, site_id, None, power, |active, h| match active {
+                        true => power > threshold - h,
+                        false => power >= threshold,
+                    });
+                }
+                Condition::DeviceOffline { .. } => {
+                    for device_sn in &known {
+                        let offline = !present.contains(device_sn);
+                        self.transition(index, site_id, Some(device_sn), offline, None);
+                    }
+                }
+                Condition::LoginFailed => {}
+            }
+        }
+    }
+
+    /// Marks the known devices of a site not seen for longer than the timeout as
+    /// offline
+    pub fn evaluate_stale(&mut self, site_id: &str) {
+        self.stale(site_id, snapshot::now());
+    }
+
+    fn stale(&mut self, site_id: &str, now: u64) {
+        let last_seen = self.last_seen.get(site_id).cloned().unwrap_or_default();
+
+        for index in 0..self.rules.len() {
+            let Condition::DeviceOffline { timeout } = self.rules[index].condition else {
+                continue;
+            };
+
+            for (device_sn, seen_at) in &last_seen {
+                if now.saturating_sub(*seen_at) > timeout {
+                    self.transition(index, site_id, Some(device_sn), true, None);
+                }
+            }
+        }
+    }
+
+    pub fn evaluate_login(&mut self, succeeded: bool) {
+        for index in 0..self.rules.len() {
+            if self.rules[index].condition == Condition::LoginFailed {
+                self.transition(index, "", None, !succeeded, None);
+            }
+        }
+    }
+
+    /// Threshold conditions are initialized silently from the first value
+    fn threshold(
+        &mut self,
+        index: usize,
+        site_id: &str,
+        device_sn: Option<&str>,
+        value: f64,
+        active: impl Fn(bool, f64) -> bool,
+    ) {
+        let hysteresis = self.rules[index].hysteresis;
+        let key = (index, site_id.to_string(), device_sn.map(String::from));
+
+        match self.states.get(&key) {
+            Some(state) => {
+                let active = active(state.active, hysteresis);
+                self.transition(index, site_id, device_sn, active, Some(value));
+            }
+            None => {
+                let state = State {
+                    active: active(false, hysteresis),
+                    notified_at: None,
+                };
+                self.states.insert(key, state);
+            }
+        }
+    }
+
+    fn transition(
+        &mut self,
+        index: usize,
+        site_id: &str,
+        device_sn: Option<&str>,
+        active: bool,
+        value: Option<f64>,
+    ) {
+        let rule = &self.rules[index];
+        let key = (index, site_id.to_string(), device_sn.map(String::from));
+        let state = self.states.entry(key).or_default();
+
+        if state.active == active {
+            return;
+        }
+        state.active = active;
+
+        if !active && !rule.resolve {
+            return;
+        }
+
+        let now = snapshot::now();
+        if let Some(notified_at) = state.notified_at
+            && now.saturating_sub(notified_at) < rule.cooldown
+        {
+            log::info!(
+                "Rule {} changed within its cooldown, not notifying",
+                rule.name
+            );
+            return;
+        }
+        state.notified_at = Some(now);
+
+        let event = Event {
+            rule,
+            firing: active,
+            site_id,
+            device_sn,
+            value,
+        };
+        let variables = event.variables();
+
+        log::info!("Rule {} is {}", rule.name, event.state());
+
+        for name in &rule.webhooks {
+            let Some(webhook) = self.webhooks.get(name).cloned() else {
+                continue;
+            };
+            let body = webhook.body(&variables);
+            let name = name.clone();
+
+            // Slow webhooks must not block polling and scrapes
+            thread::spawn(move || {
+                if let Err(err) = webhook.send(body) {
+                    log::warn!("Failed to notify webhook {name}: {err}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerts(rule: serde_json::Value) -> Alerts {
+        let mut rule = rule;
+        rule["name"] = "rule".into();
+        rule["webhooks"] = serde_json::json!([]);
+        let rule: RuleConfig = serde_json::from_value(rule).unwrap();
+
+        Alerts::new(&[rule], &HashMap::new()).unwrap()
+    }
+
+    fn snapshot(fetched_at: u64, socs: &[(&str, u32)]) -> Snapshot {
+        let solarbanks: Vec<_> = socs
+            .iter()
+            .map(|(device_sn, soc)| {
+                serde_json::json!({
+                    "device_sn": device_sn, "battery_power": soc.to_string(),
+                    "charging_power": "0", "output_power": "0", "photovoltaic_power": "0",
+                    "power_unit": "W",
+                })
+            })
+            .collect();
+        let scen_info = serde_json::from_value(serde_json::json!({
+            "solarbank_info": {
+                "solar_power_1": "0", "solar_power_2": "0",
+                "solar_power_3": "0", "solar_power_4": "0",
+                "to_home_load": "0", "total_battery_power": "0",
+                "total_charging_power": "0", "total_output_power": "0",
+                "total_photovoltaic_power": "0", "power_unit": "W",
+                "solarbank_list": solarbanks,
+            },
+        }))
+        .unwrap();
+
+        Snapshot {
+            fetched_at,
+            scen_info,
+        }
+    }
+
+    /// Whether the rule is active for the device and when it last notified
+    fn state(alerts: &Alerts, device_sn: &str) -> (bool, Option<u64>) {
+        let key = (0, "site".to_string(), Some(device_sn.to_string()));
+        let state = &alerts.states[&key];
+        (state.active, state.notified_at)
+    }
+
+    fn set_notified_at(alerts: &mut Alerts, device_sn: &str, notified_at: u64) {
+        let key = (0, "site".to_string(), Some(device_sn.to_string()));
+        alerts.states.get_mut(&key).unwrap().notified_at = Some(notified_at);
+    }
+
+    /// Evaluates a battery state of charge and returns the resulting state
+    fn soc(alerts: &mut Alerts, soc: u32) -> (bool, bool) {
+        alerts.evaluate("site", &snapshot(0, &[("SB1", soc)]));
+        let (active, notified_at) = state(alerts, "SB1");
+        (active, notified_at.is_some())
+    }
+
+    #[test]
+    fn fires_battery_full_with_hysteresis() {
+        let mut alerts = alerts(serde_json::json!({
+            "condition": "battery_full", "hysteresis": 5.0, "cooldown": 0,
+        }));
+
+        assert_eq!(soc(&mut alerts, 90), (false, false));
+        assert_eq!(soc(&mut alerts, 100), (true, true));
+        assert_eq!(soc(&mut alerts, 96), (true, true));
+        assert_eq!(soc(&mut alerts, 95), (false, true));
+    }
+
+    #[test]
+    fn fires_battery_low_with_hysteresis() {
+        let mut alerts = alerts(serde_json::json!({
+            "condition": "battery_low", "threshold": 20.0, "hysteresis": 5.0, "cooldown": 0,
+        }));
+
+        assert_eq!(soc(&mut alerts, 30), (false, false));
+        assert_eq!(soc(&mut alerts, 21), (false, false));
+        assert_eq!(soc(&mut alerts, 20), (true, true));
+        assert_eq!(soc(&mut alerts, 24), (true, true));
+        assert_eq!(soc(&mut alerts, 25), (false, true));
+    }
+
+    #[test]
+    fn initializes_silently_from_the_first_value() {
+        let mut alerts = alerts(serde_json::json!({
+            "condition": "battery_low", "threshold": 20.0, "resolve": true, "cooldown": 0,
+        }));
+
+        // Already low, e.g. after a restart
+        assert_eq!(soc(&mut alerts, 10), (true, false));
+        assert_eq!(soc(&mut alerts, 15), (true, false));
+        // Recovering is notified with resolve
+        assert_eq!(soc(&mut alerts, 50), (false, true));
+    }
+
+    #[test]
+    fn suppresses_notifications_within_the_cooldown() {
+        let mut alerts = alerts(serde_json::json!({
+            "condition": "battery_low", "threshold": 20.0, "resolve": true, "cooldown": 3600,
+        }));
+        soc(&mut alerts, 50);
+        soc(&mut alerts, 10);
+
+        let notified_at = snapshot::now() - 60;
+        set_notified_at(&mut alerts, "SB1", notified_at);
+        soc(&mut alerts, 50);
+        assert_eq!(state(&alerts, "SB1"), (false, Some(notified_at)));
+
+        let notified_at = snapshot::now() - 3600;
+        set_notified_at(&mut alerts, "SB1", notified_at);
+        soc(&mut alerts, 10);
+        let (active, notified) = state(&alerts, "SB1");
+        assert!(active && notified > Some(notified_at));
+    }
+
+    #[test]
+    fn notifies_clearing_only_with_resolve() {
+        for (resolve, notified) in [(false, false), (true, true)] {
+            let mut alerts = alerts(serde_json::json!({
+                "condition": "battery_full", "resolve": resolve, "cooldown": 0,
+            }));
+            soc(&mut alerts, 90);
+            soc(&mut alerts, 100);
+            set_notified_at(&mut alerts, "SB1", 0);
+
+            soc(&mut alerts, 90);
+            assert_eq!(state(&alerts, "SB1").1 != Some(0), notified, "{resolve}");
+        }
+    }
+
+    #[test]
+    fn marks_devices_offline_by_last_seen() {
+        let mut alerts = alerts(serde_json::json!({
+            "condition": "device_offline", "timeout": 900, "resolve": true,
+        }));
+        alerts.evaluate("site", &snapshot(1000, &[("SB1", 50), ("SB2", 50)]));
+        alerts.evaluate("site", &snapshot(1500, &[("SB2", 50)]));
+        // Missing from the site data
+        assert!(state(&alerts, "SB1").0);
+        assert!(!state(&alerts, "SB2").0);
+
+        // Without snapshots, e.g. after a reload, the last seen time still counts
+        alerts.stale("site", 2000);
+        assert!(!state(&alerts, "SB2").0);
+        alerts.stale("site", 2401);
+        assert!(state(&alerts, "SB2").0);
+
+        alerts.evaluate("site", &snapshot(2500, &[("SB1", 50), ("SB2", 50)]));
+        assert!(!state(&alerts, "SB1").0);
+        assert!(!state(&alerts, "SB2").0);
+    }
+
+    fn variables() -> Vec<(&'static str, String)> {
+        let rule: RuleConfig = serde_json::from_value(serde_json::json!({
+            "name": "Full", "condition": "battery_full", "webhooks": [],
+        }))
+        .unwrap();
+        let event = Event {
+            rule: &rule,
+            firing: true,
+            site_id: "site",
+            device_sn: Some("SB1"),
+            value: Some(100.0),
+        };
+
+        event.variables()
+    }
+
+    fn body(webhook: serde_json::Value) -> serde_json::Value {
+        let webhook: WebhookConfig = serde_json::from_value(webhook).unwrap();
+        serde_json::from_str(&webhook.body(&variables())).unwrap()
+    }
+
+    #[test]
+    fn builds_default_bodies() {
+        assert_eq!(
+            body(serde_json::json!({ "url": "http://ntfy", "kind": "ntfy", "topic": "solar" })),
+            serde_json::json!({
+                "topic": "solar",
+                "title": "Full",
+                "message": "Battery of SB1 is full (100%)",
+            })
+        );
+        assert_eq!(
+            body(serde_json::json!({ "url": "http://gotify", "kind": "gotify" })),
+            serde_json::json!({ "title": "Full", "message": "Battery of SB1 is full (100%)" })
+        );
+        assert_eq!(
+            body(serde_json::json!({ "url": "http://slack", "kind": "slack" })),
+            serde_json::json!({ "text": "Battery of SB1 is full (100%)" })
+        );
+        assert_eq!(
+            body(serde_json::json!({ "url": "http://hook" }))["state"],
+            "firing"
+        );
+    }
+
+    #[test]
+    fn escapes_json_in_templates() {
+        let variables = [("message", "Say \"hi\"\n\\o/".to_string())];
+
+        assert_eq!(
+            render("{\"text\": \"{{message}}\"}", &variables, true),
+            r#"{"text": "Say \"hi\"\n\\o/"}"#
+        );
+        assert_eq!(
+            render("{{message}} {{unknown}}", &variables, false),
+            "Say \"hi\"\n\\o/ {{unknown}}"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use crate::energy::battery_discharge;
+use crate::solix::data;
+
+/// Usable capacity in Wh by product number
+fn model_capacity(device_pn: &str) -> Option<f64> {
+    match device_pn {
+        // Solarbank E1600
+        "A17C0" => Some(1600.0),
+        // Solarbank 2 E1600 Pro, AC and Plus
+        "A17C1" | "A17C2" | "A17C3" => Some(1600.0),
+        _ => None,
+    }
+}
+
+pub struct Battery {
+    pub soc: f64,
+    pub capacity: Option<f64>,
+    pub charging: f64,
+    pub discharging: f64,
+}
+
+impl Battery {
+    pub fn new(solarbank: &data::Solarbank, capacities: &HashMap<String, f64>) -> Self {
+        let capacity = capacities
+            .get(&solarbank.device_sn)
+            .copied()
+            .or_else(|| solarbank.device_pn.as_deref().and_then(model_capacity));
+
+        Self {
+            soc: solarbank.battery_power as f64,
+            capacity,
+            charging: solarbank.charging_power,
+            discharging: solarbank.battery_discharge_power.unwrap_or_else(|| {
+                battery_discharge(
+                    solarbank.photovoltaic_power,
+                    solarbank.charging_power,
+                    solarbank.output_power,
+                )
+            }),
+        }
+    }
+
+    pub fn energy(&self) -> Option<f64> {
+        self.capacity.map(|capacity| capacity * self.soc / 100.0)
+    }
+
+    /// Seconds until full at the current charging power, if charging
+    pub fn time_to_full(&self) -> Option<f64> {
+        let missing = self.capacity? - self.energy()?;
+
+        (self.charging > 0.0 && missing > 0.0).then(|| missing / self.charging * 3600.0)
+    }
+
+    /// Seconds until empty at the current discharging power, if discharging
+    pub fn time_to_empty(&self) -> Option<f64> {
+        let energy = self.energy()?;
+
+        (self.discharging > 0.0).then(|| energy / self.discharging * 3600.0)
+    }
+}
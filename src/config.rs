@@ -1,14 +1,28 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use chrono_tz::Tz;
 use figment::providers::Format;
 use figment::{
-    providers::{Env, Json},
+    providers::{Env, Json, Yaml},
     Figment,
 };
 use serde::Deserialize;
 
+use crate::alerts::{RuleConfig, WebhookConfig};
+use crate::datalog::DataLogConfig;
+use crate::mqtt::MqttConfig;
+use crate::push::otlp::OtlpConfig;
+use crate::push::pushgateway::PushgatewayConfig;
+use crate::push::remote_write::RemoteWriteConfig;
+use crate::raw::RawMetricsConfig;
+use crate::recorder::RecorderConfig;
+use crate::tariff::TariffConfig;
+use crate::zero_export::ZeroExportConfig;
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default = "default_address")]
@@ -21,8 +35,56 @@ pub struct Config {
     country: String,
     #[serde(default = "default_timezone")]
     timezone: String,
+    #[serde(default = "default_api_url")]
+    api_url: String,
     #[serde(default = "default_cache_file")]
     cache_file: PathBuf,
+    #[serde(default = "default_cache_max_age")]
+    cache_max_age: u64,
+    #[serde(default)]
+    web_config_file: Option<PathBuf>,
+    #[serde(default)]
+    poll_interval: Option<u64>,
+    #[serde(default = "default_energy_file")]
+    energy_file: PathBuf,
+    #[serde(default = "default_energy_max_gap")]
+    energy_max_gap: u64,
+    #[serde(default = "default_savings_file")]
+    savings_file: PathBuf,
+    #[serde(default = "default_schedule_max_age")]
+    schedule_max_age: u64,
+    #[serde(default = "default_ota_max_age")]
+    ota_max_age: u64,
+    #[serde(default)]
+    battery_capacity: HashMap<String, f64>,
+    #[serde(default = "default_legacy_metrics")]
+    legacy_metrics: bool,
+    #[serde(default)]
+    site_name_label: bool,
+    #[serde(default)]
+    site_labels: HashMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    remote_write: Option<RemoteWriteConfig>,
+    #[serde(default)]
+    pushgateway: Option<PushgatewayConfig>,
+    #[serde(default)]
+    otlp: Option<OtlpConfig>,
+    #[serde(default)]
+    recorder: Option<RecorderConfig>,
+    #[serde(default)]
+    data_log: Option<DataLogConfig>,
+    #[serde(default)]
+    webhooks: HashMap<String, WebhookConfig>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    #[serde(default)]
+    zero_export: Option<ZeroExportConfig>,
+    #[serde(default)]
+    mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    raw_metrics: Option<RawMetricsConfig>,
+    #[serde(default)]
+    tariff: Option<TariffConfig>,
 }
 
 fn default_address() -> SocketAddr {
@@ -33,6 +95,34 @@ fn default_cache_file() -> PathBuf {
     PathBuf::from("token_cache.json")
 }
 
+fn default_energy_file() -> PathBuf {
+    PathBuf::from("energy_counters.json")
+}
+
+fn default_savings_file() -> PathBuf {
+    PathBuf::from("savings_counters.json")
+}
+
+fn default_energy_max_gap() -> u64 {
+    900
+}
+
+fn default_schedule_max_age() -> u64 {
+    900
+}
+
+fn default_ota_max_age() -> u64 {
+    3600
+}
+
+fn default_legacy_metrics() -> bool {
+    true
+}
+
+fn default_cache_max_age() -> u64 {
+    60
+}
+
 fn default_country() -> String {
     "DE".to_string()
 }
@@ -41,14 +131,35 @@ fn default_timezone() -> String {
     "Europe/Berlin".to_string()
 }
 
+fn default_api_url() -> String {
+    "https://ankerpower-api-eu.anker.com".to_string()
+}
+
+/// Arguments are `<subcommand> [<json config>] [<options>...]`, returns the config
+/// and the options. The config is a JSON object, which tells it apart from actions
+/// like `schedule set`.
+pub fn split_args(args: &[String]) -> (Option<&str>, &[String]) {
+    match args.get(2) {
+        Some(json) if json.trim_start().starts_with('{') => (Some(json), &args[3..]),
+        _ => (None, args.get(2..).unwrap_or_default()),
+    }
+}
+
 impl Config {
     pub fn new() -> Result<Self, Box<figment::Error>> {
         let args: Vec<String> = env::args().collect();
 
-        let json = args.get(2).map_or("{}", |v| v);
+        let json = split_args(&args).0.unwrap_or("{}");
+
+        // Unlike the environment, the file can change while running, for SIGHUP
+        let file = match env::var_os("ANKER_SOLIX_CONFIG_FILE") {
+            Some(path) => Yaml::file_exact(path),
+            None => Yaml::string(""),
+        };
 
         Figment::new()
-            .merge(Env::prefixed("ANKER_SOLIX_"))
+            .merge(file)
+            .merge(Env::prefixed("ANKER_SOLIX_").split("__"))
             .join(Json::string(json))
             .extract()
             .map_err(Box::new)
@@ -62,6 +173,15 @@ impl Config {
         self.timezone.as_str()
     }
 
+    /// The timezone for local dates, if known to the tz database
+    pub fn tz(&self) -> Option<Tz> {
+        self.timezone.parse().ok()
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
     pub fn username(&self) -> &str {
         self.username.as_str()
     }
@@ -77,4 +197,100 @@ impl Config {
     pub fn cache_file(&self) -> &Path {
         &self.cache_file
     }
+
+    pub fn cache_max_age(&self) -> u64 {
+        self.cache_max_age
+    }
+
+    pub fn web_config_file(&self) -> Option<&Path> {
+        self.web_config_file.as_deref()
+    }
+
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval.map(Duration::from_secs)
+    }
+
+    pub fn energy_file(&self) -> &Path {
+        &self.energy_file
+    }
+
+    pub fn energy_max_gap(&self) -> u64 {
+        self.energy_max_gap
+    }
+
+    pub fn savings_file(&self) -> &Path {
+        &self.savings_file
+    }
+
+    /// Seconds after which the output schedule is fetched again
+    pub fn schedule_max_age(&self) -> u64 {
+        self.schedule_max_age
+    }
+
+    /// Seconds after which the firmware update state of a device is fetched again
+    pub fn ota_max_age(&self) -> u64 {
+        self.ota_max_age
+    }
+
+    /// Battery capacities in Wh by device serial number, overriding the model default
+    pub fn battery_capacity(&self) -> &HashMap<String, f64> {
+        &self.battery_capacity
+    }
+
+    pub fn legacy_metrics(&self) -> bool {
+        self.legacy_metrics
+    }
+
+    pub fn site_name_label(&self) -> bool {
+        self.site_name_label
+    }
+
+    /// Static labels by site id, attached to every series of the site
+    pub fn site_labels(&self) -> &HashMap<String, BTreeMap<String, String>> {
+        &self.site_labels
+    }
+
+    pub fn remote_write(&self) -> Option<&RemoteWriteConfig> {
+        self.remote_write.as_ref()
+    }
+
+    pub fn pushgateway(&self) -> Option<&PushgatewayConfig> {
+        self.pushgateway.as_ref()
+    }
+
+    pub fn otlp(&self) -> Option<&OtlpConfig> {
+        self.otlp.as_ref()
+    }
+
+    pub fn recorder(&self) -> Option<&RecorderConfig> {
+        self.recorder.as_ref()
+    }
+
+    pub fn data_log(&self) -> Option<&DataLogConfig> {
+        self.data_log.as_ref()
+    }
+
+    pub fn webhooks(&self) -> &HashMap<String, WebhookConfig> {
+        &self.webhooks
+    }
+
+    pub fn rules(&self) -> &[RuleConfig] {
+        &self.rules
+    }
+
+    pub fn zero_export(&self) -> Option<&ZeroExportConfig> {
+        self.zero_export.as_ref()
+    }
+
+    pub fn mqtt(&self) -> Option<&MqttConfig> {
+        self.mqtt.as_ref()
+    }
+
+    pub fn raw_metrics(&self) -> Option<&RawMetricsConfig> {
+        self.raw_metrics.as_ref()
+    }
+
+    pub fn tariff(&self) -> Option<&TariffConfig> {
+        self.tariff.as_ref()
+    }
 }
@@ -0,0 +1,501 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::snapshot::Snapshot;
+use crate::solix::data;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    Daily,
+    Size,
+    Never,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DataLogConfig {
+    path: PathBuf,
+    #[serde(default = "default_format")]
+    format: LogFormat,
+    #[serde(default = "default_rotation")]
+    rotation: Rotation,
+    #[serde(default = "default_max_size")]
+    max_size: u64,
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_format() -> LogFormat {
+    LogFormat::Csv
+}
+
+fn default_rotation() -> Rotation {
+    Rotation::Daily
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+type SiteField = (&'static str, fn(&data::ScenInfo) -> Option<f64>);
+type DeviceField = (&'static str, fn(&data::Solarbank) -> Option<f64>);
+
+const SITE_FIELDS: [SiteField; 22] = [
+    ("home_load_power", |s| Some(s.home_load_power)),
+    ("other_loads_power", |s| Some(s.other_loads_power)),
+    ("grid_to_home_power", |s| {
+        Some(s.grid_info.as_ref()?.grid_to_home_power)
+    }),
+    ("photovoltaic_to_grid_power", |s| {
+        Some(s.grid_info.as_ref()?.photovoltaic_to_grid_power)
+    }),
+    ("home_charging_power", |s| {
+        Some(s.home_info.as_ref()?.charging_power)
+    }),
+    ("solar_power_1", |s| {
+        Some(s.solarbank_info.as_ref()?.solar_power_1)
+    }),
+    ("solar_power_2", |s| {
+        Some(s.solarbank_info.as_ref()?.solar_power_2)
+    }),
+    ("solar_power_3", |s| {
+        Some(s.solarbank_info.as_ref()?.solar_power_3)
+    }),
+    ("solar_power_4", |s| {
+        Some(s.solarbank_info.as_ref()?.solar_power_4)
+    }),
+    ("to_home_load", |s| {
+        Some(s.solarbank_info.as_ref()?.to_home_load)
+    }),
+    ("total_battery_power", |s| {
+        Some(s.solarbank_info.as_ref()?.total_battery_power)
+    }),
+    ("total_charging_power", |s| {
+        Some(s.solarbank_info.as_ref()?.total_charging_power)
+    }),
+    ("total_output_power", |s| {
+        Some(s.solarbank_info.as_ref()?.total_output_power)
+    }),
+    ("total_photovoltaic_power", |s| {
+        Some(s.solarbank_info.as_ref()?.total_photovoltaic_power)
+    }),
+    // Solarbank 2 and Smart Meter only
+    ("total_ac_power", |s| s.solarbank_info.as_ref()?.ac_power),
+    ("total_battery_discharge_power", |s| {
+        s.solarbank_info.as_ref()?.battery_discharge_power
+    }),
+    ("grid_to_battery_power", |s| {
+        s.grid_info.as_ref()?.grid_to_battery_power
+    }),
+    ("smartmeter_grid_to_home_power", |s| {
+        s.smartmeter_info.as_ref()?.grid_to_home_power
+    }),
+    ("smartmeter_photovoltaic_to_grid_power", |s| {
+        s.smartmeter_info.as_ref()?.photovoltaic_to_grid_power
+    }),
+    ("statistics_total_energy", |s| statistic(s, 0)),
+    ("statistics_total_co2", |s| statistic(s, 1)),
+    ("statistics_total_money", |s| statistic(s, 2)),
+];
+
+const DEVICE_FIELDS: [DeviceField; 11] = [
+    ("battery_power", |d| Some(d.battery_power as f64)),
+    ("charging_power", |d| Some(d.charging_power)),
+    ("output_power", |d| Some(d.output_power)),
+    ("photovoltaic_power", |d| Some(d.photovoltaic_power)),
+    // Solarbank 2 only
+    ("input_power_1", |d| d.solar_power_1),
+    ("input_power_2", |d| d.solar_power_2),
+    ("input_power_3", |d| d.solar_power_3),
+    ("input_power_4", |d| d.solar_power_4),
+    ("ac_power", |d| d.ac_power),
+    ("battery_discharge_power", |d| d.battery_discharge_power),
+    ("ac_charging_power", |d| d.grid_to_battery_power),
+];
+
+fn statistic(scene_data: &data::ScenInfo, index: usize) -> Option<f64> {
+    scene_data.statistics.get(index).map(|s| s.total)
+}
+
+fn columns() -> impl Iterator<Item = &'static str> {
+    ["timestamp", "site_id", "device_sn"]
+        .into_iter()
+        .chain(SITE_FIELDS.iter().map(|(name, _)| *name))
+        .chain(DEVICE_FIELDS.iter().map(|(name, _)| *name))
+}
+
+enum Cell {
+    Text(String),
+    Number(Option<f64>),
+}
+
+/// Appends one row per device and poll to a CSV or NDJSON file, with the values
+/// after unit normalization, i.e. the same numbers as the metrics. Rotated files
+/// get the date (daily) or time (size) of the rotation appended to their name,
+/// followed by a counter when a file with that name already exists.
+pub struct DataLog {
+    config: DataLogConfig,
+    timezone: Tz,
+    file: Option<File>,
+    size: u64,
+    day: Option<NaiveDate>,
+}
+
+impl DataLog {
+    pub fn new(config: &DataLogConfig, timezone: Tz) -> Self {
+        let modified = fs::metadata(&config.path).and_then(|m| m.modified()).ok();
+
+        Self {
+            config: config.clone(),
+            timezone,
+            file: None,
+            size: 0,
+            day: modified.map(|time| {
+                DateTime::<chrono::Utc>::from(time)
+                    .with_timezone(&timezone)
+                    .date_naive()
+            }),
+        }
+    }
+
+    pub fn append(&mut self, site_id: &str, snapshot: &Snapshot) -> io::Result<()> {
+        let time = DateTime::from_timestamp(snapshot.fetched_at as i64, 0)
+            .unwrap_or_default()
+            .with_timezone(&self.timezone);
+        let scene_data = &snapshot.scen_info;
+
+        let mut lines = String::new();
+        let site_cells = || {
+            [
+                Cell::Text(time.to_rfc3339()),
+                Cell::Text(site_id.to_string()),
+            ]
+            .into_iter()
+        };
+
+        // Sites without devices still get a row, with empty device columns
+        let devices: Vec<Option<&data::Solarbank>> = match scene_data.solarbanks() {
+            [] => vec![None],
+            solarbanks => solarbanks.iter().map(Some).collect(),
+        };
+
+        for device in devices {
+            let cells = site_cells()
+                .chain([Cell::Text(
+                    device.map(|d| d.device_sn.clone()).unwrap_or_default(),
+                )])
+                .chain(
+                    SITE_FIELDS
+                        .iter()
+                        .map(|(_, get)| Cell::Number(get(scene_data))),
+                )
+                .chain(
+                    DEVICE_FIELDS
+                        .iter()
+                        .map(|(_, get)| Cell::Number(device.and_then(get))),
+                );
+
+            lines.push_str(&self.line(cells));
+            lines.push('\n');
+        }
+
+        self.rotate_if_needed(time.date_naive(), lines.len() as u64)?;
+
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(lines.as_bytes())?;
+        }
+        self.size += lines.len() as u64;
+
+        Ok(())
+    }
+
+    fn line(&self, cells: impl Iterator<Item = Cell>) -> String {
+        match self.config.format {
+            LogFormat::Csv => cells
+                .map(|cell| match cell {
+                    Cell::Text(text) if text.contains([',', '"', '\n']) => {
+                        format!("\"{}\"", text.replace('"', "\"\""))
+                    }
+                    Cell::Text(text) => text,
+                    Cell::Number(value) => value.map(|v| v.to_string()).unwrap_or_default(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            LogFormat::Ndjson => {
+                let object: serde_json::Map<_, _> = columns()
+                    .zip(cells)
+                    .map(|(column, cell)| {
+                        let value = match cell {
+                            Cell::Text(text) => text.into(),
+                            Cell::Number(value) => value.into(),
+                        };
+                        (column.to_string(), value)
+                    })
+                    .collect();
+
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+
+    /// Opens the log for appending, writing the CSV header into new files
+    fn open(&mut self) -> io::Result<File> {
+        if let Some(parent) = self.config.path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.size = file.metadata()?.len();
+
+        if self.size == 0 && self.config.format == LogFormat::Csv {
+            let header = format!("{}\n", columns().collect::<Vec<_>>().join(","));
+            file.write_all(header.as_bytes())?;
+            self.size = header.len() as u64;
+        }
+
+        Ok(file)
+    }
+
+    fn rotate_if_needed(&mut self, day: NaiveDate, additional: u64) -> io::Result<()> {
+        let previous_day = self.day.replace(day);
+
+        let suffix = match self.config.rotation {
+            Rotation::Daily => match previous_day {
+                Some(previous) if previous != day => previous.format("%Y-%m-%d").to_string(),
+                _ => return Ok(()),
+            },
+            Rotation::Size => {
+                if self.file.is_none() {
+                    self.size = fs::metadata(&self.config.path).map_or(0, |m| m.len());
+                }
+                if self.size == 0 || self.size + additional <= self.config.max_size {
+                    return Ok(());
+                }
+
+                chrono::Utc::now()
+                    .with_timezone(&self.timezone)
+                    .format("%Y-%m-%dT%H-%M-%S")
+                    .to_string()
+            }
+            Rotation::Never => return Ok(()),
+        };
+
+        if !self.config.path.exists() {
+            return Ok(());
+        }
+
+        self.file = None;
+        fs::rename(
+            &self.config.path,
+            free_rotated_path(&self.config.path, &suffix),
+        )?;
+        log::info!("Rotated data log {:?}", self.config.path);
+
+        self.remove_old()
+    }
+
+    /// Removes the oldest rotated files beyond `keep`
+    fn remove_old(&self) -> io::Result<()> {
+        let (Some(dir), Some(prefix)) = (
+            self.config.path.parent(),
+            self.config.path.file_stem().and_then(|s| s.to_str()),
+        ) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{prefix}-");
+        let extension = self.config.path.extension();
+
+        let mut rotated: Vec<(RotationKey, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension() == extension)
+            .filter_map(|path| {
+                let key = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|stem| stem.strip_prefix(&prefix))
+                    .and_then(rotation_key)?;
+                Some((key, path))
+            })
+            .collect();
+        // Dates and times sort chronologically as text, then by counter
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.config.keep);
+        for (_, path) in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => path.with_file_name(format!("{stem}-{suffix}.{extension}")),
+        None => path.with_file_name(format!("{stem}-{suffix}")),
+    }
+}
+
+/// Appends a counter to the rotated name while a file with it exists, e.g. for
+/// two size rotations within a second
+fn free_rotated_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut rotated = rotated_path(path, suffix);
+    let mut counter = 1;
+
+    while rotated.exists() {
+        rotated = rotated_path(path, &format!("{suffix}-{counter}"));
+        counter += 1;
+    }
+
+    rotated
+}
+
+/// Date or time of a rotation and its counter
+type RotationKey = (String, u32);
+
+/// Parses the suffix [`rotated_path`] appends, `None` for unrelated files
+fn rotation_key(suffix: &str) -> Option<RotationKey> {
+    let time = suffix
+        .get(..19)
+        .filter(|time| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H-%M-%S").is_ok());
+    let date = suffix
+        .get(..10)
+        .filter(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok());
+    let time_len = time.or(date)?.len();
+
+    let counter = match &suffix[time_len..] {
+        "" => 0,
+        rest => {
+            let digits = rest.strip_prefix('-')?;
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            digits.parse().ok()?
+        }
+    };
+
+    Some((suffix[..time_len].to_string(), counter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(dir: &Path, keep: usize) -> DataLog {
+        let config = serde_json::json!({
+            "path": dir.join("solix.csv"),
+            "rotation": "size",
+            "max_size": 1,
+            "keep": keep,
+        });
+
+        DataLog::new(&serde_json::from_value(config).unwrap(), Tz::UTC)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn parses_rotation_suffixes() {
+        assert_eq!(rotation_key("2024-05-01"), Some(("2024-05-01".into(), 0)));
+        assert_eq!(
+            rotation_key("2024-05-01T12-30-00-2"),
+            Some(("2024-05-01T12-30-00".into(), 2))
+        );
+        assert_eq!(rotation_key("backup"), None);
+        assert_eq!(rotation_key("2024-05-01-old"), None);
+        assert_eq!(rotation_key("2024-05-01-"), None);
+    }
+
+    #[test]
+    fn keeps_rotations_within_a_second() {
+        let dir = temp_dir("datalog-rotations");
+        let mut log = log(&dir, 10);
+        let day = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+
+        for _ in 0..3 {
+            fs::write(dir.join("solix.csv"), "row\n").unwrap();
+            log.rotate_if_needed(day, 4).unwrap();
+        }
+
+        let names = file_names(&dir);
+        assert_eq!(names.len(), 3);
+        assert!(names.iter().all(|name| name.starts_with("solix-")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removes_only_rotated_files() {
+        let dir = temp_dir("datalog-remove");
+        for name in [
+            "solix-2024-05-01.csv",
+            "solix-2024-05-02T10-00-00.csv",
+            "solix-2024-05-02T10-00-00-1.csv",
+            "solix-backup.csv",
+            "solix-2024-05-01.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        log(&dir, 1).remove_old().unwrap();
+
+        assert_eq!(
+            file_names(&dir),
+            [
+                "solix-2024-05-01.txt",
+                "solix-2024-05-02T10-00-00-1.csv",
+                "solix-backup.csv",
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
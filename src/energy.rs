@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::solix::data;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Flow {
+    SolarProduction,
+    HomeLoad,
+    GridImport,
+    GridExport,
+    BatteryCharge,
+    BatteryDischarge,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Key {
+    pub site_id: String,
+    pub device_sn: Option<String>,
+    pub flow: Flow,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    key: Key,
+    wh: f64,
+}
+
+/// Integrates power readings over time into energy counters (Wh) using the
+/// trapezoidal rule. Readings further apart than `max_gap` seconds, e.g. across a
+/// restart or a cloud outage, only start a new interval instead of being
+/// extrapolated.
+pub struct Energy {
+    path: PathBuf,
+    max_gap: u64,
+    totals: HashMap<Key, f64>,
+    last: HashMap<Key, (u64, f64)>,
+}
+
+impl Energy {
+    pub fn load(path: &Path, max_gap: u64) -> Self {
+        let totals = match std::fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str::<Vec<Entry>>(&content) {
+                Ok(entries) => {
+                    log::info!("Loaded energy counters from file");
+                    entries.into_iter().map(|e| (e.key, e.wh)).collect()
+                }
+                Err(err) => {
+                    log::warn!("Failed to parse energy counters from file ({path:?}): {err:?}");
+                    HashMap::new()
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                log::warn!("Failed to read energy counters from file ({path:?}): {err:?}");
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            max_gap,
+            totals,
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn save(&self) {
+        let entries: Vec<_> = self
+            .totals
+            .iter()
+            .map(|(key, wh)| Entry {
+                key: key.clone(),
+                wh: *wh,
+            })
+            .collect();
+
+        if let Some(parent) = self.path.parent()
+            && !parent.as_os_str().is_empty()
+            && let Err(err) = std::fs::create_dir_all(parent)
+        {
+            log::warn!("Failed to create directory for energy counters ({parent:?}): {err:?}");
+        }
+
+        let tmp = self.path.with_extension("tmp");
+
+        match serde_json::to_string(&entries) {
+            Ok(json) => {
+                if let Err(err) =
+                    std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, &self.path))
+                {
+                    log::warn!("Failed to write energy counters ({:?}): {err:?}", self.path);
+                }
+            }
+            Err(err) => log::warn!("Failed to serialize energy counters: {err:?}"),
+        }
+    }
+
+    pub fn totals(&self) -> impl Iterator<Item = (&Key, f64)> {
+        self.totals.iter().map(|(key, wh)| (key, *wh))
+    }
+
+    /// Returns the energy in Wh added to the total
+    fn integrate(&mut self, key: Key, timestamp: u64, watts: f64) -> f64 {
+        let previous = self.last.insert(key.clone(), (timestamp, watts));
+        let total = self.totals.entry(key).or_default();
+
+        let Some((last_timestamp, last_watts)) = previous else {
+            return 0.0;
+        };
+
+        // Clock jumps backwards are treated like a gap
+        let dt = timestamp.saturating_sub(last_timestamp);
+        if dt == 0 || dt > self.max_gap {
+            return 0.0;
+        }
+
+        let wh = (last_watts + watts) / 2.0 * dt as f64 / 3600.0;
+        *total += wh;
+        wh
+    }
+
+    /// Integrates a reading, returning the energy in Wh of the site flows since the
+    /// previous reading. Flows of missing sections, e.g. without a Solarbank, are
+    /// left out.
+    pub fn update(
+        &mut self,
+        site_id: &str,
+        timestamp: u64,
+        scene_data: &data::ScenInfo,
+    ) -> HashMap<Flow, f64> {
+        let mut site_flows = vec![(Flow::HomeLoad, scene_data.home_load_power)];
+        if let Some(grid) = &scene_data.grid_info {
+            site_flows.extend([
+                (Flow::GridImport, grid.grid_to_home_power),
+                (Flow::GridExport, grid.photovoltaic_to_grid_power),
+            ]);
+        }
+        if let Some(info) = &scene_data.solarbank_info {
+            site_flows.extend([
+                (Flow::SolarProduction, info.total_photovoltaic_power),
+                (Flow::BatteryCharge, info.total_charging_power),
+                (
+                    Flow::BatteryDischarge,
+                    info.battery_discharge_power.unwrap_or_else(|| {
+                        battery_discharge(
+                            info.total_photovoltaic_power,
+                            info.total_charging_power,
+                            info.total_output_power,
+                        )
+                    }),
+                ),
+            ]);
+        }
+
+        let mut added = HashMap::new();
+        for (flow, watts) in site_flows {
+            let key = Key {
+                site_id: site_id.into(),
+                device_sn: None,
+                flow,
+            };
+            added.insert(flow, self.integrate(key, timestamp, watts));
+        }
+
+        for solarbank in scene_data.solarbanks() {
+            let device_flows = [
+                (Flow::SolarProduction, solarbank.photovoltaic_power),
+                (Flow::BatteryCharge, solarbank.charging_power),
+                (
+                    Flow::BatteryDischarge,
+                    solarbank.battery_discharge_power.unwrap_or_else(|| {
+                        battery_discharge(
+                            solarbank.photovoltaic_power,
+                            solarbank.charging_power,
+                            solarbank.output_power,
+                        )
+                    }),
+                ),
+            ];
+
+            for (flow, watts) in device_flows {
+                let key = Key {
+                    site_id: site_id.into(),
+                    device_sn: Some(solarbank.device_sn.clone()),
+                    flow,
+                };
+                self.integrate(key, timestamp, watts);
+            }
+        }
+
+        added
+    }
+}
+
+/// The Solarbank E1600 does not report discharging directly: whatever is output beyond
+/// the solar power that is not used for charging comes from the battery.
+pub fn battery_discharge(photovoltaic: f64, charging: f64, output: f64) -> f64 {
+    (output - (photovoltaic - charging).max(0.0)).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(flow: Flow) -> Key {
+        Key {
+            site_id: "site".into(),
+            device_sn: None,
+            flow,
+        }
+    }
+
+    #[test]
+    fn integrates_trapezoids() {
+        let mut energy = Energy::load(Path::new("missing.json"), 900);
+
+        assert_eq!(energy.integrate(key(Flow::HomeLoad), 1000, 100.0), 0.0);
+        assert_eq!(energy.integrate(key(Flow::HomeLoad), 1360, 300.0), 20.0);
+        assert_eq!(energy.integrate(key(Flow::HomeLoad), 1720, 300.0), 30.0);
+
+        let totals: Vec<_> = energy.totals().collect();
+        assert_eq!(totals, [(&key(Flow::HomeLoad), 50.0)]);
+    }
+
+    #[test]
+    fn skips_gaps_and_clock_jumps() {
+        let mut energy = Energy::load(Path::new("missing.json"), 900);
+        let mut integrate =
+            |timestamp, watts| energy.integrate(key(Flow::HomeLoad), timestamp, watts);
+
+        integrate(1000, 100.0);
+        // Above max_gap
+        assert_eq!(integrate(1901, 100.0), 0.0);
+        // Same timestamp
+        assert_eq!(integrate(1901, 100.0), 0.0);
+        // Backwards
+        assert_eq!(integrate(1800, 100.0), 0.0);
+        // Continues from the last reading
+        assert_eq!(integrate(2160, 100.0), 10.0);
+    }
+
+    #[test]
+    fn saves_and_loads_totals() {
+        let path = std::env::temp_dir().join(format!("energy-{}.json", std::process::id()));
+        let mut energy = Energy::load(&path, 900);
+        energy.integrate(key(Flow::GridImport), 0, 3600.0);
+        energy.integrate(key(Flow::GridImport), 60, 3600.0);
+        energy.save();
+
+        let loaded = Energy::load(&path, 900);
+        let totals: Vec<_> = loaded.totals().collect();
+        assert_eq!(totals, [(&key(Flow::GridImport), 60.0)]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn estimates_battery_discharge() {
+        assert_eq!(battery_discharge(100.0, 0.0, 300.0), 200.0);
+        assert_eq!(battery_discharge(400.0, 100.0, 300.0), 0.0);
+        assert_eq!(battery_discharge(0.0, 50.0, 0.0), 0.0);
+    }
+}
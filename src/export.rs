@@ -0,0 +1,343 @@
+use std::io::Write;
+
+use chrono::{DateTime, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+
+use crate::recorder::{self, Recorder, Row, Tier, DEVICE_COLUMNS, SITE_COLUMNS};
+use crate::Config;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Usage(String),
+    #[error(transparent)]
+    Recorder(#[from] recorder::Error),
+    #[error("Failed to write output: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Options {
+    from: u64,
+    to: u64,
+    tier: Tier,
+    format: Format,
+    site_id: Option<String>,
+    devices: bool,
+}
+
+/// Parses a date (`2024-05-01`, the whole day in the configured timezone) or an
+/// RFC 3339 timestamp into unix seconds. Dates are inclusive, so `end` selects the
+/// start of the following day.
+fn parse_time(value: &str, timezone: Tz, end: bool) -> Result<u64, Error> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp().max(0) as u64);
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| Error::Usage(format!("Invalid date {value:?}, expected YYYY-MM-DD")))?;
+    let date = match end {
+        true => date.succ_opt().unwrap_or(date),
+        false => date,
+    };
+
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    let time = timezone
+        .from_local_datetime(&midnight)
+        .earliest()
+        .ok_or_else(|| Error::Usage(format!("Invalid local date {value:?}")))?;
+
+    Ok(time.timestamp().max(0) as u64)
+}
+
+fn parse_options(args: &[String], timezone: Tz) -> Result<Options, Error> {
+    let mut from = None;
+    let mut to = None;
+    let mut tier = Tier::Hourly;
+    let mut format = Format::Csv;
+    let mut site_id = None;
+    let mut devices = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| Error::Usage(format!("Missing value for {arg}")))
+        };
+
+        match arg.as_str() {
+            "--from" => from = Some(parse_time(value()?, timezone, false)?),
+            "--to" => to = Some(parse_time(value()?, timezone, true)?),
+            "--tier" => {
+                let name = value()?;
+                tier = Tier::from_name(name).ok_or_else(|| {
+                    Error::Usage(format!("Unknown tier {name:?}, expected raw, 5m, 1h or 1d"))
+                })?;
+            }
+            "--format" => {
+                format = match value()?.as_str() {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => {
+                        return Err(Error::Usage(format!(
+                            "Unknown format {other:?}, expected csv or json"
+                        )))
+                    }
+                }
+            }
+            "--site" => site_id = Some(value()?.clone()),
+            "--devices" => devices = true,
+            other => return Err(Error::Usage(format!("Unknown argument {other:?}"))),
+        }
+    }
+
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(Error::Usage("--from and --to are required".into()));
+    };
+
+    Ok(Options {
+        from,
+        to,
+        tier,
+        format,
+        site_id,
+        devices,
+    })
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn write_csv(
+    out: &mut impl Write,
+    rows: &[Row],
+    columns: &[&str],
+    devices: bool,
+    timezone: Tz,
+) -> std::io::Result<()> {
+    let device_column = if devices { "device_sn," } else { "" };
+    writeln!(
+        out,
+        "time,site_id,{device_column}samples,{}",
+        columns.join(",")
+    )?;
+
+    for row in rows {
+        let mut fields = vec![time(row.timestamp, timezone), csv_field(&row.site_id)];
+        if let Some(device_sn) = &row.device_sn {
+            fields.push(csv_field(device_sn));
+        }
+        fields.push(row.samples.to_string());
+        fields.extend(
+            row.values
+                .iter()
+                .map(|value| value.map(|v| v.to_string()).unwrap_or_default()),
+        );
+
+        writeln!(out, "{}", fields.join(","))?;
+    }
+
+    Ok(())
+}
+
+fn write_json(
+    out: &mut impl Write,
+    rows: &[Row],
+    columns: &[&str],
+    timezone: Tz,
+) -> std::io::Result<()> {
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let mut object = serde_json::Map::new();
+            object.insert("time".into(), time(row.timestamp, timezone).into());
+            object.insert("site_id".into(), row.site_id.clone().into());
+            if let Some(device_sn) = &row.device_sn {
+                object.insert("device_sn".into(), device_sn.clone().into());
+            }
+            object.insert("samples".into(), row.samples.into());
+            for (column, value) in columns.iter().zip(&row.values) {
+                object.insert(column.to_string(), (*value).into());
+            }
+
+            object
+        })
+        .collect();
+
+    serde_json::to_writer_pretty(&mut *out, &rows)?;
+    writeln!(out)
+}
+
+fn time(timestamp: u64, timezone: Tz) -> String {
+    timezone
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Writes the recorded history of a date range to stdout, `args` being the
+/// arguments after the subcommand and config.
+pub fn run(config: &Config, args: &[String]) -> Result<(), Error> {
+    let Some(recorder_config) = config.recorder() else {
+        return Err(Error::Usage("Exporting requires RECORDER__PATH".into()));
+    };
+
+    let timezone = config
+        .tz()
+        .ok_or_else(|| Error::Usage(format!("Invalid timezone {:?}", config.timezone())))?;
+
+    let options = parse_options(args, timezone)?;
+
+    let recorder = Recorder::open(recorder_config, timezone)?;
+    let rows = recorder.query(
+        options.tier,
+        options.from,
+        options.to,
+        options.site_id.as_deref(),
+        options.devices,
+    )?;
+
+    let columns = match options.devices {
+        true => &DEVICE_COLUMNS[..],
+        false => &SITE_COLUMNS[..],
+    };
+
+    let mut out = std::io::stdout().lock();
+    match options.format {
+        Format::Csv => write_csv(&mut out, &rows, columns, options.devices, timezone)?,
+        Format::Json => write_json(&mut out, &rows, columns, timezone)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![Row {
+            timestamp: 1714514400,
+            site_id: "site, 1".into(),
+            device_sn: Some("SB1".into()),
+            samples: 2,
+            values: vec![Some(50.0), None, Some(12.5), None, Some(0.0)],
+        }]
+    }
+
+    #[test]
+    fn selects_whole_local_days() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let options = parse_options(
+            &args(&["--from", "2024-05-01", "--to", "2024-05-01"]),
+            berlin,
+        )
+        .unwrap();
+
+        assert_eq!(options.from, 1714514400); // 2024-05-01T00:00:00+02:00
+        assert_eq!(options.to, 1714600800); // 2024-05-02T00:00:00+02:00
+        assert_eq!(options.tier, Tier::Hourly);
+        assert_eq!(options.format, Format::Csv);
+    }
+
+    #[test]
+    fn parses_timestamps_and_options() {
+        let utc: Tz = "UTC".parse().unwrap();
+        let options = parse_options(
+            &args(&[
+                "--from",
+                "2024-05-01T12:00:00+02:00",
+                "--to",
+                "2024-05-01T13:00:00Z",
+                "--tier",
+                "5m",
+                "--format",
+                "json",
+                "--site",
+                "site",
+                "--devices",
+            ]),
+            utc,
+        )
+        .unwrap();
+
+        assert_eq!((options.from, options.to), (1714557600, 1714568400));
+        assert_eq!(options.tier, Tier::FiveMinutes);
+        assert_eq!(options.format, Format::Json);
+        assert_eq!(options.site_id.as_deref(), Some("site"));
+        assert!(options.devices);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let utc: Tz = "UTC".parse().unwrap();
+        let error = |list: &[&str]| match parse_options(&args(list), utc) {
+            Err(err) => err.to_string(),
+            Ok(_) => panic!("{list:?} was accepted"),
+        };
+
+        assert_eq!(
+            error(&["--from", "2024-05-01"]),
+            "--from and --to are required"
+        );
+        assert_eq!(
+            error(&["--from", "May 1"]),
+            "Invalid date \"May 1\", expected YYYY-MM-DD"
+        );
+        assert_eq!(error(&["--to"]), "Missing value for --to");
+        assert_eq!(
+            error(&["--tier", "1w"]),
+            "Unknown tier \"1w\", expected raw, 5m, 1h or 1d"
+        );
+    }
+
+    #[test]
+    fn writes_csv_with_empty_missing_values() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let mut out = Vec::new();
+        write_csv(&mut out, &rows(), &DEVICE_COLUMNS, true, berlin).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "time,site_id,device_sn,samples,battery_soc,charging_power,discharging_power,output_power,photovoltaic_power\n\
+            2024-05-01T00:00:00+02:00,\"site, 1\",SB1,2,50,,12.5,,0\n"
+        );
+    }
+
+    #[test]
+    fn writes_json_with_null_missing_values() {
+        let utc: Tz = "UTC".parse().unwrap();
+        let mut out = Vec::new();
+        write_json(&mut out, &rows(), &DEVICE_COLUMNS, utc).unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "time": "2024-04-30T22:00:00+00:00",
+                "site_id": "site, 1",
+                "device_sn": "SB1",
+                "samples": 2,
+                "battery_soc": 50.0,
+                "charging_power": null,
+                "discharging_power": 12.5,
+                "output_power": null,
+                "photovoltaic_power": 0.0,
+            }])
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    OpenMetrics,
+    Prometheus,
+}
+
+impl Format {
+    /// Picks the format with the highest quality in an `Accept` header, preferring
+    /// OpenMetrics on ties. Without a match, the Prometheus text format is used.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut open_metrics = 0.0;
+        let mut prometheus = 0.0;
+
+        for (media_type, quality) in accept.into_iter().flat_map(parse_accept) {
+            match media_type.as_str() {
+                "application/openmetrics-text" => open_metrics = quality.max(open_metrics),
+                "text/plain" => prometheus = quality.max(prometheus),
+                _ => {}
+            }
+        }
+
+        match open_metrics > 0.0 && open_metrics >= prometheus {
+            true => Format::OpenMetrics,
+            false => Format::Prometheus,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+
+    /// Converts the OpenMetrics text, as encoded by the registry, into this format.
+    pub fn encode(&self, open_metrics: String) -> String {
+        match self {
+            Format::OpenMetrics => open_metrics,
+            Format::Prometheus => to_prometheus(&open_metrics),
+        }
+    }
+}
+
+/// Parses an `Accept` or `Accept-Encoding` header into values and their quality.
+pub fn parse_accept(header: &str) -> Vec<(String, f64)> {
+    header
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';').map(str::trim);
+            let value = params.next().filter(|value| !value.is_empty())?;
+
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+
+            Some((value.to_ascii_lowercase(), quality))
+        })
+        .collect()
+}
+
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding
+        .into_iter()
+        .flat_map(parse_accept)
+        .any(|(encoding, quality)| encoding == "gzip" && quality > 0.0)
+}
+
+pub fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// The Prometheus text format 0.0.4 has no `# UNIT` and `# EOF` lines, and names
+/// counter families after their `_total` samples.
+fn to_prometheus(open_metrics: &str) -> String {
+    let counters: HashSet<&str> = open_metrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect();
+
+    let mut buffer = String::with_capacity(open_metrics.len());
+
+    for line in open_metrics.lines() {
+        if line.starts_with("# UNIT ") || line == "# EOF" {
+            continue;
+        }
+
+        let descriptor = ["# HELP ", "# TYPE "]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, line.strip_prefix(prefix)?)));
+
+        match descriptor {
+            Some((prefix, rest)) => {
+                let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let rest = match rest {
+                    "unknown" => "untyped",
+                    rest => rest,
+                };
+
+                buffer.push_str(prefix);
+                buffer.push_str(name);
+                if counters.contains(name) {
+                    buffer.push_str("_total");
+                }
+                buffer.push(' ');
+                buffer.push_str(rest);
+            }
+            None => buffer.push_str(line),
+        }
+
+        buffer.push('\n');
+    }
+
+    buffer
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Unknown,
+}
+
+#[derive(Debug)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+#[derive(Debug)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub unit: Option<String>,
+    pub kind: Kind,
+    pub samples: Vec<Sample>,
+}
+
+/// Parses the OpenMetrics text as encoded by the registry. Only the subset used by
+/// this exporter is supported: no timestamps and no exemplars.
+pub fn parse(open_metrics: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+
+    for line in open_metrics.lines() {
+        if let Some(descriptor) = line.strip_prefix("# ") {
+            let mut parts = descriptor.splitn(3, ' ');
+            let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let rest = parts.next().unwrap_or_default();
+
+            if families.last().is_none_or(|family| family.name != name) {
+                families.push(MetricFamily {
+                    name: name.to_string(),
+                    help: String::new(),
+                    unit: None,
+                    kind: Kind::Unknown,
+                    samples: Vec::new(),
+                });
+            }
+            let family = families.last_mut().unwrap();
+
+            match keyword {
+                "HELP" => family.help = rest.to_string(),
+                "UNIT" => family.unit = Some(rest.to_string()),
+                "TYPE" => {
+                    family.kind = match rest {
+                        "counter" => Kind::Counter,
+                        "gauge" => Kind::Gauge,
+                        _ => Kind::Unknown,
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let (Some(sample), Some(family)) = (parse_sample(line), families.last_mut()) else {
+            continue;
+        };
+
+        family.samples.push(sample);
+    }
+
+    families
+}
+
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line.find(['{', ' '])?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+
+    if let Some(mut inner) = rest.strip_prefix('{') {
+        loop {
+            inner = inner.trim_start_matches(',');
+            if let Some(after) = inner.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+
+            let (label, after) = inner.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (i, '"') => break i,
+                    (_, c) => value.push(c),
+                }
+            };
+
+            labels.push((label.to_string(), value));
+            inner = &after[end + 1..];
+        }
+    }
+
+    let value = rest.split_whitespace().next()?;
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => value.parse().ok()?,
+    };
+
+    Some(Sample {
+        name,
+        labels,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::metrics::counter::Counter;
+    use prometheus_client::metrics::gauge::Gauge;
+    use prometheus_client::registry::{Registry, Unit};
+
+    use super::*;
+
+    #[test]
+    fn parses_quality_values() {
+        assert_eq!(
+            parse_accept("Text/Plain;q=0.5, application/openmetrics-text; version=1.0.0, */*;q=0"),
+            [
+                ("text/plain".to_string(), 0.5),
+                ("application/openmetrics-text".to_string(), 1.0),
+                ("*/*".to_string(), 0.0),
+            ]
+        );
+        // Unparsable qualities count as 1
+        assert_eq!(parse_accept("gzip;q=high, ,"), [("gzip".to_string(), 1.0)]);
+    }
+
+    #[test]
+    fn negotiates_the_format() {
+        assert_eq!(Format::negotiate(None), Format::Prometheus);
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text;q=0.5,text/plain;q=0.5")),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text;q=0.4,text/plain")),
+            Format::Prometheus
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text;q=0")),
+            Format::Prometheus
+        );
+    }
+
+    #[test]
+    fn refuses_gzip_with_zero_quality() {
+        assert!(accepts_gzip(Some("deflate, gzip")));
+        assert!(accepts_gzip(Some("GZIP;q=0.1")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("br")));
+        assert!(!accepts_gzip(None));
+    }
+
+    #[test]
+    fn converts_to_prometheus_text() {
+        let mut registry = Registry::default();
+        let counter = Counter::<f64>::default();
+        counter.inc_by(1.5);
+        registry.register_with_unit("energy", "Energy", Unit::Other("wh".into()), counter);
+        let gauge = Gauge::<i64>::default();
+        gauge.set(3);
+        registry.register("devices", "Devices", gauge);
+
+        let mut open_metrics = String::new();
+        encode(&mut open_metrics, &registry).unwrap();
+
+        assert_eq!(
+            to_prometheus(&open_metrics),
+            "# HELP energy_wh_total Energy.\n\
+            # TYPE energy_wh_total counter\n\
+            energy_wh_total 1.5\n\
+            # HELP devices Devices.\n\
+            # TYPE devices gauge\n\
+            devices 3\n"
+        );
+        assert_eq!(
+            Format::OpenMetrics.encode(open_metrics.clone()),
+            open_metrics
+        );
+    }
+}
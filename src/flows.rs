@@ -0,0 +1,175 @@
+use crate::energy::battery_discharge;
+use crate::solix::data;
+
+/// Breakdown of the site's power into flows between solar, battery, home and grid.
+///
+/// Solar power charges the battery first, the rest is output by the Solarbank
+/// together with any battery discharge. Grid export is attributed to solar before
+/// battery power.
+#[derive(Debug)]
+pub struct Flows {
+    pub solar_to_home: f64,
+    pub solar_to_battery: f64,
+    pub solar_to_grid: f64,
+    pub battery_to_home: f64,
+    pub grid_to_home: f64,
+}
+
+impl Flows {
+    /// `None` without Solarbank or grid info, e.g. for power stations only
+    pub fn new(scene_data: &data::ScenInfo) -> Option<Self> {
+        let (Some(info), Some(grid)) = (&scene_data.solarbank_info, &scene_data.grid_info) else {
+            return None;
+        };
+
+        let photovoltaic = info.total_photovoltaic_power;
+        let charging = info.total_charging_power;
+        let output = info.total_output_power;
+        let export = grid.photovoltaic_to_grid_power;
+
+        let solar_to_battery = charging.min(photovoltaic);
+        let solar_output = (photovoltaic - solar_to_battery).min(output);
+        let battery_output = info
+            .battery_discharge_power
+            .unwrap_or_else(|| battery_discharge(photovoltaic, charging, output));
+
+        let solar_to_grid = export.min(solar_output);
+        let battery_to_grid = (export - solar_to_grid).min(battery_output);
+
+        Some(Self {
+            solar_to_home: solar_output - solar_to_grid,
+            solar_to_battery,
+            solar_to_grid,
+            battery_to_home: battery_output - battery_to_grid,
+            grid_to_home: grid.grid_to_home_power,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, f64)> {
+        [
+            ("solar_to_home", self.solar_to_home),
+            ("solar_to_battery", self.solar_to_battery),
+            ("solar_to_grid", self.solar_to_grid),
+            ("battery_to_home", self.battery_to_home),
+            ("grid_to_home", self.grid_to_home),
+        ]
+        .into_iter()
+    }
+}
+
+/// Share of the solar production that is not exported, including battery charging.
+/// Undefined without production, e.g. at night.
+pub fn self_consumption_ratio(scene_data: &data::ScenInfo) -> Option<f64> {
+    let photovoltaic = scene_data.solarbank_info.as_ref()?.total_photovoltaic_power;
+    let export = scene_data.grid_info.as_ref()?.photovoltaic_to_grid_power;
+
+    (photovoltaic > 0.0).then(|| ((photovoltaic - export) / photovoltaic).clamp(0.0, 1.0))
+}
+
+/// Share of the home load that is not covered by the grid. Undefined without load.
+pub fn autarky_ratio(scene_data: &data::ScenInfo) -> Option<f64> {
+    let load = scene_data.home_load_power;
+    let import = scene_data.grid_info.as_ref()?.grid_to_home_power;
+
+    (load > 0.0).then(|| ((load - import) / load).clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene_data(
+        photovoltaic: f64,
+        charging: f64,
+        output: f64,
+        grid: (f64, f64),
+        load: f64,
+    ) -> data::ScenInfo {
+        serde_json::from_value(serde_json::json!({
+            "home_load_power": load.to_string(),
+            "grid_info": {
+                "grid_to_home_power": grid.0.to_string(),
+                "photovoltaic_to_grid_power": grid.1.to_string(),
+            },
+            "solarbank_info": {
+                "solar_power_1": "0", "solar_power_2": "0",
+                "solar_power_3": "0", "solar_power_4": "0",
+                "to_home_load": output.to_string(), "total_battery_power": "0.5",
+                "total_charging_power": charging.to_string(),
+                "total_output_power": output.to_string(),
+                "total_photovoltaic_power": photovoltaic.to_string(),
+                "power_unit": "W", "solarbank_list": [],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn split(scene_data: &data::ScenInfo) -> Vec<(&'static str, f64)> {
+        Flows::new(scene_data).unwrap().iter().collect()
+    }
+
+    #[test]
+    fn charges_from_solar_first() {
+        // 600 W solar: 200 W charging, 400 W output of which 100 W are exported
+        let flows = split(&scene_data(600.0, 200.0, 400.0, (0.0, 100.0), 300.0));
+
+        assert_eq!(
+            flows,
+            [
+                ("solar_to_home", 300.0),
+                ("solar_to_battery", 200.0),
+                ("solar_to_grid", 100.0),
+                ("battery_to_home", 0.0),
+                ("grid_to_home", 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_solar_before_battery() {
+        // 100 W solar and 300 W from the battery, 150 W exported
+        let flows = split(&scene_data(100.0, 0.0, 400.0, (50.0, 150.0), 300.0));
+
+        assert_eq!(
+            flows,
+            [
+                ("solar_to_home", 0.0),
+                ("solar_to_battery", 0.0),
+                ("solar_to_grid", 100.0),
+                ("battery_to_home", 250.0),
+                ("grid_to_home", 50.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_solarbank_and_grid_info() {
+        let scene_data: data::ScenInfo =
+            serde_json::from_value(serde_json::json!({ "home_load_power": "100" })).unwrap();
+
+        assert!(Flows::new(&scene_data).is_none());
+        assert_eq!(self_consumption_ratio(&scene_data), None);
+        assert_eq!(autarky_ratio(&scene_data), None);
+    }
+
+    #[test]
+    fn computes_ratios() {
+        let scene_data = scene_data(400.0, 0.0, 400.0, (100.0, 100.0), 400.0);
+
+        assert_eq!(self_consumption_ratio(&scene_data), Some(0.75));
+        assert_eq!(autarky_ratio(&scene_data), Some(0.75));
+    }
+
+    #[test]
+    fn leaves_ratios_undefined_at_night_and_without_load() {
+        // Night: no production, the home runs on the grid
+        let night = scene_data(0.0, 0.0, 0.0, (300.0, 0.0), 300.0);
+        assert_eq!(self_consumption_ratio(&night), None);
+        assert_eq!(autarky_ratio(&night), Some(0.0));
+
+        // No load: everything is charged or exported
+        let idle = scene_data(500.0, 300.0, 200.0, (0.0, 200.0), 0.0);
+        assert_eq!(self_consumption_ratio(&idle), Some(0.6));
+        assert_eq!(autarky_ratio(&idle), None);
+    }
+}
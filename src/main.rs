@@ -1,26 +1,79 @@
+mod alerts;
+mod battery;
 mod config;
+mod datalog;
+mod energy;
+mod export;
+mod exposition;
+mod flows;
 mod metrics;
+mod mqtt;
+mod push;
+mod raw;
+mod recorder;
+mod rest;
+mod schedule;
+mod snapshot;
 mod solix;
+mod tariff;
+mod units;
+mod web;
+mod zero_export;
+use std::collections::HashMap;
+use std::env;
 use std::net::SocketAddr;
 use std::process;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use alerts::Alerts;
+use chrono::Timelike;
 pub use config::Config;
+use datalog::DataLog;
+use energy::Energy;
+use exposition::Format;
 pub use metrics::Metrics;
+use mqtt::Subscriber;
+use recorder::Recorder;
+use signal_hook::consts::SIGHUP;
 use signal_hook::consts::SIGINT;
 use signal_hook::consts::SIGTERM;
 use signal_hook::flag;
+use snapshot::Snapshot;
+use solix::data;
 use solix::Credentials;
 use solix::SolixApi;
-use tiny_http::{Response, Server};
+use tariff::source::PriceSource;
+use tariff::{Savings, TariffConfig};
+use tiny_http::{Header, Request, Response, ResponseBox};
+use web::WebServer;
+use zero_export::Controller;
 
 struct App {
     config: Config,
     credentials: Option<Credentials>,
-    metrics: Metrics,
+    metrics: Arc<Metrics>,
+    energy: Energy,
+    savings: Savings,
+    recorder: Option<Recorder>,
+    data_log: Option<DataLog>,
+    alerts: Alerts,
     solix: SolixApi,
-    site_ids: Vec<String>,
+    sites: Vec<data::SiteList>,
+    snapshots: HashMap<String, Snapshot>,
+    /// Output schedules by site with their fetch time, `None` if fetching failed
+    schedules: HashMap<String, (u64, Option<data::Schedule>)>,
+    /// Prices stored in the Anker app by site
+    site_prices: HashMap<String, data::SitePrice>,
+    /// Dynamic grid prices, if configured with the tariff
+    price_source: Option<PriceSource>,
+    /// OTA info by device with its fetch time, `None` if fetching failed
+    ota: HashMap<String, (u64, Option<data::OtaInfo>)>,
+    controller: Option<Controller>,
+    /// Whether the account has power stations, which require fetching the homepage
+    has_pps: bool,
+    subscriber: Option<Subscriber>,
 }
 
 impl App {
@@ -41,15 +94,18 @@ impl App {
                 log::info!("Logged in successfully");
                 let creds: Credentials = login.into();
                 self.credentials = Some(creds.save(self.config.cache_file()));
+                self.alerts.evaluate_login(true);
 
                 true
             }
             Err(solix::Error::InvalidCredentials) => {
                 log::error!("Invalid credentials");
+                self.alerts.evaluate_login(false);
                 false
             }
             Err(err) => {
                 log::error!("Failed to login: {err}");
+                self.alerts.evaluate_login(false);
                 true
             }
         }
@@ -63,9 +119,79 @@ impl App {
         };
 
         match self.solix.get_scen_info(creds, site_id) {
-            Ok(data) => {
+            Ok(raw) => {
+                // Exported before parsing, so new fields show up even if they break it
+                let leaves = self
+                    .config
+                    .raw_metrics()
+                    .map(|config| config.leaves(&raw))
+                    .unwrap_or_default();
+                self.metrics.update_raw(site_id, &leaves);
+
+                let mut data: data::ScenInfo = match serde_json::from_value(raw) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        log::error!("Failed to parse scen info of site {site_id}: {err}");
+                        return false;
+                    }
+                };
+                self.metrics.update_legacy(site_id, &data);
+
+                if let Err(err) = units::normalize(&mut data) {
+                    log::warn!("Rejecting data of site {site_id}: {err}");
+                    return false;
+                }
+
                 log::info!("Metrics updated successfully");
-                self.metrics.update(site_id, data);
+                let snapshot = Snapshot::new(data);
+
+                self.metrics.update(site_id, &snapshot.scen_info);
+                let wh = self
+                    .energy
+                    .update(site_id, snapshot.fetched_at, &snapshot.scen_info);
+                self.metrics.update_energy(&self.energy);
+
+                let grid = self
+                    .price_source
+                    .as_ref()
+                    .and_then(|source| source.price_at(snapshot.fetched_at as i64));
+                let price = tariff::price(
+                    self.config.tariff(),
+                    self.site_prices.get(site_id),
+                    self.local_minute(),
+                    grid,
+                );
+                if let Some(price) = &price {
+                    self.savings.accrue(site_id, price, &wh);
+                }
+                self.metrics.update_price(site_id, price.as_ref());
+                self.metrics.update_savings(&self.savings);
+
+                if let Some(recorder) = &mut self.recorder
+                    && let Err(err) = recorder.record(site_id, &snapshot)
+                {
+                    log::warn!("Failed to record site {site_id}: {err}");
+                }
+
+                if let Some(data_log) = &mut self.data_log
+                    && let Err(err) = data_log.append(site_id, &snapshot)
+                {
+                    log::warn!("Failed to write data log: {err}");
+                }
+
+                self.alerts.evaluate(site_id, &snapshot);
+                self.update_schedule(site_id);
+                self.update_firmware(site_id, &snapshot);
+
+                if let Some(subscriber) = &self.subscriber {
+                    for solarbank in snapshot.scen_info.solarbanks() {
+                        if let Some(device_pn) = &solarbank.device_pn {
+                            subscriber.watch(site_id, &solarbank.device_sn, device_pn);
+                        }
+                    }
+                }
+
+                self.snapshots.insert(site_id.to_string(), snapshot);
                 true
             }
             Err(solix::Error::InvalidCredentials) => match retried {
@@ -89,6 +215,198 @@ impl App {
         }
     }
 
+    /// Refreshes the OTA info of the Solarbanks if outdated, exports their firmware
+    /// versions and logs changes since the previous snapshot
+    fn update_firmware(&mut self, site_id: &str, snapshot: &Snapshot) {
+        let now = snapshot::now();
+        for solarbank in snapshot.scen_info.solarbanks() {
+            let device_sn = &solarbank.device_sn;
+            let current = solarbank.firmware_version();
+
+            let previous = self
+                .snapshots
+                .get(site_id)
+                .and_then(|previous| {
+                    let solarbanks = previous.scen_info.solarbanks();
+                    solarbanks
+                        .iter()
+                        .find(|previous| previous.device_sn == *device_sn)
+                })
+                .and_then(data::Solarbank::firmware_version);
+            if let (Some(previous), Some(current)) = (previous, current)
+                && previous != current
+            {
+                log::info!("Solarbank {device_sn} updated from firmware {previous} to {current}");
+            }
+
+            let outdated = self.ota.get(device_sn).is_none_or(|(fetched_at, _)| {
+                now.saturating_sub(*fetched_at) >= self.config.ota_max_age()
+            });
+
+            if outdated && let Some(creds) = &self.credentials {
+                let ota = match self.solix.get_ota_info(creds, device_sn) {
+                    Ok(ota) => Some(ota),
+                    Err(err) => {
+                        log::warn!("Failed to get OTA info of {device_sn}: {err}");
+                        None
+                    }
+                };
+
+                let offered = |ota: Option<&data::OtaInfo>| {
+                    ota.filter(|ota| ota.update_available(current))
+                        .and_then(data::OtaInfo::available_version)
+                        .map(str::to_string)
+                };
+                let before = self
+                    .ota
+                    .get(device_sn)
+                    .and_then(|(_, ota)| offered(ota.as_ref()));
+                if let Some(available) = offered(ota.as_ref())
+                    && before.as_ref() != Some(&available)
+                {
+                    log::info!(
+                        "Firmware {available} available for Solarbank {device_sn}, running {}",
+                        current.unwrap_or("unknown")
+                    );
+                }
+
+                self.ota.insert(device_sn.clone(), (now, ota));
+            }
+
+            let ota = self.ota.get(device_sn).and_then(|(_, ota)| ota.as_ref());
+            self.metrics
+                .update_firmware(site_id, device_sn, current, ota);
+        }
+    }
+
+    /// Refreshes the output schedule if outdated and exports its active slot
+    fn update_schedule(&mut self, site_id: &str) {
+        let now = snapshot::now();
+        let outdated = self.schedules.get(site_id).is_none_or(|(fetched_at, _)| {
+            now.saturating_sub(*fetched_at) >= self.config.schedule_max_age()
+        });
+
+        if outdated && let Some(creds) = &self.credentials {
+            let schedule = match self.solix.get_site_device_param::<data::Schedule>(
+                creds,
+                site_id,
+                data::Schedule::PARAM_TYPE,
+            ) {
+                Ok(schedule) => Some(schedule),
+                Err(err) => {
+                    log::warn!("Failed to get output schedule of site {site_id}: {err}");
+                    None
+                }
+            };
+            self.schedules.insert(site_id.to_string(), (now, schedule));
+        }
+
+        let Some(minute) = self.local_minute() else {
+            return;
+        };
+
+        let slot = self
+            .schedules
+            .get(site_id)
+            .and_then(|(_, schedule)| schedule.as_ref())
+            .and_then(|schedule| schedule::active_slot(schedule, minute));
+        self.metrics.update_schedule(site_id, slot);
+    }
+
+    /// Minutes since midnight in the configured timezone
+    fn local_minute(&self) -> Option<u32> {
+        let local = chrono::Utc::now().with_timezone(&self.config.tz()?);
+
+        Some(local.hour() * 60 + local.minute())
+    }
+
+    /// Runs one step of the zero export controller, writing the new output power
+    /// into the active slot of the output schedule
+    fn control(&mut self) {
+        let Some(controller) = &self.controller else {
+            return;
+        };
+
+        let site_id = match (controller.site_id(), self.sites.as_slice()) {
+            (Some(site_id), _) => site_id.to_string(),
+            (None, [site]) => site.site_id.clone(),
+            (None, sites) => {
+                log::warn!(
+                    "{} sites found, set ZERO_EXPORT__SITE_ID to control one",
+                    sites.len()
+                );
+                return;
+            }
+        };
+
+        let grid = controller.read_meter();
+
+        let expired = self
+            .credentials
+            .as_ref()
+            .is_none_or(|creds| creds.expires_in().unwrap() <= 0);
+        if expired {
+            self.login(false);
+        }
+        self.update_schedule(&site_id);
+
+        let (Some(creds), Some(minute)) = (&self.credentials, self.local_minute()) else {
+            return;
+        };
+        let Some((_, Some(mut schedule))) = self.schedules.get(&site_id).cloned() else {
+            return;
+        };
+        let Some(slot) = schedule::active_index(&schedule, minute)
+            .and_then(|index| schedule.ranges.get_mut(index))
+        else {
+            log::warn!("No schedule slot of site {site_id} is active, not controlling");
+            return;
+        };
+
+        let now = Instant::now();
+        let Some(controller) = &mut self.controller else {
+            return;
+        };
+        let Some(power) = controller.decide(grid, slot.power(), now) else {
+            return;
+        };
+
+        let [load, others @ ..] = slot.appliance_loads.as_mut_slice() else {
+            log::warn!("Schedule slot of site {site_id} has no appliance load, not controlling");
+            return;
+        };
+        load.power = power;
+        others.iter_mut().for_each(|load| load.power = 0);
+
+        match self.solix.set_site_device_param(
+            creds,
+            &site_id,
+            data::Schedule::PARAM_TYPE,
+            &schedule,
+        ) {
+            Ok(()) => {
+                log::info!("Changed output of site {site_id} to {power} W");
+                controller.written(now);
+                self.schedules
+                    .insert(site_id.clone(), (snapshot::now(), Some(schedule)));
+                self.update_schedule(&site_id);
+            }
+            Err(err) => log::warn!("Failed to change output of site {site_id}: {err}"),
+        }
+    }
+
+    /// Refreshes the power stations, which are only listed on the homepage
+    fn update_pps(&mut self) {
+        let Some(creds) = &self.credentials else {
+            return;
+        };
+
+        match self.solix.get_site_homepage(creds) {
+            Ok(data) => self.metrics.update_pps(&data.pps_list),
+            Err(err) => log::warn!("Failed to get power stations: {err}"),
+        }
+    }
+
     fn update_site_ids(&mut self, retried: bool) -> bool {
         self.login(false);
 
@@ -98,14 +416,18 @@ impl App {
 
         match self.solix.get_site_homepage(creds) {
             Ok(data) => {
-                self.site_ids = data
-                    .site_list
-                    .into_iter()
-                    .map(|site| {
-                        log::info!("Found site ({}): {}", site.site_id, site.site_name);
-                        site.site_id
-                    })
-                    .collect();
+                for site in &data.site_list {
+                    log::info!("Found site ({}): {}", site.site_id, site.site_name);
+                }
+                self.sites = data.site_list;
+                self.update_site_prices();
+                self.has_pps = !data.pps_list.is_empty();
+                self.metrics.update_pps(&data.pps_list);
+                self.metrics.set_sites(
+                    &self.sites,
+                    self.config.site_name_label(),
+                    self.config.site_labels(),
+                );
                 true
             }
             Err(solix::Error::InvalidCredentials) => match retried {
@@ -126,16 +448,160 @@ impl App {
         }
     }
 
+    /// Fetches the prices stored in the Anker app, which only apply without a local
+    /// import price
+    fn update_site_prices(&mut self) {
+        let Some(creds) = &self.credentials else {
+            return;
+        };
+
+        self.site_prices.clear();
+        for site in &self.sites {
+            match self.solix.get_site_price(creds, &site.site_id) {
+                Ok(price) => {
+                    self.site_prices.insert(site.site_id.clone(), price);
+                }
+                Err(err) => log::warn!("Failed to get price of site {}: {err}", site.site_id),
+            }
+        }
+    }
+
+    /// Re-reads the configuration and re-runs the site discovery. The address and web
+    /// config file are bound to the listening socket and require a restart.
+    pub fn reload(&mut self) {
+        log::info!("Reloading configuration");
+
+        let config = match Config::new() {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Failed to reload configuration, keeping previous one: {err}");
+                return;
+            }
+        };
+
+        if let Some(tariff) = config.tariff()
+            && let Err(err) = tariff.validate()
+        {
+            log::error!("Failed to reload configuration, keeping previous one: {err}");
+            return;
+        }
+
+        if config.address() != self.config.address()
+            || config.web_config_file() != self.config.web_config_file()
+        {
+            log::warn!("Changes of ADDRESS and WEB_CONFIG_FILE require a restart");
+        }
+
+        if config.legacy_metrics() != self.config.legacy_metrics() {
+            log::warn!("Changes of LEGACY_METRICS require a restart");
+        }
+
+        if config.energy_file() != self.config.energy_file()
+            || config.energy_max_gap() != self.config.energy_max_gap()
+            || config.savings_file() != self.config.savings_file()
+        {
+            log::warn!("Changes of ENERGY_FILE, ENERGY_MAX_GAP and SAVINGS_FILE require a restart");
+        }
+
+        if config.remote_write() != self.config.remote_write()
+            || config.otlp() != self.config.otlp()
+            || config.recorder() != self.config.recorder()
+            || config.data_log() != self.config.data_log()
+            || config.zero_export() != self.config.zero_export()
+            || config.mqtt() != self.config.mqtt()
+        {
+            log::warn!(
+                "Changes of REMOTE_WRITE, OTLP, RECORDER, DATA_LOG, ZERO_EXPORT and MQTT require a restart"
+            );
+        }
+
+        if config.api_url() != self.config.api_url()
+            || config.country() != self.config.country()
+            || config.timezone() != self.config.timezone()
+        {
+            self.solix = SolixApi::new(config.api_url(), config.country(), config.timezone());
+        }
+
+        if config.username() != self.config.username()
+            || config.password() != self.config.password()
+        {
+            self.credentials = None;
+        } else if config.cache_file() != self.config.cache_file() {
+            self.credentials = self
+                .credentials
+                .take()
+                .map(|creds| creds.save(config.cache_file()));
+        }
+
+        self.metrics
+            .set_battery_capacity(config.battery_capacity().clone());
+
+        if config.rules() != self.config.rules() || config.webhooks() != self.config.webhooks() {
+            match Alerts::new(config.rules(), config.webhooks()) {
+                Ok(alerts) => self.alerts = alerts,
+                Err(err) => log::error!("{err}, keeping previous rules"),
+            }
+        }
+
+        let source = config.tariff().and_then(TariffConfig::source);
+        if source != self.config.tariff().and_then(TariffConfig::source) {
+            self.price_source = source.map(PriceSource::new);
+        }
+
+        self.config = config;
+        self.snapshots.clear();
+        self.schedules.clear();
+        self.ota.clear();
+        self.update_site_ids(false);
+    }
+
+    pub fn shutdown(&mut self) {
+        self.energy.save();
+        self.savings.save();
+
+        if let Some(subscriber) = &self.subscriber {
+            subscriber.disconnect();
+        }
+
+        if let Some(creds) = self.credentials.take() {
+            creds.save(self.config.cache_file());
+        }
+    }
+
     pub fn address(&self) -> SocketAddr {
         self.config.address()
     }
 
-    pub fn get_metrics(&mut self) -> Option<String> {
+    /// Fetches the data of all sites, returns whether any site was updated.
+    pub fn poll(&mut self) -> bool {
         let updated = self
-            .site_ids
-            .clone()
+            .site_ids()
             .iter()
-            .any(|site_id| self.update_metrics(site_id, false));
+            .filter(|site_id| self.update_metrics(site_id, false))
+            .count();
+
+        for site_id in self.site_ids() {
+            self.alerts.evaluate_stale(&site_id);
+        }
+
+        if self.has_pps {
+            self.update_pps();
+        }
+
+        if updated > 0 {
+            self.energy.save();
+            self.savings.save();
+        }
+
+        updated > 0
+    }
+
+    pub fn get_metrics(&mut self) -> Option<String> {
+        // With a poll interval, scrapes are served from the last poll
+        let updated = match self.config.poll_interval() {
+            Some(_) => !self.snapshots.is_empty(),
+            None => self.poll(),
+        };
 
         match updated {
             true => Some(self.metrics.gather()),
@@ -143,10 +609,156 @@ impl App {
         }
     }
 
-    pub fn get_site_ids(&mut self) -> &[String] {
+    pub fn get_site_ids(&mut self) -> Vec<String> {
         self.update_site_ids(false);
 
-        &self.site_ids
+        self.site_ids()
+    }
+
+    pub fn get_sites_json(&self) -> String {
+        let sites: Vec<_> = self
+            .sites
+            .iter()
+            .map(|site| rest::site_summary(site, self.snapshots.get(&site.site_id)))
+            .collect();
+
+        serde_json::to_string(&sites).unwrap()
+    }
+
+    /// Returns the latest snapshot of a site as JSON, refreshing it first if it is
+    /// older than `cache_max_age`. Errors carry the HTTP status code to respond with.
+    pub fn get_site_json(&mut self, site_id: &str) -> Result<String, u16> {
+        if !self.sites.iter().any(|site| site.site_id == site_id) {
+            return Err(404);
+        }
+
+        let stale = self
+            .snapshots
+            .get(site_id)
+            .is_none_or(|snapshot| snapshot.age() >= self.config.cache_max_age());
+
+        if stale {
+            self.update_metrics(site_id, false);
+        }
+
+        let site = self.sites.iter().find(|site| site.site_id == site_id);
+
+        match (site, self.snapshots.get(site_id)) {
+            (Some(site), Some(snapshot)) => {
+                Ok(serde_json::to_string(&rest::site_detail(site, snapshot)).unwrap())
+            }
+            (None, _) => Err(404),
+            (_, None) => Err(503),
+        }
+    }
+
+    fn site_ids(&self) -> Vec<String> {
+        self.sites.iter().map(|site| site.site_id.clone()).collect()
+    }
+
+    pub fn handle(&mut self, request: &Request) -> ResponseBox {
+        let path = request.url().split('?').next().unwrap_or_default();
+        let path = path.trim_end_matches('/');
+
+        if path == "/api/v1/sites" {
+            return json_response(self.get_sites_json());
+        }
+
+        if let Some(site_id) = path.strip_prefix("/api/v1/sites/") {
+            return match self.get_site_json(site_id) {
+                Ok(json) => json_response(json),
+                Err(status) => Response::empty(status).boxed(),
+            };
+        }
+
+        match self.get_metrics() {
+            Some(metrics) => {
+                let format = Format::negotiate(web::header(request, "Accept"));
+                let gzip = exposition::accepts_gzip(web::header(request, "Accept-Encoding"));
+
+                metrics_response(format, format.encode(metrics), gzip)
+            }
+            None => {
+                log::warn!("Metrics are not available, responding with 500");
+                Response::empty(500).boxed()
+            }
+        }
+    }
+}
+
+fn metrics_response(format: Format, body: String, gzip: bool) -> ResponseBox {
+    let content_type = Header::from_bytes("Content-Type", format.content_type()).unwrap();
+
+    if gzip {
+        match exposition::gzip(body.as_bytes()) {
+            Ok(compressed) => {
+                let encoding = Header::from_bytes("Content-Encoding", "gzip").unwrap();
+
+                return Response::from_data(compressed)
+                    .with_header(content_type)
+                    .with_header(encoding)
+                    .boxed();
+            }
+            Err(err) => log::warn!("Failed to compress metrics: {err}"),
+        }
+    }
+
+    Response::from_string(body)
+        .with_header(content_type)
+        .boxed()
+}
+
+fn json_response(json: String) -> ResponseBox {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    Response::from_string(json).with_header(header).boxed()
+}
+
+/// Fetches all sites once and pushes the metrics to the Pushgateway. Returns the exit
+/// code: 1 without a Pushgateway, 2 if no site could be fetched, 3 if the push failed.
+fn push(mut app: App) -> i32 {
+    let Some(pushgateway) = app.config.pushgateway().cloned() else {
+        log::error!("Pushing requires PUSHGATEWAY__URL");
+        return 1;
+    };
+
+    let updated = app.poll();
+    app.shutdown();
+
+    if !updated {
+        log::error!("Failed to fetch any site, not pushing");
+        return 2;
+    }
+
+    let body = Format::Prometheus.encode(app.metrics.gather());
+
+    match pushgateway.push(body) {
+        Ok(()) => {
+            log::info!("Pushed metrics to {}", pushgateway.url());
+            0
+        }
+        Err(err) => {
+            log::error!("Failed to push metrics: {err}");
+            3
+        }
+    }
+}
+
+/// Runs `run` once `next` has passed, returning the next deadline. Without an
+/// interval, the feature is not configured and there is no deadline.
+fn run_due(
+    next: Option<Instant>,
+    interval: Option<Duration>,
+    run: impl FnOnce(),
+) -> Option<Instant> {
+    let interval = interval?;
+
+    match next {
+        Some(next) if Instant::now() < next => Some(next),
+        _ => {
+            run();
+            Some(Instant::now() + interval)
+        }
     }
 }
 
@@ -161,33 +773,331 @@ fn main() {
         }
     };
 
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("export") {
+        if let Err(err) = export::run(&config, config::split_args(&args).1) {
+            log::error!("{err}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.get(1).map(String::as_str) == Some("mqtt") {
+        if let Err(err) = mqtt::run(&config, config::split_args(&args).1) {
+            log::error!("{err}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let recorder = match (config.recorder(), config.tz()) {
+        (Some(recorder), Some(tz)) => match Recorder::open(recorder, tz) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                log::error!("Failed to open recorder: {err}");
+                process::exit(1);
+            }
+        },
+        (Some(_), None) => {
+            log::error!("Invalid timezone {:?} for the recorder", config.timezone());
+            process::exit(1);
+        }
+        (None, _) => None,
+    };
+
+    let data_log = match (config.data_log(), config.tz()) {
+        (Some(data_log), Some(tz)) => Some(DataLog::new(data_log, tz)),
+        (Some(_), None) => {
+            log::error!("Invalid timezone {:?} for the data log", config.timezone());
+            process::exit(1);
+        }
+        (None, _) => None,
+    };
+
+    if let Some(tariff) = config.tariff()
+        && let Err(err) = tariff.validate()
+    {
+        log::error!("{err}");
+        process::exit(1);
+    }
+
+    let alerts = match Alerts::new(config.rules(), config.webhooks()) {
+        Ok(alerts) => alerts,
+        Err(err) => {
+            log::error!("{err}");
+            process::exit(1);
+        }
+    };
+
+    let metrics = Arc::new(Metrics::new(config.legacy_metrics()));
+    metrics.set_battery_capacity(config.battery_capacity().clone());
+
     let mut app = App {
-        metrics: Metrics::new(),
-        solix: SolixApi::new(config.country(), config.timezone()),
+        metrics,
+        energy: Energy::load(config.energy_file(), config.energy_max_gap()),
+        savings: Savings::load(config.savings_file()),
+        recorder,
+        data_log,
+        alerts,
+        solix: SolixApi::new(config.api_url(), config.country(), config.timezone()),
+        controller: config.zero_export().map(Controller::new),
+        price_source: config
+            .tariff()
+            .and_then(TariffConfig::source)
+            .map(PriceSource::new),
+        has_pps: false,
+        subscriber: None,
         credentials: Credentials::load(config.cache_file()),
         config,
-        site_ids: Vec::new(),
+        sites: Vec::new(),
+        snapshots: HashMap::new(),
+        schedules: HashMap::new(),
+        site_prices: HashMap::new(),
+        ota: HashMap::new(),
     };
 
     // Also ensures that credentials are still valid despite their expiration date
     app.get_site_ids();
+    app.metrics.update_energy(&app.energy);
+    app.metrics.update_savings(&app.savings);
+
+    if args.get(1).map(String::as_str) == Some("push") {
+        process::exit(push(app));
+    }
 
-    let server = Server::http(app.address()).unwrap();
+    if args.get(1).map(String::as_str) == Some("schedule") {
+        let Some(creds) = &app.credentials else {
+            log::error!("Failed to login");
+            process::exit(1);
+        };
 
-    let _ = flag::register_conditional_shutdown(SIGINT, 0, Arc::new(AtomicBool::new(true)));
-    let _ = flag::register_conditional_shutdown(SIGTERM, 0, Arc::new(AtomicBool::new(true)));
+        let args = config::split_args(&args).1;
+        if let Err(err) = schedule::run(&app.solix, creds, &app.sites, args) {
+            log::error!("{err}");
+            process::exit(1);
+        }
+        app.shutdown();
+        return;
+    }
 
-    for request in server.incoming_requests() {
-        let result = match app.get_metrics() {
-            Some(metrics) => request.respond(Response::from_string(metrics)),
-            None => {
-                log::warn!("Metrics are not available, responding with 500");
-                request.respond(Response::empty(500))
+    if let Some(config) = app.config.mqtt()
+        && let Some(creds) = &app.credentials
+    {
+        let metrics = Arc::clone(&app.metrics);
+        match Subscriber::connect(config, &app.solix, creds, metrics) {
+            Ok(subscriber) => app.subscriber = Some(subscriber),
+            Err(err) => log::error!("{err}, continuing without realtime metrics"),
+        }
+    }
+
+    let mut web = match WebServer::new(app.address(), app.config.web_config_file()) {
+        Ok(web) => web,
+        Err(err) => {
+            log::error!("{err}");
+            process::exit(1);
+        }
+    };
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let reload = Arc::new(AtomicBool::new(false));
+
+    let mut pushers = Vec::new();
+    if let Some(config) = app.config.remote_write() {
+        let metrics = Arc::clone(&app.metrics);
+        pushers.push(push::remote_write::spawn(
+            config.clone(),
+            metrics,
+            Arc::clone(&shutdown),
+        ));
+    }
+    if let Some(config) = app.config.otlp() {
+        let metrics = Arc::clone(&app.metrics);
+        match push::otlp::spawn(config.clone(), metrics, Arc::clone(&shutdown)) {
+            Ok(pusher) => pushers.push(pusher),
+            Err(err) => {
+                log::error!("Failed to start the OTLP export: {err}");
+                process::exit(1);
             }
+        }
+    }
+
+    for signal in [SIGINT, SIGTERM] {
+        // A second signal exits immediately, in case the graceful shutdown hangs
+        let _ = flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown));
+        let _ = flag::register(signal, Arc::clone(&shutdown));
+    }
+    let _ = flag::register(SIGHUP, Arc::clone(&reload));
+
+    // Only set while the feature is configured, so an unset one never wakes the loop
+    let mut next_poll: Option<Instant> = None;
+    let mut next_control: Option<Instant> = None;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            app.reload();
+        }
+
+        let interval = app.config.poll_interval();
+        next_poll = run_due(next_poll, interval, || {
+            app.poll();
+        });
+
+        let interval = app.controller.as_ref().map(Controller::interval);
+        next_control = run_due(next_control, interval, || app.control());
+
+        web.reload_if_changed();
+
+        let timeout = [next_poll, next_control]
+            .into_iter()
+            .flatten()
+            .min()
+            .map_or(Duration::from_secs(1), |next| {
+                next.saturating_duration_since(Instant::now())
+                    .clamp(Duration::from_millis(10), Duration::from_secs(1))
+            });
+
+        let Some(request) = web.recv(timeout) else {
+            continue;
         };
 
-        if let Err(err) = result {
+        let response = match web.authorize(&request) {
+            true => app.handle(&request),
+            false => {
+                log::warn!("Unauthorized request from {:?}", request.remote_addr());
+                web::unauthorized()
+            }
+        };
+
+        if let Err(err) = request.respond(response) {
             log::error!("Failed to responde: {err}");
         }
     }
+
+    log::info!("Shutting down");
+
+    drop(web);
+    app.shutdown();
+
+    for pusher in pushers {
+        if pusher.join().is_err() {
+            log::error!("Pushing metrics panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    fn app(config: Config) -> App {
+        App {
+            metrics: Arc::new(Metrics::new(false)),
+            energy: Energy::load(config.energy_file(), config.energy_max_gap()),
+            savings: Savings::load(config.savings_file()),
+            recorder: None,
+            data_log: None,
+            alerts: Alerts::new(config.rules(), config.webhooks()).unwrap(),
+            solix: SolixApi::new(config.api_url(), config.country(), config.timezone()),
+            controller: config.zero_export().map(Controller::new),
+            price_source: None,
+            has_pps: false,
+            subscriber: None,
+            credentials: Some(Credentials::new(
+                "user".into(),
+                "token".into(),
+                snapshot::now() + 3600,
+            )),
+            config,
+            sites: Vec::new(),
+            snapshots: HashMap::new(),
+            schedules: HashMap::new(),
+            site_prices: HashMap::new(),
+            ota: HashMap::new(),
+        }
+    }
+
+    /// Serves the meter reading and the output schedule, returning the written
+    /// schedules
+    fn cloud(server: tiny_http::Server) -> mpsc::Receiver<serde_json::Value> {
+        let (sender, receiver) = mpsc::channel();
+        let schedule = serde_json::json!({
+            "ranges": [{
+                "id": 0,
+                "start_time": "00:00",
+                "end_time": "24:00",
+                "turn_on": true,
+                "appliance_loads": [{ "id": 1, "name": "Load", "power": 200, "number": 1 }],
+                "charge_priority": 80,
+            }],
+        });
+
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+
+                let response = match request.url() {
+                    "/meter" => serde_json::json!({ "power": 150 }),
+                    "/power_service/v1/site/get_site_device_param" => serde_json::json!({
+                        "code": 0,
+                        "msg": "success!",
+                        "data": { "param_data": schedule.to_string() },
+                    }),
+                    "/power_service/v1/site/set_site_device_param" => {
+                        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        let param_data = body["param_data"].as_str().unwrap();
+                        sender
+                            .send(serde_json::from_str(param_data).unwrap())
+                            .unwrap();
+
+                        serde_json::json!({ "code": 0, "msg": "success!" })
+                    }
+                    url => panic!("Unexpected request to {url}"),
+                };
+
+                request
+                    .respond(tiny_http::Response::from_string(response.to_string()))
+                    .unwrap();
+            }
+        });
+
+        receiver
+    }
+
+    #[test]
+    fn controls_output_of_active_slot() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let written = cloud(server);
+        let dir = std::env::temp_dir().join(format!("control-{}", process::id()));
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "api_url": url,
+            "energy_file": dir.join("energy.json"),
+            "savings_file": dir.join("savings.json"),
+            "zero_export": {
+                "site_id": "site",
+                "meter": {
+                    "source": "http",
+                    "url": format!("{url}/meter"),
+                    "pointer": "/power",
+                },
+            },
+        }))
+        .unwrap();
+        let mut app = app(config);
+
+        app.control();
+
+        let schedule = written.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(schedule["ranges"][0]["appliance_loads"][0]["power"], 350);
+        assert_eq!(schedule["ranges"][0]["appliance_loads"][0]["name"], "Load");
+
+        // Within the minimum write interval
+        app.control();
+        assert!(written.recv_timeout(Duration::from_millis(500)).is_err());
+    }
 }
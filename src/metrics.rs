@@ -1,23 +1,72 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::{Arc, RwLock};
 
 use prometheus_client::encoding::text::encode;
-use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::encoding::{EncodeLabel, EncodeLabelSet, LabelSetEncoder};
+use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::registry::Registry;
+use prometheus_client::registry::{Registry, Unit};
 
+use crate::battery::Battery;
+use crate::energy::{Energy, Flow};
+use crate::flows::{self, Flows};
+use crate::mqtt::payload::Realtime;
 use crate::solix::data;
+use crate::tariff::{Account, Price, Savings};
+
+/// Reserved for the labels set by the exporter
+const RESERVED_LABELS: &[&str] = &[
+    "site_id",
+    "site_name",
+    "ms_type",
+    "power_site_type",
+    "unit",
+    "device_sn",
+    "flow",
+    "input",
+    "currency",
+    "path",
+    "current_version",
+    "available_version",
+];
+
+/// Labels identifying a site on every series: the site id, followed by the
+/// optional site name and user-defined static labels.
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Site {
+    site_id: String,
+    labels: Arc<[(String, String)]>,
+}
+
+impl EncodeLabelSet for Site {
+    fn encode(&self, encoder: &mut LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        ("site_id", self.site_id.as_str()).encode(encoder.encode_label())?;
+        self.labels.as_ref().encode(encoder)
+    }
+}
 
 #[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
-pub struct Labels {
+pub struct SiteInfoLabels {
     site_id: String,
+    site_name: String,
+    ms_type: String,
+    power_site_type: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Labels {
+    #[prometheus(flatten)]
+    site: Site,
     unit: String,
 }
 
 impl Labels {
-    pub fn new(site_id: &str, unit: &str) -> Self {
+    pub fn new(site: &Site, unit: &str) -> Self {
         Self {
-            site_id: site_id.into(),
+            site: site.clone(),
             unit: unit.into(),
         }
     }
@@ -25,28 +74,94 @@ impl Labels {
 
 #[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct SolarbankLabels {
-    site_id: String,
+    #[prometheus(flatten)]
+    site: Site,
     unit: String,
     device_sn: String,
 }
 
 impl SolarbankLabels {
-    pub fn new(site_id: &str, unit: &str, device_sn: &str) -> Self {
+    pub fn new(site: &Site, unit: &str, device_sn: &str) -> Self {
         Self {
-            site_id: site_id.into(),
+            site: site.clone(),
             unit: unit.into(),
             device_sn: device_sn.into(),
         }
     }
 }
 
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SiteLabels {
+    #[prometheus(flatten)]
+    site: Site,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    device_sn: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FlowLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    flow: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SolarLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    input: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceInputLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    device_sn: String,
+    input: String,
+}
+
+/// Power stations belong to the account, not to a site
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PpsLabels {
+    device_sn: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CurrencyLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    currency: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FirmwareLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    device_sn: String,
+    current_version: String,
+    available_version: String,
+}
+
+#[derive(Default, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RawLabels {
+    #[prometheus(flatten)]
+    site: Site,
+    path: String,
+}
+
 type GaugeU32<T = Labels> = Family<T, Gauge<u32, AtomicU32>>;
 type GaugeF64<T = Labels> = Family<T, Gauge<f64, AtomicU64>>;
+type CounterF64<T> = Family<T, Counter<f64, AtomicU64>>;
 
+/// Metrics with the cloud's unit in a `unit` label, superseded by the metrics in
+/// SI base units. Kept during the migration, see `legacy_metrics` in the config.
 #[derive(Default)]
-pub struct Metrics {
-    pub registry: Registry,
-
+pub struct LegacyMetrics {
     pub home_load_power: GaugeU32,
     pub other_load_power: GaugeU32,
 
@@ -75,201 +190,1357 @@ pub struct Metrics {
     pub solarbank_total_photovoltaic_power: GaugeU32,
 }
 
-impl Metrics {
-    pub fn new() -> Self {
-        let mut metrics = Self::default();
-
-        metrics.registry.register(
+impl LegacyMetrics {
+    fn register(&self, registry: &mut Registry) {
+        registry.register(
             "anker_solix_home_load_power",
             "Home load power",
-            metrics.home_load_power.clone(),
+            self.home_load_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_other_load_power",
             "Other load power",
-            metrics.other_load_power.clone(),
+            self.other_load_power.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_grid_to_home_power",
             "Grid to home power",
-            metrics.grid_to_home_power.clone(),
+            self.grid_to_home_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_photovoltaic_to_grid_power",
             "Photovoltaic to grid power",
-            metrics.photovoltaic_to_grid_power.clone(),
+            self.photovoltaic_to_grid_power.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_home_charging_power",
             "Home charging power",
-            metrics.home_charging_power.clone(),
+            self.home_charging_power.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_statistics_total_power",
             "Statistics total power",
-            metrics.statistics_total_power.clone(),
+            self.statistics_total_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_statistics_total_co2",
             "Statistics total CO2",
-            metrics.statistics_total_co2.clone(),
+            self.statistics_total_co2.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_statistics_total_money",
             "Statistics total money",
-            metrics.statistics_total_money.clone(),
+            self.statistics_total_money.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solar_power_1",
             "Solar power 1",
-            metrics.solar_power_1.clone(),
+            self.solar_power_1.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solar_power_2",
             "Solar power 2",
-            metrics.solar_power_2.clone(),
+            self.solar_power_2.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solar_power_3",
             "Solar power 3",
-            metrics.solar_power_3.clone(),
+            self.solar_power_3.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solar_power_4",
             "Solar power 4",
-            metrics.solar_power_4.clone(),
+            self.solar_power_4.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_battery_power",
             "Solarbank power percent",
-            metrics.solarbank_battery_power.clone(),
+            self.solarbank_battery_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_charging_power",
             "Solarbank charging power",
-            metrics.solarbank_charging_power.clone(),
+            self.solarbank_charging_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_output_power",
             "Solarbank output power",
-            metrics.solarbank_output_power.clone(),
+            self.solarbank_output_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_photovoltaic_power",
             "Solarbank photovoltaic power",
-            metrics.solarbank_photovoltaic_power.clone(),
+            self.solarbank_photovoltaic_power.clone(),
         );
 
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_total_charging_power",
             "Solarbank total charging power",
-            metrics.solarbank_total_charging_power.clone(),
+            self.solarbank_total_charging_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_total_output_power",
             "Solarbank total output power",
-            metrics.solarbank_total_output_power.clone(),
+            self.solarbank_total_output_power.clone(),
         );
-        metrics.registry.register(
+        registry.register(
             "anker_solix_solarbank_total_photovoltaic_power",
             "Solarbank total photovoltaic power",
-            metrics.solarbank_total_photovoltaic_power.clone(),
+            self.solarbank_total_photovoltaic_power.clone(),
         );
-
-        metrics
-    }
-
-    pub fn gather(&self) -> String {
-        let mut buffer = String::new();
-        encode(&mut buffer, &self.registry).unwrap();
-
-        buffer
     }
 
-    pub fn update(&self, site_id: &str, scene_data: data::ScenInfo) {
-        let grid_labels = Labels::new(site_id, "W");
+    /// Expects the data as returned by the cloud, before unit normalization.
+    fn update(&self, site: &Site, scene_data: &data::ScenInfo) {
+        let grid_labels = Labels::new(site, "W");
 
         self.home_load_power
             .get_or_create(&grid_labels)
-            .set(scene_data.home_load_power);
+            .set(scene_data.home_load_power as u32);
         self.other_load_power
             .get_or_create(&grid_labels)
-            .set(scene_data.other_loads_power);
+            .set(scene_data.other_loads_power as u32);
 
-        self.grid_to_home_power
-            .get_or_create(&grid_labels)
-            .set(scene_data.grid_info.grid_to_home_power);
-        self.photovoltaic_to_grid_power
-            .get_or_create(&grid_labels)
-            .set(scene_data.grid_info.photovoltaic_to_grid_power);
+        if let Some(grid) = &scene_data.grid_info {
+            self.grid_to_home_power
+                .get_or_create(&grid_labels)
+                .set(grid.grid_to_home_power as u32);
+            self.photovoltaic_to_grid_power
+                .get_or_create(&grid_labels)
+                .set(grid.photovoltaic_to_grid_power as u32);
+        }
+
+        if let Some(home) = &scene_data.home_info {
+            self.home_charging_power
+                .get_or_create(&Labels::new(site, &home.power_unit))
+                .set(home.charging_power);
+        }
 
-        self.home_charging_power
-            .get_or_create(&Labels::new(site_id, &scene_data.home_info.power_unit))
-            .set(scene_data.home_info.charging_power);
+        // Sites without a Solarbank have no statistics
+        let statistics = [
+            &self.statistics_total_power,
+            &self.statistics_total_co2,
+            &self.statistics_total_money,
+        ];
+        for (family, statistic) in statistics.into_iter().zip(&scene_data.statistics) {
+            family
+                .get_or_create(&Labels::new(site, &statistic.unit))
+                .set(statistic.total);
+        }
 
-        self.statistics_total_power
-            .get_or_create(&Labels::new(site_id, &scene_data.statistics[0].unit))
-            .set(scene_data.statistics[0].total);
-        self.statistics_total_co2
-            .get_or_create(&Labels::new(site_id, &scene_data.statistics[1].unit))
-            .set(scene_data.statistics[1].total);
-        self.statistics_total_money
-            .get_or_create(&Labels::new(site_id, &scene_data.statistics[2].unit))
-            .set(scene_data.statistics[2].total);
+        let Some(info) = &scene_data.solarbank_info else {
+            return;
+        };
 
-        let solar_power_labels = Labels::new(site_id, &scene_data.solarbank_info.power_unit);
+        let solar_power_labels = Labels::new(site, &info.power_unit);
 
         self.solar_power_1
             .get_or_create(&solar_power_labels)
-            .set(scene_data.solarbank_info.solar_power_1);
+            .set(info.solar_power_1 as u32);
         self.solar_power_2
             .get_or_create(&solar_power_labels)
-            .set(scene_data.solarbank_info.solar_power_2);
+            .set(info.solar_power_2 as u32);
         self.solar_power_3
             .get_or_create(&solar_power_labels)
-            .set(scene_data.solarbank_info.solar_power_3);
+            .set(info.solar_power_3 as u32);
         self.solar_power_4
             .get_or_create(&solar_power_labels)
-            .set(scene_data.solarbank_info.solar_power_4);
+            .set(info.solar_power_4 as u32);
 
-        for solarbank in &scene_data.solarbank_info.solarbank_list {
+        for solarbank in &info.solarbank_list {
             let solarbank_labels =
-                SolarbankLabels::new(site_id, &solarbank.power_unit, &solarbank.device_sn);
+                SolarbankLabels::new(site, &solarbank.power_unit, &solarbank.device_sn);
 
             self.solarbank_battery_power
                 .get_or_create(&solarbank_labels)
                 .set(solarbank.battery_power);
             self.solarbank_charging_power
                 .get_or_create(&solarbank_labels)
-                .set(solarbank.charging_power);
+                .set(solarbank.charging_power as u32);
             self.solarbank_output_power
                 .get_or_create(&solarbank_labels)
-                .set(solarbank.output_power);
+                .set(solarbank.output_power as u32);
             self.solarbank_photovoltaic_power
                 .get_or_create(&solarbank_labels)
-                .set(solarbank.photovoltaic_power);
+                .set(solarbank.photovoltaic_power as u32);
         }
 
-        let solarbank_total_labels = Labels::new(site_id, &scene_data.solarbank_info.power_unit);
+        let solarbank_total_labels = Labels::new(site, &info.power_unit);
 
         self.solarbank_total_battery_power
             .get_or_create(&solarbank_total_labels)
-            .set(scene_data.solarbank_info.total_battery_power);
+            .set(info.total_battery_power);
         self.solarbank_total_charging_power
             .get_or_create(&solarbank_total_labels)
-            .set(scene_data.solarbank_info.total_charging_power);
+            .set(info.total_charging_power as u32);
         self.solarbank_total_output_power
             .get_or_create(&solarbank_total_labels)
-            .set(scene_data.solarbank_info.total_output_power);
+            .set(info.total_output_power);
         self.solarbank_total_photovoltaic_power
             .get_or_create(&solarbank_total_labels)
-            .set(scene_data.solarbank_info.total_photovoltaic_power);
+            .set(info.total_photovoltaic_power as u32);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub registry: Registry,
+    battery_capacity: RwLock<HashMap<String, f64>>,
+    sites: RwLock<HashMap<String, Site>>,
+    legacy: Option<LegacyMetrics>,
+    /// Price series by site, to drop a previous currency
+    price_labels: RwLock<HashMap<String, CurrencyLabels>>,
+    /// Firmware series by device, to drop previous versions
+    firmware_labels: RwLock<HashMap<String, FirmwareLabels>>,
+    /// Raw series by site, to drop fields that disappeared
+    raw_labels: RwLock<HashMap<String, Vec<RawLabels>>>,
+
+    pub site_info: GaugeU32<SiteInfoLabels>,
+
+    pub home_load_power_watts: GaugeF64<SiteLabels>,
+    pub other_load_power_watts: GaugeF64<SiteLabels>,
+
+    pub grid_to_home_power_watts: GaugeF64<SiteLabels>,
+    pub photovoltaic_to_grid_power_watts: GaugeF64<SiteLabels>,
+
+    pub home_charging_power_watts: GaugeF64<SiteLabels>,
+
+    pub statistics_total_energy_wh: CounterF64<SiteLabels>,
+    pub statistics_total_co2_grams: CounterF64<SiteLabels>,
+    pub statistics_money_saved: CounterF64<CurrencyLabels>,
+
+    pub solar_power_watts: GaugeF64<SolarLabels>,
+
+    pub solarbank_charging_power_watts: GaugeF64<DeviceLabels>,
+    pub solarbank_output_power_watts: GaugeF64<DeviceLabels>,
+    pub solarbank_photovoltaic_power_watts: GaugeF64<DeviceLabels>,
+
+    // Solarbank 2 and Smart Meter, only present for these devices
+    pub solarbank_solar_power_watts: GaugeF64<DeviceInputLabels>,
+    pub solarbank_ac_output_power_watts: GaugeF64<DeviceLabels>,
+    pub solarbank_battery_discharge_power_watts: GaugeF64<DeviceLabels>,
+    pub solarbank_grid_to_battery_power_watts: GaugeF64<DeviceLabels>,
+    pub solarbank_total_ac_output_power_watts: GaugeF64<SiteLabels>,
+    pub solarbank_total_battery_discharge_power_watts: GaugeF64<SiteLabels>,
+    pub grid_to_battery_power_watts: GaugeF64<SiteLabels>,
+    pub smartmeter_grid_import_power_watts: GaugeF64<DeviceLabels>,
+    pub smartmeter_grid_export_power_watts: GaugeF64<DeviceLabels>,
+
+    pub smartplug_power_watts: GaugeF64<DeviceLabels>,
+    pub smartplug_switch_on: GaugeF64<DeviceLabels>,
+    pub smartplug_energy_today_wh: GaugeF64<DeviceLabels>,
+
+    pub pps_battery_soc_percent: GaugeF64<PpsLabels>,
+    pub pps_ac_input_power_watts: GaugeF64<PpsLabels>,
+    pub pps_dc_input_power_watts: GaugeF64<PpsLabels>,
+    pub pps_ac_output_power_watts: GaugeF64<PpsLabels>,
+    pub pps_dc_output_power_watts: GaugeF64<PpsLabels>,
+    pub pps_temperature_celsius: GaugeF64<PpsLabels>,
+
+    // Received over MQTT, only present for mapped fields
+    pub realtime_messages_total: Family<DeviceLabels, Counter>,
+    pub realtime_battery_soc_percent: GaugeF64<DeviceLabels>,
+    pub realtime_photovoltaic_power_watts: GaugeF64<DeviceLabels>,
+    pub realtime_output_power_watts: GaugeF64<DeviceLabels>,
+    pub realtime_charging_power_watts: GaugeF64<DeviceLabels>,
+    pub realtime_battery_discharge_power_watts: GaugeF64<DeviceLabels>,
+    pub realtime_ac_output_power_watts: GaugeF64<DeviceLabels>,
+    pub realtime_temperature_celsius: GaugeF64<DeviceLabels>,
+
+    pub solarbank_battery_soc_percent: GaugeF64<DeviceLabels>,
+    pub solarbank_battery_energy_wh: GaugeF64<DeviceLabels>,
+    pub solarbank_battery_time_to_full_seconds: GaugeF64<DeviceLabels>,
+    pub solarbank_battery_time_to_empty_seconds: GaugeF64<DeviceLabels>,
+
+    pub solarbank_total_charging_power_watts: GaugeF64<SiteLabels>,
+    pub solarbank_total_output_power_watts: GaugeF64<SiteLabels>,
+    pub solarbank_total_photovoltaic_power_watts: GaugeF64<SiteLabels>,
+
+    pub power_flow_watts: GaugeF64<FlowLabels>,
+    pub self_consumption_ratio: GaugeF64<SiteLabels>,
+    pub autarky_ratio: GaugeF64<SiteLabels>,
+
+    pub schedule_output_power_watts: GaugeF64<SiteLabels>,
+
+    pub solarbank_firmware_info: GaugeU32<FirmwareLabels>,
+    pub solarbank_update_available: GaugeU32<DeviceLabels>,
+
+    pub raw: GaugeF64<RawLabels>,
+
+    pub solar_production_wh: CounterF64<SiteLabels>,
+    pub home_load_wh: CounterF64<SiteLabels>,
+    pub grid_import_wh: CounterF64<SiteLabels>,
+    pub grid_export_wh: CounterF64<SiteLabels>,
+    pub battery_charge_wh: CounterF64<SiteLabels>,
+    pub battery_discharge_wh: CounterF64<SiteLabels>,
+
+    pub electricity_price: GaugeF64<CurrencyLabels>,
+    pub feed_in_price: GaugeF64<CurrencyLabels>,
+    pub grid_price: GaugeF64<CurrencyLabels>,
+    pub savings: CounterF64<CurrencyLabels>,
+    pub feed_in_revenue: CounterF64<CurrencyLabels>,
+    pub grid_import_cost: CounterF64<CurrencyLabels>,
+    pub grid_export_cost: CounterF64<CurrencyLabels>,
+    pub savings_negative: CounterF64<CurrencyLabels>,
+    pub feed_in_revenue_negative: CounterF64<CurrencyLabels>,
+    pub grid_import_cost_negative: CounterF64<CurrencyLabels>,
+    pub grid_export_cost_negative: CounterF64<CurrencyLabels>,
+
+    pub solarbank_photovoltaic_wh: CounterF64<DeviceLabels>,
+    pub solarbank_battery_charge_wh: CounterF64<DeviceLabels>,
+    pub solarbank_battery_discharge_wh: CounterF64<DeviceLabels>,
+}
+
+/// Sets a counter to a total that is tracked elsewhere. A decreasing total is a
+/// reset, so the series is recreated.
+fn set_counter<S: Clone + Hash + Eq>(family: &CounterF64<S>, labels: &S, total: f64) {
+    let delta = total - family.get_or_create(labels).get();
+
+    if delta < 0.0 {
+        family.remove(labels);
+        family.get_or_create(labels).inc_by(total);
+    } else if delta > 0.0 {
+        family.get_or_create(labels).inc_by(delta);
+    }
+}
+
+fn set_optional<S: Clone + Hash + Eq>(family: &GaugeF64<S>, labels: &S, value: Option<f64>) {
+    match value {
+        Some(value) => {
+            family.get_or_create(labels).set(value);
+        }
+        None => {
+            family.remove(labels);
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(legacy: bool) -> Self {
+        let mut metrics = Self::default();
+
+        if legacy {
+            let legacy = LegacyMetrics::default();
+            legacy.register(&mut metrics.registry);
+            metrics.legacy = Some(legacy);
+        }
+
+        metrics.registry.register(
+            "anker_solix_site_info",
+            "Site metadata, always 1",
+            metrics.site_info.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_home_load_power",
+            "Home load power",
+            Unit::Other("watts".into()),
+            metrics.home_load_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_other_load_power",
+            "Other load power",
+            Unit::Other("watts".into()),
+            metrics.other_load_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_grid_to_home_power",
+            "Grid to home power",
+            Unit::Other("watts".into()),
+            metrics.grid_to_home_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_photovoltaic_to_grid_power",
+            "Photovoltaic to grid power",
+            Unit::Other("watts".into()),
+            metrics.photovoltaic_to_grid_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_home_charging_power",
+            "Home charging power",
+            Unit::Other("watts".into()),
+            metrics.home_charging_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_statistics_total_energy",
+            "Statistics total energy",
+            Unit::Other("wh".into()),
+            metrics.statistics_total_energy_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_statistics_total_co2",
+            "Statistics total CO2 saved",
+            Unit::Grams,
+            metrics.statistics_total_co2_grams.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_statistics_money_saved",
+            "Statistics total money saved",
+            metrics.statistics_money_saved.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_electricity_price",
+            "Grid import price per kWh in effect",
+            metrics.electricity_price.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_feed_in_price",
+            "Feed-in compensation per kWh in effect",
+            metrics.feed_in_price.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_grid_price",
+            "Dynamic grid price per kWh of the price source",
+            metrics.grid_price.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_savings",
+            "Grid import avoided by solar and battery, at the import price",
+            metrics.savings.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_feed_in_revenue",
+            "Grid export at the feed-in compensation",
+            metrics.feed_in_revenue.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_grid_import_cost",
+            "Grid import at the dynamic grid price",
+            metrics.grid_import_cost.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_grid_export_cost",
+            "Grid export at the dynamic grid price",
+            metrics.grid_export_cost.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_savings_negative",
+            "Grid import avoided by solar and battery, at negative import prices",
+            metrics.savings_negative.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_feed_in_revenue_negative",
+            "Grid export at negative feed-in compensation",
+            metrics.feed_in_revenue_negative.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_grid_import_cost_negative",
+            "Grid import at negative dynamic grid prices",
+            metrics.grid_import_cost_negative.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_grid_export_cost_negative",
+            "Grid export at negative dynamic grid prices",
+            metrics.grid_export_cost_negative.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solar_power",
+            "Solar power by input",
+            Unit::Other("watts".into()),
+            metrics.solar_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_charging_power",
+            "Solarbank charging power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_charging_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_output_power",
+            "Solarbank output power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_photovoltaic_power",
+            "Solarbank photovoltaic power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_photovoltaic_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_solar_power",
+            "Solarbank solar power by MPPT input",
+            Unit::Other("watts".into()),
+            metrics.solarbank_solar_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_ac_output_power",
+            "Solarbank AC socket output power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_ac_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_discharge_power",
+            "Solarbank battery discharge power as reported",
+            Unit::Other("watts".into()),
+            metrics.solarbank_battery_discharge_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_grid_to_battery_power",
+            "Solarbank AC charging power from the grid",
+            Unit::Other("watts".into()),
+            metrics.solarbank_grid_to_battery_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_soc",
+            "Solarbank battery state of charge",
+            Unit::Other("percent".into()),
+            metrics.solarbank_battery_soc_percent.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_energy",
+            "Solarbank battery stored energy",
+            Unit::Other("wh".into()),
+            metrics.solarbank_battery_energy_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_time_to_full",
+            "Solarbank battery time until full at the current charging power",
+            Unit::Seconds,
+            metrics.solarbank_battery_time_to_full_seconds.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_time_to_empty",
+            "Solarbank battery time until empty at the current discharging power",
+            Unit::Seconds,
+            metrics.solarbank_battery_time_to_empty_seconds.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_total_charging_power",
+            "Solarbank total charging power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_total_charging_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_total_output_power",
+            "Solarbank total output power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_total_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_total_photovoltaic_power",
+            "Solarbank total photovoltaic power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_total_photovoltaic_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_total_ac_output_power",
+            "Solarbank total AC socket output power",
+            Unit::Other("watts".into()),
+            metrics.solarbank_total_ac_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_total_battery_discharge_power",
+            "Solarbank total battery discharge power as reported",
+            Unit::Other("watts".into()),
+            metrics
+                .solarbank_total_battery_discharge_power_watts
+                .clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_grid_to_battery_power",
+            "Grid to battery power",
+            Unit::Other("watts".into()),
+            metrics.grid_to_battery_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_smartmeter_grid_import_power",
+            "Grid import power measured by the smart meter",
+            Unit::Other("watts".into()),
+            metrics.smartmeter_grid_import_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_smartmeter_grid_export_power",
+            "Grid export power measured by the smart meter",
+            Unit::Other("watts".into()),
+            metrics.smartmeter_grid_export_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_smartplug_power",
+            "Smart plug power",
+            Unit::Other("watts".into()),
+            metrics.smartplug_power_watts.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_smartplug_switch_on",
+            "Smart plug switch state, 1 if on",
+            metrics.smartplug_switch_on.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_smartplug_energy_today",
+            "Smart plug energy since midnight",
+            Unit::Other("wh".into()),
+            metrics.smartplug_energy_today_wh.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_battery_soc",
+            "Power station battery state of charge",
+            Unit::Other("percent".into()),
+            metrics.pps_battery_soc_percent.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_ac_input_power",
+            "Power station AC input power",
+            Unit::Other("watts".into()),
+            metrics.pps_ac_input_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_dc_input_power",
+            "Power station DC input power",
+            Unit::Other("watts".into()),
+            metrics.pps_dc_input_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_ac_output_power",
+            "Power station AC output power",
+            Unit::Other("watts".into()),
+            metrics.pps_ac_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_dc_output_power",
+            "Power station DC output power",
+            Unit::Other("watts".into()),
+            metrics.pps_dc_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_pps_temperature",
+            "Power station battery temperature",
+            Unit::Celsius,
+            metrics.pps_temperature_celsius.clone(),
+        );
+
+        metrics.registry.register(
+            "anker_solix_realtime_messages",
+            "Device messages received over MQTT",
+            metrics.realtime_messages_total.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_battery_soc",
+            "Solarbank battery state of charge received over MQTT",
+            Unit::Other("percent".into()),
+            metrics.realtime_battery_soc_percent.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_photovoltaic_power",
+            "Solarbank photovoltaic power received over MQTT",
+            Unit::Other("watts".into()),
+            metrics.realtime_photovoltaic_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_output_power",
+            "Solarbank output power received over MQTT",
+            Unit::Other("watts".into()),
+            metrics.realtime_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_charging_power",
+            "Solarbank charging power received over MQTT",
+            Unit::Other("watts".into()),
+            metrics.realtime_charging_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_battery_discharge_power",
+            "Solarbank battery discharge power received over MQTT",
+            Unit::Other("watts".into()),
+            metrics.realtime_battery_discharge_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_ac_output_power",
+            "Solarbank AC socket output power received over MQTT",
+            Unit::Other("watts".into()),
+            metrics.realtime_ac_output_power_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_realtime_temperature",
+            "Solarbank temperature received over MQTT",
+            Unit::Celsius,
+            metrics.realtime_temperature_celsius.clone(),
+        );
+
+        metrics.registry.register(
+            "anker_solix_solarbank_firmware_info",
+            "Solarbank firmware versions, always 1",
+            metrics.solarbank_firmware_info.clone(),
+        );
+        metrics.registry.register(
+            "anker_solix_solarbank_update_available",
+            "Whether a firmware update is offered for the Solarbank",
+            metrics.solarbank_update_available.clone(),
+        );
+
+        metrics.registry.register(
+            "anker_solix_raw",
+            "Numeric field of the cloud data as reported, by JSON path",
+            metrics.raw.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_power_flow",
+            "Power flowing between solar, battery, home and grid",
+            Unit::Other("watts".into()),
+            metrics.power_flow_watts.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_self_consumption",
+            "Share of the solar production used on site",
+            Unit::Other("ratio".into()),
+            metrics.self_consumption_ratio.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_autarky",
+            "Share of the home load not covered by the grid",
+            Unit::Other("ratio".into()),
+            metrics.autarky_ratio.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_schedule_output_power",
+            "Target output power of the active home load schedule slot, 0 if output is off",
+            Unit::Other("watts".into()),
+            metrics.schedule_output_power_watts.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solar_production",
+            "Solar production energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.solar_production_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_home_load",
+            "Home load energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.home_load_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_grid_import",
+            "Grid import energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.grid_import_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_grid_export",
+            "Grid export energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.grid_export_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_battery_charge",
+            "Battery charge energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.battery_charge_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_battery_discharge",
+            "Battery discharge energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.battery_discharge_wh.clone(),
+        );
+
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_photovoltaic",
+            "Solarbank photovoltaic energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.solarbank_photovoltaic_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_charge",
+            "Solarbank battery charge energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.solarbank_battery_charge_wh.clone(),
+        );
+        metrics.registry.register_with_unit(
+            "anker_solix_solarbank_battery_discharge",
+            "Solarbank battery discharge energy, integrated from power",
+            Unit::Other("wh".into()),
+            metrics.solarbank_battery_discharge_wh.clone(),
+        );
+
+        metrics
+    }
+
+    pub fn set_battery_capacity(&self, battery_capacity: HashMap<String, f64>) {
+        *self.battery_capacity.write().unwrap() = battery_capacity;
+    }
+
+    /// Updates the site info and the site labels attached to every series. Series
+    /// with previous labels are only dropped on restart.
+    pub fn set_sites(
+        &self,
+        sites: &[data::SiteList],
+        site_name_label: bool,
+        site_labels: &HashMap<String, BTreeMap<String, String>>,
+    ) {
+        self.site_info.clear();
+
+        let mut map = self.sites.write().unwrap();
+        map.clear();
+
+        for site in sites {
+            self.site_info
+                .get_or_create(&SiteInfoLabels {
+                    site_id: site.site_id.clone(),
+                    site_name: site.site_name.clone(),
+                    ms_type: site
+                        .ms_type
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                    power_site_type: site
+                        .power_site_type
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                })
+                .set(1);
+
+            let mut labels = Vec::new();
+
+            if site_name_label {
+                labels.push(("site_name".to_string(), site.site_name.clone()));
+            }
+
+            for (name, value) in site_labels.get(&site.site_id).into_iter().flatten() {
+                if !is_valid_label(name) {
+                    log::warn!(
+                        "Ignoring invalid or reserved label {name:?} of site {}",
+                        site.site_id
+                    );
+                    continue;
+                }
+
+                labels.push((name.clone(), value.clone()));
+            }
+
+            map.insert(
+                site.site_id.clone(),
+                Site {
+                    site_id: site.site_id.clone(),
+                    labels: labels.into(),
+                },
+            );
+        }
+    }
+
+    fn site(&self, site_id: &str) -> Site {
+        self.sites
+            .read()
+            .unwrap()
+            .get(site_id)
+            .cloned()
+            .unwrap_or_else(|| Site {
+                site_id: site_id.into(),
+                labels: Arc::new([]),
+            })
+    }
+
+    pub fn gather(&self) -> String {
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry).unwrap();
+
+        buffer
+    }
+
+    /// Updates the legacy metrics, if enabled, with the data as returned by the cloud.
+    pub fn update_legacy(&self, site_id: &str, scene_data: &data::ScenInfo) {
+        if let Some(legacy) = &self.legacy {
+            legacy.update(&self.site(site_id), scene_data);
+        }
+    }
+
+    /// Expects the data to be normalized to SI base units, see [`crate::units::normalize`].
+    pub fn update(&self, site_id: &str, scene_data: &data::ScenInfo) {
+        let site = self.site(site_id);
+        let site_labels = SiteLabels { site: site.clone() };
+
+        self.home_load_power_watts
+            .get_or_create(&site_labels)
+            .set(scene_data.home_load_power);
+        self.other_load_power_watts
+            .get_or_create(&site_labels)
+            .set(scene_data.other_loads_power);
+
+        // Sites with power stations only have neither grid, home nor Solarbank info
+        if let Some(grid) = &scene_data.grid_info {
+            self.grid_to_home_power_watts
+                .get_or_create(&site_labels)
+                .set(grid.grid_to_home_power);
+            self.photovoltaic_to_grid_power_watts
+                .get_or_create(&site_labels)
+                .set(grid.photovoltaic_to_grid_power);
+            set_optional(
+                &self.grid_to_battery_power_watts,
+                &site_labels,
+                grid.grid_to_battery_power,
+            );
+        }
+
+        if let Some(home) = &scene_data.home_info {
+            self.home_charging_power_watts
+                .get_or_create(&site_labels)
+                .set(home.charging_power);
+        }
+
+        if let Some(statistic) = scene_data.statistics.first() {
+            set_counter(
+                &self.statistics_total_energy_wh,
+                &site_labels,
+                statistic.total,
+            );
+        }
+        if let Some(statistic) = scene_data.statistics.get(1) {
+            set_counter(
+                &self.statistics_total_co2_grams,
+                &site_labels,
+                statistic.total,
+            );
+        }
+        if let Some(statistic) = scene_data.statistics.get(2) {
+            let currency_labels = CurrencyLabels {
+                site: site.clone(),
+                currency: statistic.unit.clone(),
+            };
+            set_counter(
+                &self.statistics_money_saved,
+                &currency_labels,
+                statistic.total,
+            );
+        }
+
+        if let Some(info) = &scene_data.solarbank_info {
+            for (input, watts) in [
+                info.solar_power_1,
+                info.solar_power_2,
+                info.solar_power_3,
+                info.solar_power_4,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                self.solar_power_watts
+                    .get_or_create(&SolarLabels {
+                        site: site.clone(),
+                        input: (input + 1).to_string(),
+                    })
+                    .set(watts);
+            }
+
+            for solarbank in &info.solarbank_list {
+                let device_labels = DeviceLabels {
+                    site: site.clone(),
+                    device_sn: solarbank.device_sn.clone(),
+                };
+
+                self.solarbank_charging_power_watts
+                    .get_or_create(&device_labels)
+                    .set(solarbank.charging_power);
+                self.solarbank_output_power_watts
+                    .get_or_create(&device_labels)
+                    .set(solarbank.output_power);
+                self.solarbank_photovoltaic_power_watts
+                    .get_or_create(&device_labels)
+                    .set(solarbank.photovoltaic_power);
+
+                for (input, watts) in [
+                    solarbank.solar_power_1,
+                    solarbank.solar_power_2,
+                    solarbank.solar_power_3,
+                    solarbank.solar_power_4,
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    let input_labels = DeviceInputLabels {
+                        site: site.clone(),
+                        device_sn: solarbank.device_sn.clone(),
+                        input: (input + 1).to_string(),
+                    };
+                    set_optional(&self.solarbank_solar_power_watts, &input_labels, watts);
+                }
+                set_optional(
+                    &self.solarbank_ac_output_power_watts,
+                    &device_labels,
+                    solarbank.ac_power,
+                );
+                set_optional(
+                    &self.solarbank_battery_discharge_power_watts,
+                    &device_labels,
+                    solarbank.battery_discharge_power,
+                );
+                set_optional(
+                    &self.solarbank_grid_to_battery_power_watts,
+                    &device_labels,
+                    solarbank.grid_to_battery_power,
+                );
+
+                let battery = Battery::new(solarbank, &self.battery_capacity.read().unwrap());
+
+                self.solarbank_battery_soc_percent
+                    .get_or_create(&device_labels)
+                    .set(battery.soc);
+
+                set_optional(
+                    &self.solarbank_battery_energy_wh,
+                    &device_labels,
+                    battery.energy(),
+                );
+                set_optional(
+                    &self.solarbank_battery_time_to_full_seconds,
+                    &device_labels,
+                    battery.time_to_full(),
+                );
+                set_optional(
+                    &self.solarbank_battery_time_to_empty_seconds,
+                    &device_labels,
+                    battery.time_to_empty(),
+                );
+            }
+
+            self.solarbank_total_charging_power_watts
+                .get_or_create(&site_labels)
+                .set(info.total_charging_power);
+            self.solarbank_total_output_power_watts
+                .get_or_create(&site_labels)
+                .set(info.total_output_power);
+            self.solarbank_total_photovoltaic_power_watts
+                .get_or_create(&site_labels)
+                .set(info.total_photovoltaic_power);
+            set_optional(
+                &self.solarbank_total_ac_output_power_watts,
+                &site_labels,
+                info.ac_power,
+            );
+            set_optional(
+                &self.solarbank_total_battery_discharge_power_watts,
+                &site_labels,
+                info.battery_discharge_power,
+            );
+        }
+
+        if let Some(smartmeter) = &scene_data.smartmeter_info {
+            let smartmeter_labels = DeviceLabels {
+                site: site.clone(),
+                device_sn: smartmeter.device_sn.clone(),
+            };
+
+            set_optional(
+                &self.smartmeter_grid_import_power_watts,
+                &smartmeter_labels,
+                smartmeter.grid_to_home_power,
+            );
+            set_optional(
+                &self.smartmeter_grid_export_power_watts,
+                &smartmeter_labels,
+                smartmeter.photovoltaic_to_grid_power,
+            );
+        }
+
+        let plugs = scene_data
+            .smart_plug_info
+            .iter()
+            .flat_map(|info| &info.smartplug_list);
+        for plug in plugs {
+            let plug_labels = DeviceLabels {
+                site: site.clone(),
+                device_sn: plug.device_sn.clone(),
+            };
+
+            set_optional(&self.smartplug_power_watts, &plug_labels, plug.plug_power);
+            set_optional(
+                &self.smartplug_switch_on,
+                &plug_labels,
+                plug.switch_status.map(|on| on as u8 as f64),
+            );
+            set_optional(
+                &self.smartplug_energy_today_wh,
+                &plug_labels,
+                plug.energy_today,
+            );
+        }
+
+        for (flow, watts) in Flows::new(scene_data).iter().flat_map(Flows::iter) {
+            self.power_flow_watts
+                .get_or_create(&FlowLabels {
+                    site: site.clone(),
+                    flow: flow.into(),
+                })
+                .set(watts);
+        }
+
+        // Ratios are undefined without production or load, e.g. at night
+        set_optional(
+            &self.self_consumption_ratio,
+            &site_labels,
+            flows::self_consumption_ratio(scene_data),
+        );
+        set_optional(
+            &self.autarky_ratio,
+            &site_labels,
+            flows::autarky_ratio(scene_data),
+        );
 
         log::info!("Updated metrics for site {site_id}");
     }
+
+    /// Replaces the power station series, which are listed on the homepage
+    pub fn update_pps(&self, pps_list: &[data::Pps]) {
+        let families = [
+            &self.pps_battery_soc_percent,
+            &self.pps_ac_input_power_watts,
+            &self.pps_dc_input_power_watts,
+            &self.pps_ac_output_power_watts,
+            &self.pps_dc_output_power_watts,
+            &self.pps_temperature_celsius,
+        ];
+        for family in families {
+            family.clear();
+        }
+
+        for pps in pps_list {
+            let labels = PpsLabels {
+                device_sn: pps.device_sn.clone(),
+            };
+            let values = [
+                pps.battery_power,
+                pps.ac_input_power,
+                pps.dc_input_power,
+                pps.ac_output_power,
+                pps.dc_output_power,
+                pps.temperature,
+            ];
+
+            for (family, value) in families.iter().zip(values) {
+                set_optional(family, &labels, value);
+            }
+        }
+    }
+
+    /// Sets the firmware versions of a Solarbank. Whether an update is available is
+    /// only exported while its OTA info is known.
+    pub fn update_firmware(
+        &self,
+        site_id: &str,
+        device_sn: &str,
+        current_version: Option<&str>,
+        ota: Option<&data::OtaInfo>,
+    ) {
+        let site = self.site(site_id);
+        let labels = FirmwareLabels {
+            site: site.clone(),
+            device_sn: device_sn.into(),
+            current_version: current_version.unwrap_or_default().into(),
+            available_version: ota
+                .and_then(data::OtaInfo::available_version)
+                .unwrap_or_default()
+                .into(),
+        };
+
+        let mut firmware_labels = self.firmware_labels.write().unwrap();
+        if let Some(previous) = firmware_labels.insert(device_sn.to_string(), labels.clone())
+            && previous != labels
+        {
+            self.solarbank_firmware_info.remove(&previous);
+        }
+        self.solarbank_firmware_info.get_or_create(&labels).set(1);
+
+        let device_labels = DeviceLabels {
+            site,
+            device_sn: device_sn.into(),
+        };
+        match ota {
+            Some(ota) => {
+                self.solarbank_update_available
+                    .get_or_create(&device_labels)
+                    .set(ota.update_available(current_version) as u32);
+            }
+            None => {
+                self.solarbank_update_available.remove(&device_labels);
+            }
+        }
+    }
+
+    /// Sets the prices per kWh in effect, removing the series of the site without
+    pub fn update_price(&self, site_id: &str, price: Option<&Price>) {
+        let mut price_labels = self.price_labels.write().unwrap();
+        if let Some(previous) = price_labels.remove(site_id) {
+            self.electricity_price.remove(&previous);
+            self.feed_in_price.remove(&previous);
+            self.grid_price.remove(&previous);
+        }
+
+        let Some(price) = price else {
+            return;
+        };
+        let labels = CurrencyLabels {
+            site: self.site(site_id),
+            currency: price.currency.clone(),
+        };
+        set_optional(&self.electricity_price, &labels, price.import);
+        set_optional(&self.feed_in_price, &labels, price.feed_in);
+        set_optional(&self.grid_price, &labels, price.grid);
+        price_labels.insert(site_id.to_string(), labels);
+    }
+
+    pub fn update_savings(&self, savings: &Savings) {
+        for (key, total) in savings.totals() {
+            let family = match key.account {
+                Account::Savings => &self.savings,
+                Account::FeedInRevenue => &self.feed_in_revenue,
+                Account::GridImportCost => &self.grid_import_cost,
+                Account::GridExportCost => &self.grid_export_cost,
+                Account::SavingsNegative => &self.savings_negative,
+                Account::FeedInRevenueNegative => &self.feed_in_revenue_negative,
+                Account::GridImportCostNegative => &self.grid_import_cost_negative,
+                Account::GridExportCostNegative => &self.grid_export_cost_negative,
+            };
+
+            let labels = CurrencyLabels {
+                site: self.site(&key.site_id),
+                currency: key.currency.clone(),
+            };
+            set_counter(family, &labels, total);
+        }
+    }
+
+    /// Replaces the raw series of a site with the given paths and values
+    pub fn update_raw(&self, site_id: &str, leaves: &[(String, f64)]) {
+        let site = self.site(site_id);
+
+        let mut raw_labels = self.raw_labels.write().unwrap();
+        for labels in raw_labels.remove(site_id).unwrap_or_default() {
+            self.raw.remove(&labels);
+        }
+
+        let labels: Vec<RawLabels> = leaves
+            .iter()
+            .map(|(path, value)| {
+                let labels = RawLabels {
+                    site: site.clone(),
+                    path: path.clone(),
+                };
+                self.raw.get_or_create(&labels).set(*value);
+                labels
+            })
+            .collect();
+
+        raw_labels.insert(site_id.to_string(), labels);
+    }
+
+    pub fn count_realtime_message(&self, site_id: &str, device_sn: &str) {
+        let labels = DeviceLabels {
+            site: self.site(site_id),
+            device_sn: device_sn.into(),
+        };
+
+        self.realtime_messages_total.get_or_create(&labels).inc();
+    }
+
+    /// Sets the values a device message carried, keeping the others as they were
+    /// last received
+    pub fn update_realtime(&self, site_id: &str, device_sn: &str, realtime: &Realtime) {
+        let labels = DeviceLabels {
+            site: self.site(site_id),
+            device_sn: device_sn.into(),
+        };
+        let values = [
+            (&self.realtime_battery_soc_percent, realtime.battery_soc),
+            (
+                &self.realtime_photovoltaic_power_watts,
+                realtime.photovoltaic_power,
+            ),
+            (&self.realtime_output_power_watts, realtime.output_power),
+            (&self.realtime_charging_power_watts, realtime.charging_power),
+            (
+                &self.realtime_battery_discharge_power_watts,
+                realtime.battery_discharge_power,
+            ),
+            (
+                &self.realtime_ac_output_power_watts,
+                realtime.ac_output_power,
+            ),
+            (&self.realtime_temperature_celsius, realtime.temperature),
+        ];
+
+        for (family, value) in values {
+            if let Some(value) = value {
+                family.get_or_create(&labels).set(value);
+            }
+        }
+    }
+
+    /// Sets the target output power of the active slot, removing the series without
+    /// one
+    pub fn update_schedule(&self, site_id: &str, slot: Option<&data::ScheduleSlot>) {
+        let labels = SiteLabels {
+            site: self.site(site_id),
+        };
+        let power = slot.map(|slot| match slot.turn_on {
+            true => slot.power() as f64,
+            false => 0.0,
+        });
+
+        set_optional(&self.schedule_output_power_watts, &labels, power);
+    }
+
+    pub fn update_energy(&self, energy: &Energy) {
+        for (key, wh) in energy.totals() {
+            match &key.device_sn {
+                None => {
+                    let family = match key.flow {
+                        Flow::SolarProduction => &self.solar_production_wh,
+                        Flow::HomeLoad => &self.home_load_wh,
+                        Flow::GridImport => &self.grid_import_wh,
+                        Flow::GridExport => &self.grid_export_wh,
+                        Flow::BatteryCharge => &self.battery_charge_wh,
+                        Flow::BatteryDischarge => &self.battery_discharge_wh,
+                    };
+
+                    let labels = SiteLabels {
+                        site: self.site(&key.site_id),
+                    };
+                    set_counter(family, &labels, wh);
+                }
+                Some(device_sn) => {
+                    let family = match key.flow {
+                        Flow::SolarProduction => &self.solarbank_photovoltaic_wh,
+                        Flow::BatteryCharge => &self.solarbank_battery_charge_wh,
+                        Flow::BatteryDischarge => &self.solarbank_battery_discharge_wh,
+                        Flow::HomeLoad | Flow::GridImport | Flow::GridExport => continue,
+                    };
+
+                    let labels = DeviceLabels {
+                        site: self.site(&key.site_id),
+                        device_sn: device_sn.clone(),
+                    };
+                    set_counter(family, &labels, wh);
+                }
+            }
+        }
+    }
+}
+
+fn is_valid_label(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+        && !RESERVED_LABELS.contains(&name)
 }
@@ -0,0 +1,342 @@
+pub mod payload;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, Transport};
+use serde::Deserialize;
+
+use crate::metrics::Metrics;
+use crate::solix::{self, Credentials, SolixApi};
+use crate::Config;
+use payload::{FieldMapping, Realtime};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Usage(String),
+    #[error("Failed to fetch the MQTT certificates: {0}")]
+    Api(#[from] solix::Error),
+    #[error("Failed to read recorded messages: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    /// Broker instead of the one of the account, e.g. a local broker for testing
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default = "default_port")]
+    port: u16,
+    /// Connects with the client certificate of the account, which is fetched from
+    /// the cloud. Without, the connection is plain and unauthenticated.
+    #[serde(default = "default_tls")]
+    tls: bool,
+    /// Prefix of the device topics, taken from the account with TLS
+    #[serde(default = "default_app_name")]
+    app_name: String,
+    /// Field mappings by product number, e.g. `A17C1`, extending and replacing the
+    /// default fields
+    #[serde(default)]
+    fields: HashMap<String, FieldMapping>,
+    /// File the received messages are appended to, one per line
+    #[serde(default)]
+    record_file: Option<PathBuf>,
+}
+
+fn default_port() -> u16 {
+    8883
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+fn default_app_name() -> String {
+    "anker_power".to_string()
+}
+
+impl MqttConfig {
+    /// The default fields of the product with the configured ones, `None` if neither
+    /// exist
+    fn mapping(&self, device_pn: Option<&str>) -> Option<FieldMapping> {
+        let device_pn = device_pn?;
+        let mut mapping = payload::default_mapping(device_pn);
+        if let Some(fields) = self.fields.get(device_pn) {
+            mapping.extend(fields.clone());
+        }
+
+        (!mapping.is_empty()).then_some(mapping)
+    }
+}
+
+/// Device of a site, as known from the polled data
+struct Device {
+    site_id: String,
+    device_pn: String,
+}
+
+/// Devices by serial number
+type Devices = Arc<RwLock<HashMap<String, Device>>>;
+
+fn topic(app_name: &str, device: &Device, device_sn: &str) -> String {
+    format!("dt/{app_name}/{}/{device_sn}/#", device.device_pn)
+}
+
+/// Receives the messages the devices publish to the Anker broker every few
+/// seconds and exports the mapped fields as realtime metrics. Devices are
+/// subscribed to once they are seen in the polled data.
+pub struct Subscriber {
+    client: Client,
+    app_name: String,
+    devices: Devices,
+}
+
+impl Subscriber {
+    /// Connects in the background, fetching the client certificate first with TLS
+    pub fn connect(
+        config: &MqttConfig,
+        solix: &SolixApi,
+        creds: &Credentials,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let info = match config.tls {
+            true => Some(solix.get_mqtt_info(creds)?),
+            false => None,
+        };
+
+        let host = match (&config.host, &info) {
+            (Some(host), _) => host.clone(),
+            (None, Some(info)) => info.endpoint_addr.clone(),
+            (None, None) => return Err(Error::Usage("MQTT host is required without TLS".into())),
+        };
+        let (client_id, app_name) = match &info {
+            Some(info) => (
+                format!(
+                    "android-{}-{}-{}",
+                    info.app_name, info.user_id, info.certificate_id
+                ),
+                info.app_name.clone(),
+            ),
+            None => (
+                format!("anker-solix-exporter-{}", std::process::id()),
+                config.app_name.clone(),
+            ),
+        };
+
+        let mut options = MqttOptions::new(client_id, &host, config.port);
+        options.set_keep_alive(Duration::from_secs(60));
+        if let Some(info) = &info {
+            options.set_transport(Transport::tls(
+                info.aws_root_ca1_pem.clone().into_bytes(),
+                Some((
+                    info.certificate_pem.clone().into_bytes(),
+                    info.private_key.clone().into_bytes(),
+                )),
+                None,
+            ));
+        }
+
+        let mut recorder = match &config.record_file {
+            Some(path) => Some(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+
+        let (client, mut connection) = Client::new(options, 10);
+        let devices = Devices::default();
+
+        let subscriber = Self {
+            client: client.clone(),
+            app_name: app_name.clone(),
+            devices: Arc::clone(&devices),
+        };
+        let config = config.clone();
+
+        log::info!("Connecting to MQTT broker {host}:{}", config.port);
+
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    // Subscriptions don't survive reconnects with a clean session
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker");
+                        for (device_sn, device) in devices.read().unwrap().iter() {
+                            let topic = topic(&app_name, device, device_sn);
+                            if let Err(err) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+                                log::warn!("Failed to subscribe to {topic}: {err}");
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if let Some(recorder) = &mut recorder {
+                            record(recorder, &publish.payload);
+                        }
+                        receive(&config, &devices, &metrics, &publish.payload);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("MQTT connection failed: {err}");
+                        thread::sleep(Duration::from_secs(5));
+                    }
+                }
+            }
+        });
+
+        Ok(subscriber)
+    }
+
+    /// Subscribes to a device not seen before
+    pub fn watch(&self, site_id: &str, device_sn: &str, device_pn: &str) {
+        let device = Device {
+            site_id: site_id.to_string(),
+            device_pn: device_pn.to_string(),
+        };
+        let topic = topic(&self.app_name, &device, device_sn);
+
+        let mut devices = self.devices.write().unwrap();
+        if devices.contains_key(device_sn) {
+            return;
+        }
+        devices.insert(device_sn.to_string(), device);
+
+        log::info!("Subscribing to {topic}");
+        if let Err(err) = self.client.try_subscribe(&topic, QoS::AtMostOnce) {
+            log::warn!("Failed to subscribe to {topic}: {err}");
+        }
+    }
+
+    pub fn disconnect(&self) {
+        let _ = self.client.try_disconnect();
+    }
+}
+
+/// Appends a message as a single line, for replaying it with `mqtt decode`
+fn record(recorder: &mut BufWriter<File>, message: &[u8]) {
+    let line = match serde_json::from_slice::<serde_json::Value>(message) {
+        Ok(json) => json.to_string(),
+        Err(err) => {
+            log::debug!("Not recording invalid message: {err}");
+            return;
+        }
+    };
+
+    if let Err(err) = writeln!(recorder, "{line}").and_then(|_| recorder.flush()) {
+        log::warn!("Failed to record MQTT message: {err}");
+    }
+}
+
+fn receive(config: &MqttConfig, devices: &Devices, metrics: &Metrics, message: &[u8]) {
+    let message = match payload::decode(message) {
+        Ok(message) => message,
+        Err(err) => {
+            log::debug!("Ignoring MQTT message: {err}");
+            return;
+        }
+    };
+
+    let Some(device_sn) = &message.device_sn else {
+        log::debug!("Ignoring MQTT message without device");
+        return;
+    };
+    let devices = devices.read().unwrap();
+    let Some(device) = devices.get(device_sn) else {
+        log::debug!("Ignoring MQTT message of unknown device {device_sn}");
+        return;
+    };
+
+    log::debug!(
+        "Received message {:04x} of {device_sn}: {}",
+        message.message_type,
+        message
+            .fields
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    metrics.count_realtime_message(&device.site_id, device_sn);
+
+    let Some(mapping) = config.mapping(Some(&device.device_pn)) else {
+        return;
+    };
+    let realtime = Realtime::new(&message.fields, &mapping);
+    if !realtime.is_empty() {
+        metrics.update_realtime(&device.site_id, device_sn, &realtime);
+    }
+}
+
+fn decode_file(config: Option<&MqttConfig>, path: &Path) -> Result<(), Error> {
+    for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match payload::decode(line.as_bytes()) {
+            Ok(message) => message,
+            Err(err) => {
+                println!("Line {}: {err}", index + 1);
+                continue;
+            }
+        };
+
+        println!(
+            "Line {}: {} {} message {:04x}",
+            index + 1,
+            message.device_pn.as_deref().unwrap_or("-"),
+            message.device_sn.as_deref().unwrap_or("-"),
+            message.message_type
+        );
+        for field in &message.fields {
+            println!("  {field}");
+        }
+
+        // The default fields apply without MQTT config, too
+        let mapping = match config {
+            Some(config) => config.mapping(message.device_pn.as_deref()),
+            None => message.device_pn.as_deref().map(payload::default_mapping),
+        };
+        if let Some(mapping) = mapping {
+            for (quantity, value) in Realtime::new(&message.fields, &mapping).values() {
+                println!("  {quantity}: {value}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes recorded messages, printing their fields to stdout. `args` are the
+/// arguments after the subcommand and config.
+pub fn run(config: &Config, args: &[String]) -> Result<(), Error> {
+    let mut args = args.iter();
+    match args.next().map(String::as_str) {
+        Some("decode") => {}
+        Some(other) => return Err(Error::Usage(format!("Unknown action {other:?}"))),
+        None => return Err(Error::Usage("Missing action, expected decode".into())),
+    }
+
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => match args.next() {
+                Some(value) => file = Some(PathBuf::from(value)),
+                None => return Err(Error::Usage(format!("Missing value for {arg}"))),
+            },
+            other => return Err(Error::Usage(format!("Unknown argument {other:?}"))),
+        }
+    }
+
+    let Some(file) = file else {
+        return Err(Error::Usage("--file is required".into()));
+    };
+
+    decode_file(config.mqtt(), &file)
+}
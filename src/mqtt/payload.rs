@@ -0,0 +1,538 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use base64::Engine;
+use serde::Deserialize;
+use serde_with::{json::JsonString, serde_as};
+
+/// Magic bytes starting every device payload
+const MAGIC: [u8; 2] = [0xff, 0x09];
+
+/// Magic, length, protocol pattern and message type
+const HEADER_LEN: usize = 9;
+
+/// Field ids start at `a1`, anything below is part of the header
+const FIRST_FIELD: u8 = 0xa1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid payload encoding: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invalid payload: {0}")]
+    Format(String),
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Head {
+    #[serde(default)]
+    device_pn: Option<String>,
+    #[serde(default)]
+    device_sn: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Payload {
+    #[serde(default)]
+    device_sn: Option<String>,
+    /// Base64 of the binary payload
+    data: String,
+}
+
+/// Message as published by the devices, with the payload as JSON string
+#[serde_as]
+#[derive(Deserialize, Debug)]
+struct Envelope {
+    #[serde(default)]
+    head: Head,
+    #[serde_as(as = "JsonString")]
+    payload: Payload,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(f64),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{text:?}"),
+            Value::Number(number) => write!(f, "{number}"),
+            Value::Bytes(bytes) => write!(f, "0x{}", hex::encode(bytes)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub id: u8,
+    pub value: Value,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x} = {}", self.id, self.value)
+    }
+}
+
+/// Decoded device message
+#[derive(Debug)]
+pub struct Message {
+    pub device_pn: Option<String>,
+    pub device_sn: Option<String>,
+    pub message_type: u16,
+    pub fields: Vec<Field>,
+}
+
+/// Decodes a message as published on the `dt/` topics of the devices
+pub fn decode(message: &[u8]) -> Result<Message, Error> {
+    let envelope: Envelope = serde_json::from_slice(message)?;
+    let data = base64::engine::general_purpose::STANDARD.decode(envelope.payload.data.trim())?;
+    let (message_type, fields) = parse(&data)?;
+
+    Ok(Message {
+        device_pn: envelope.head.device_pn,
+        device_sn: envelope.head.device_sn.or(envelope.payload.device_sn),
+        message_type,
+        fields,
+    })
+}
+
+/// Little endian integer of up to 8 bytes
+fn integer(bytes: &[u8], signed: bool) -> Option<f64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+
+    let mut buffer = [0u8; 8];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+    let unsigned = u64::from_le_bytes(buffer);
+
+    if !signed {
+        return Some(unsigned as f64);
+    }
+
+    let shift = 64 - 8 * bytes.len() as u32;
+    Some(((unsigned << shift) as i64 >> shift) as f64)
+}
+
+/// Decodes a value by its type byte: `00` text, `01` and `03` unsigned, `02` signed
+/// integers, `05` floats, anything else is kept as bytes
+fn value(kind: u8, bytes: &[u8]) -> Value {
+    let number = match (kind, bytes.len()) {
+        (0x00, _) => {
+            let text = String::from_utf8_lossy(bytes);
+            return Value::Text(text.trim_end_matches('\0').to_string());
+        }
+        (0x01 | 0x03, _) => integer(bytes, false),
+        (0x02, _) => integer(bytes, true),
+        (0x05, 4) => Some(f32::from_le_bytes(bytes.try_into().unwrap()) as f64),
+        _ => None,
+    };
+
+    match number {
+        Some(number) => Value::Number(number),
+        None => Value::Bytes(bytes.to_vec()),
+    }
+}
+
+/// Parses the binary payload into its message type and fields. The payload is the
+/// header, fields of id, length, type and value, and a XOR checksum.
+pub fn parse(data: &[u8]) -> Result<(u16, Vec<Field>), Error> {
+    if data.len() < HEADER_LEN + 1 || data[..2] != MAGIC {
+        return Err(Error::Format("Missing header".into()));
+    }
+
+    let length = u16::from_le_bytes([data[2], data[3]]) as usize;
+    if length != data.len() {
+        return Err(Error::Format(format!(
+            "Length {length} does not match {} bytes",
+            data.len()
+        )));
+    }
+
+    let (body, checksum) = data.split_at(data.len() - 1);
+    if body.iter().fold(0, |sum, byte| sum ^ byte) != checksum[0] {
+        return Err(Error::Format("Checksum mismatch".into()));
+    }
+
+    let message_type = u16::from_be_bytes([data[7], data[8]]);
+
+    // Some message types carry an additional sequence byte before the fields
+    let mut rest = &body[HEADER_LEN..];
+    while let [byte, tail @ ..] = rest
+        && *byte < FIRST_FIELD
+    {
+        rest = tail;
+    }
+
+    let mut fields = Vec::new();
+    while let [id, len, tail @ ..] = rest {
+        let len = *len as usize;
+        if len == 0 || tail.len() < len {
+            return Err(Error::Format(format!("Field {id:02x} exceeds the payload")));
+        }
+
+        fields.push(Field {
+            id: *id,
+            value: value(tail[0], &tail[1..len]),
+        });
+        rest = &tail[len..];
+    }
+
+    if !rest.is_empty() {
+        return Err(Error::Format("Trailing bytes after the fields".into()));
+    }
+
+    Ok((message_type, fields))
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantity {
+    BatterySoc,
+    PhotovoltaicPower,
+    OutputPower,
+    ChargingPower,
+    BatteryDischargePower,
+    AcOutputPower,
+    Temperature,
+}
+
+/// Meaning of a field id, the value being multiplied by the factor
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldConfig {
+    quantity: Quantity,
+    #[serde(default = "default_factor")]
+    factor: f64,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+/// Field configs by their hex id, e.g. `a5`
+pub type FieldMapping = BTreeMap<String, FieldConfig>;
+
+/// Id, quantity and factor of a field
+type DefaultField = (&'static str, Quantity, f64);
+
+/// Fields decoded so far by product number. They were taken from recorded messages
+/// and may differ with other firmware.
+const DEFAULT_FIELDS: [(&str, &[DefaultField]); 2] = [
+    // Solarbank E1600
+    (
+        "A17C0",
+        &[
+            ("a3", Quantity::BatterySoc, 1.0),
+            ("a5", Quantity::Temperature, 1.0),
+            ("a7", Quantity::PhotovoltaicPower, 1.0),
+            ("a8", Quantity::ChargingPower, 1.0),
+            ("a9", Quantity::OutputPower, 1.0),
+        ],
+    ),
+    // Solarbank 2 Pro
+    (
+        "A17C1",
+        &[
+            ("a5", Quantity::Temperature, 0.1),
+            ("a6", Quantity::BatterySoc, 1.0),
+            ("a7", Quantity::PhotovoltaicPower, 1.0),
+            ("a8", Quantity::ChargingPower, 1.0),
+            ("a9", Quantity::BatteryDischargePower, 1.0),
+            ("aa", Quantity::OutputPower, 1.0),
+            ("ab", Quantity::AcOutputPower, 1.0),
+        ],
+    ),
+];
+
+/// The default mapping of a product number, empty for unknown ones
+pub fn default_mapping(device_pn: &str) -> FieldMapping {
+    DEFAULT_FIELDS
+        .iter()
+        .filter(|(pn, _)| *pn == device_pn)
+        .flat_map(|(_, fields)| fields.iter())
+        .map(|(id, quantity, factor)| {
+            let config = FieldConfig {
+                quantity: *quantity,
+                factor: *factor,
+            };
+            (id.to_string(), config)
+        })
+        .collect()
+}
+
+/// High-resolution values of a Solarbank, powers in W, the temperature in °C.
+/// Messages only carry some of them, depending on the message type.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Realtime {
+    pub battery_soc: Option<f64>,
+    pub photovoltaic_power: Option<f64>,
+    pub output_power: Option<f64>,
+    pub charging_power: Option<f64>,
+    pub battery_discharge_power: Option<f64>,
+    pub ac_output_power: Option<f64>,
+    pub temperature: Option<f64>,
+}
+
+impl Realtime {
+    /// Picks the numeric fields of the mapping, ignoring all others
+    pub fn new(fields: &[Field], mapping: &FieldMapping) -> Self {
+        let mut realtime = Self::default();
+
+        for field in fields {
+            let Some(config) = mapping.get(&format!("{:02x}", field.id)) else {
+                continue;
+            };
+            let Value::Number(number) = field.value else {
+                log::debug!("Ignoring non-numeric field {field}");
+                continue;
+            };
+
+            let value = Some(number * config.factor);
+            match config.quantity {
+                Quantity::BatterySoc => realtime.battery_soc = value,
+                Quantity::PhotovoltaicPower => realtime.photovoltaic_power = value,
+                Quantity::OutputPower => realtime.output_power = value,
+                Quantity::ChargingPower => realtime.charging_power = value,
+                Quantity::BatteryDischargePower => realtime.battery_discharge_power = value,
+                Quantity::AcOutputPower => realtime.ac_output_power = value,
+                Quantity::Temperature => realtime.temperature = value,
+            }
+        }
+
+        realtime
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The values present, named like their quantity
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        [
+            ("battery_soc", self.battery_soc),
+            ("photovoltaic_power", self.photovoltaic_power),
+            ("output_power", self.output_power),
+            ("charging_power", self.charging_power),
+            ("battery_discharge_power", self.battery_discharge_power),
+            ("ac_output_power", self.ac_output_power),
+            ("temperature", self.temperature),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payload of a message type with the given fields, `03 01 0f` being the
+    /// protocol pattern
+    fn payload(message_type: u16, fields: &[u8]) -> Vec<u8> {
+        let length = (HEADER_LEN + fields.len() + 1) as u16;
+
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(&[0x03, 0x01, 0x0f]);
+        data.extend_from_slice(&message_type.to_be_bytes());
+        data.extend_from_slice(fields);
+        data.push(data.iter().fold(0, |sum, byte| sum ^ byte));
+        data
+    }
+
+    const FIELDS: [u8; 24] = [
+        0xa1, 0x04, 0x00, b'S', b'N', 0x00, // text
+        0xa2, 0x02, 0x01, 0x5a, // unsigned 90
+        0xa3, 0x03, 0x02, 0xfe, 0xff, // signed -2
+        0xa4, 0x05, 0x05, 0x00, 0x00, 0x20, 0x41, // float 10.0
+        0xa5, 0x01, // no value
+    ];
+
+    fn error(data: &[u8]) -> String {
+        parse(data).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let (message_type, fields) = parse(&payload(0x0405, &FIELDS[..22])).unwrap();
+
+        assert_eq!(message_type, 0x0405);
+        assert_eq!(
+            fields,
+            [
+                Field {
+                    id: 0xa1,
+                    value: Value::Text("SN".into())
+                },
+                Field {
+                    id: 0xa2,
+                    value: Value::Number(90.0)
+                },
+                Field {
+                    id: 0xa3,
+                    value: Value::Number(-2.0)
+                },
+                Field {
+                    id: 0xa4,
+                    value: Value::Number(10.0)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_sequence_byte() {
+        let mut fields = vec![0x07];
+        fields.extend_from_slice(&FIELDS[6..10]);
+
+        let (_, fields) = parse(&payload(0x0857, &fields)).unwrap();
+        assert_eq!(
+            fields,
+            [Field {
+                id: 0xa2,
+                value: Value::Number(90.0)
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_types_as_bytes() {
+        let (_, fields) = parse(&payload(0x0405, &[0xa1, 0x03, 0x04, 0x01, 0x02])).unwrap();
+        assert_eq!(fields[0].value, Value::Bytes(vec![0x01, 0x02]));
+        assert_eq!(fields[0].to_string(), "a1 = 0x0102");
+    }
+
+    #[test]
+    fn rejects_invalid_header() {
+        let mut data = payload(0x0405, &FIELDS[..10]);
+        data[0] = 0xfe;
+        assert_eq!(error(&data), "Invalid payload: Missing header");
+        assert_eq!(error(&MAGIC), "Invalid payload: Missing header");
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut data = payload(0x0405, &FIELDS[..10]);
+        data.insert(HEADER_LEN, 0xa1);
+        assert_eq!(
+            error(&data),
+            "Invalid payload: Length 20 does not match 21 bytes"
+        );
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut data = payload(0x0405, &FIELDS[..10]);
+        data[HEADER_LEN + 3] ^= 0x01;
+        assert_eq!(error(&data), "Invalid payload: Checksum mismatch");
+    }
+
+    #[test]
+    fn rejects_zero_length_field() {
+        assert_eq!(
+            error(&payload(0x0405, &FIELDS[22..])),
+            "Invalid payload: Field a5 exceeds the payload"
+        );
+
+        let data = payload(0x0405, &[0xa1, 0x00, 0xa2, 0x00]);
+        assert_eq!(
+            error(&data),
+            "Invalid payload: Field a1 exceeds the payload"
+        );
+    }
+
+    #[test]
+    fn rejects_overlong_field() {
+        let data = payload(0x0405, &[0xa1, 0x05, 0x01, 0x5a]);
+        assert_eq!(
+            error(&data),
+            "Invalid payload: Field a1 exceeds the payload"
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut fields = FIELDS[6..10].to_vec();
+        fields.push(0xa3);
+
+        assert_eq!(
+            error(&payload(0x0405, &fields)),
+            "Invalid payload: Trailing bytes after the fields"
+        );
+    }
+
+    #[test]
+    fn decodes_message() {
+        let data = base64::engine::general_purpose::STANDARD.encode(payload(0x0405, &FIELDS[..10]));
+        let message = serde_json::json!({
+            "head": { "device_pn": "A17C1" },
+            "payload": serde_json::json!({ "device_sn": "SN1", "data": data }).to_string(),
+        });
+
+        let message = decode(message.to_string().as_bytes()).unwrap();
+        assert_eq!(message.device_pn.as_deref(), Some("A17C1"));
+        assert_eq!(message.device_sn.as_deref(), Some("SN1"));
+        assert_eq!(message.message_type, 0x0405);
+        assert_eq!(message.fields.len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        assert!(matches!(decode(b"{"), Err(Error::Json(_))));
+
+        let message = serde_json::json!({ "payload": r#"{"data": "!"}"# });
+        assert!(matches!(
+            decode(message.to_string().as_bytes()),
+            Err(Error::Base64(_))
+        ));
+
+        let message = serde_json::json!({ "payload": r#"{"data": "AAAA"}"# });
+        assert!(matches!(
+            decode(message.to_string().as_bytes()),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[test]
+    fn maps_fields_to_quantities() {
+        let (_, fields) = parse(&payload(0x0405, &FIELDS[..22])).unwrap();
+        let mapping: FieldMapping = serde_json::from_value(serde_json::json!({
+            "a1": { "quantity": "temperature" },
+            "a2": { "quantity": "battery_soc" },
+            "a4": { "quantity": "output_power", "factor": 0.5 },
+        }))
+        .unwrap();
+
+        let realtime = Realtime::new(&fields, &mapping);
+        assert_eq!(
+            realtime,
+            Realtime {
+                battery_soc: Some(90.0),
+                output_power: Some(5.0),
+                ..Realtime::default()
+            }
+        );
+        assert_eq!(
+            realtime.values(),
+            [("battery_soc", 90.0), ("output_power", 5.0)]
+        );
+        assert!(Realtime::new(&fields, &FieldMapping::new()).is_empty());
+    }
+
+    #[test]
+    fn has_default_mappings() {
+        let mapping = default_mapping("A17C1");
+        assert_eq!(mapping["a6"].quantity, Quantity::BatterySoc);
+        assert_eq!(mapping["a5"].factor, 0.1);
+
+        assert!(!default_mapping("A17C0").is_empty());
+        assert!(default_mapping("A1771").is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+//! Minimal gRPC client for unary calls, enough for the OTLP metrics service.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{HeaderMap, Request, Uri};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
+
+use super::Error;
+
+fn connection_error(err: impl std::fmt::Display) -> Error {
+    Error::Connection(err.to_string())
+}
+
+fn tls_connector() -> Result<TlsConnector, Error> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(connection_error)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Status of a response, sent in the trailers or, for errors without a body, in
+/// the headers
+fn grpc_status(headers: &HeaderMap) -> Option<(u32, String)> {
+    let code = headers.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+    let message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .unwrap_or_default();
+
+    Some((code, message.to_string()))
+}
+
+/// Connects for every call, as calls are minutes apart
+pub struct GrpcClient {
+    runtime: Runtime,
+    uri: Uri,
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+}
+
+impl GrpcClient {
+    /// `endpoint` is `http://` for plain HTTP/2 and `https://` for TLS
+    pub fn new(endpoint: &str, timeout: Duration) -> Result<Self, Error> {
+        let uri: Uri = endpoint
+            .parse()
+            .map_err(|err| Error::Connection(format!("Invalid endpoint {endpoint:?}: {err}")))?;
+
+        let tls = match uri.scheme_str() {
+            Some("https") => Some(tls_connector()?),
+            Some("http") => None,
+            _ => {
+                return Err(Error::Connection(format!(
+                    "Invalid endpoint {endpoint:?}, expected http:// or https://"
+                )));
+            }
+        };
+        if uri.host().is_none() {
+            return Err(Error::Connection(format!(
+                "Invalid endpoint {endpoint:?}, missing host"
+            )));
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(connection_error)?;
+
+        Ok(Self {
+            runtime,
+            uri,
+            tls,
+            timeout,
+        })
+    }
+
+    /// Calls `path` like `/package.Service/Method` with an encoded protobuf message,
+    /// returning the encoded response message
+    pub fn unary(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+        message: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        self.runtime.block_on(async {
+            tokio::time::timeout(self.timeout, self.call(path, headers, message))
+                .await
+                .unwrap_or_else(|_| Err(Error::Connection("Request timed out".into())))
+        })
+    }
+
+    async fn call(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+        message: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let host = self.uri.host().unwrap_or_default();
+        let port = self.uri.port_u16().unwrap_or(match self.tls {
+            Some(_) => 443,
+            None => 80,
+        });
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(connection_error)?;
+
+        match &self.tls {
+            Some(tls) => {
+                let name = ServerName::try_from(host.to_string()).map_err(connection_error)?;
+                let stream = tls.connect(name, stream).await.map_err(connection_error)?;
+
+                self.send(stream, path, headers, message).await
+            }
+            None => self.send(stream, path, headers, message).await,
+        }
+    }
+
+    async fn send<S>(
+        &self,
+        stream: S,
+        path: &str,
+        headers: &[(&str, &str)],
+        message: &[u8],
+    ) -> Result<Vec<u8>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (client, connection) = h2::client::handshake(stream)
+            .await
+            .map_err(connection_error)?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::debug!("gRPC connection closed: {err}");
+            }
+        });
+
+        let authority = self.uri.authority().map(|a| a.as_str()).unwrap_or_default();
+        let scheme = self.uri.scheme_str().unwrap_or("http");
+
+        let mut request = Request::post(format!("{scheme}://{authority}{path}"))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("user-agent", "anker-solix-exporter");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(()).map_err(connection_error)?;
+
+        // Uncompressed length-prefixed message
+        let mut frame = Vec::with_capacity(5 + message.len());
+        frame.push(0);
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+
+        let mut client = client.ready().await.map_err(connection_error)?;
+        let (response, mut body) = client
+            .send_request(request, false)
+            .map_err(connection_error)?;
+        body.send_data(Bytes::from(frame), true)
+            .map_err(connection_error)?;
+
+        let response = response.await.map_err(connection_error)?;
+        let status = response.status().as_u16();
+        let (parts, mut body) = response.into_parts();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(connection_error)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            data.extend_from_slice(&chunk);
+        }
+        let trailers = body.trailers().await.map_err(connection_error)?;
+
+        if status != 200 {
+            return Err(Error::Status(status, String::from_utf8_lossy(&data).into()));
+        }
+
+        match trailers
+            .as_ref()
+            .and_then(grpc_status)
+            .or_else(|| grpc_status(&parts.headers))
+        {
+            Some((0, _)) => Ok(data.get(5..).unwrap_or_default().to_vec()),
+            Some((code, message)) => Err(Error::Grpc(code, message)),
+            None => Err(Error::Connection("Response without gRPC status".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    /// Received path, headers and body, answered with the given gRPC status
+    type Received = (String, HeaderMap, Vec<u8>);
+
+    fn server(code: u32) -> (String, mpsc::Receiver<Received>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async move {
+                listener.set_nonblocking(true).unwrap();
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = h2::server::handshake(stream).await.unwrap();
+
+                while let Some(request) = connection.accept().await {
+                    let (request, mut respond) = request.unwrap();
+                    let path = request.uri().path().to_string();
+                    let headers = request.headers().clone();
+
+                    let mut body = request.into_body();
+                    let mut data = Vec::new();
+                    while let Some(chunk) = body.data().await {
+                        data.extend_from_slice(&chunk.unwrap());
+                    }
+                    sender.send((path, headers, data)).unwrap();
+
+                    let response = http::Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(())
+                        .unwrap();
+                    let mut stream = respond.send_response(response, false).unwrap();
+                    stream
+                        .send_data(Bytes::from_static(&[0, 0, 0, 0, 0]), false)
+                        .unwrap();
+
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", code.to_string().parse().unwrap());
+                    trailers.insert("grpc-message", "failed".parse().unwrap());
+                    stream.send_trailers(trailers).unwrap();
+                }
+            });
+        });
+
+        (endpoint, receiver)
+    }
+
+    #[test]
+    fn calls_unary_method() {
+        let (endpoint, received) = server(0);
+        let client = GrpcClient::new(&endpoint, Duration::from_secs(5)).unwrap();
+
+        let response = client
+            .unary(
+                "/test.Service/Method",
+                &[("x-scope-orgid", "home")],
+                b"message",
+            )
+            .unwrap();
+        assert!(response.is_empty());
+
+        let (path, headers, body) = received.recv().unwrap();
+        assert_eq!(path, "/test.Service/Method");
+        assert_eq!(headers["content-type"], "application/grpc");
+        assert_eq!(headers["te"], "trailers");
+        assert_eq!(headers["x-scope-orgid"], "home");
+        assert_eq!(body, b"\0\0\0\0\x07message");
+    }
+
+    #[test]
+    fn fails_on_grpc_status() {
+        let (endpoint, _received) = server(14);
+        let client = GrpcClient::new(&endpoint, Duration::from_secs(5)).unwrap();
+
+        let err = client.unary("/test.Service/Method", &[], b"").unwrap_err();
+        assert!(matches!(err, Error::Grpc(14, ref message) if message == "failed"));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn rejects_invalid_endpoint() {
+        assert!(GrpcClient::new("collector:4317", Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+mod grpc;
+pub mod otlp;
+mod proto;
+pub mod pushgateway;
+pub mod remote_write;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use serde::Deserialize;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Request error: {0}")]
+    Request(Box<ureq::Error>),
+    #[error("Unexpected status {0}: {1}")]
+    Status(u16, String),
+    #[error("Connection error: {0}")]
+    Connection(String),
+    #[error("Unexpected gRPC status {0}: {1}")]
+    Grpc(u32, String),
+}
+
+impl Error {
+    /// Network errors, rate limits and server errors are worth retrying, other
+    /// client errors will fail again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Request(_) | Error::Connection(_) => true,
+            Error::Status(status, _) => *status == 429 || *status >= 500,
+            // Cancelled, deadline exceeded, resource exhausted, aborted, out of range,
+            // unavailable and data loss, as by the OTLP specification
+            Error::Grpc(code, _) => matches!(code, 1 | 4 | 8 | 10 | 11 | 14 | 15),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BasicAuth {
+    username: String,
+    password: String,
+}
+
+/// Authentication for push targets, flattened into their configs
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Auth {
+    #[serde(default)]
+    basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    bearer_token: Option<String>,
+}
+
+impl Auth {
+    /// Value of the `Authorization` header, if any
+    pub fn header(&self) -> Option<String> {
+        if let Some(token) = &self.bearer_token {
+            return Some(format!("Bearer {token}"));
+        }
+
+        self.basic_auth
+            .as_ref()
+            .map(|BasicAuth { username, password }| {
+                let credentials = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}:{password}"));
+
+                format!("Basic {credentials}")
+            })
+    }
+
+    pub fn apply<B>(&self, request: ureq::RequestBuilder<B>) -> ureq::RequestBuilder<B> {
+        match self.header() {
+            Some(value) => request.header("Authorization", &value),
+            None => request,
+        }
+    }
+}
+
+pub fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .http_status_as_error(false)
+        .build()
+        .into()
+}
+
+pub fn check_response(
+    response: Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+) -> Result<(), Error> {
+    let mut response = response.map_err(|err| Error::Request(Box::new(err)))?;
+    let status = response.status().as_u16();
+
+    match status {
+        200..=299 => Ok(()),
+        _ => {
+            let body = response.body_mut().read_to_string().unwrap_or_default();
+
+            Err(Error::Status(status, body))
+        }
+    }
+}
+
+/// Runs `push` every `interval` on its own thread until `shutdown` is set, then a
+/// last time.
+pub fn every(
+    interval: Duration,
+    shutdown: Arc<AtomicBool>,
+    mut push: impl FnMut() + Send + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next = Instant::now() + interval;
+
+        while !shutdown.load(Ordering::Relaxed) {
+            if Instant::now() < next {
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            }
+            next += interval;
+
+            push();
+        }
+
+        push();
+    })
+}
@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::grpc::GrpcClient;
+use super::{proto, Auth, Error};
+use crate::exposition::{self, Kind, MetricFamily};
+use crate::Metrics;
+
+/// Labels which identify the resource instead of a data point
+const RESOURCE_LABELS: [&str; 2] = ["site_id", "device_sn"];
+
+const GRPC_EXPORT_PATH: &str = "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+
+/// Named like the values of `OTEL_EXPORTER_OTLP_PROTOCOL`
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    #[serde(rename = "grpc")]
+    Grpc,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OtlpConfig {
+    endpoint: String,
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default = "default_interval")]
+    interval: u64,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    resource_attributes: BTreeMap<String, String>,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// UCUM units of the OpenMetrics unit suffixes
+fn unit(unit: Option<&str>) -> &'static str {
+    match unit {
+        Some("watts") => "W",
+        Some("wh") => "W.h",
+        Some("percent") => "%",
+        Some("ratio") => "1",
+        Some("seconds") => "s",
+        Some("grams") => "g",
+        _ => "",
+    }
+}
+
+type DataPoints = BTreeMap<usize, Vec<u8>>;
+
+fn key_value(buffer: &mut Vec<u8>, number: u64, key: &str, value: &str) {
+    let mut any_value = Vec::new();
+    proto::string(&mut any_value, 1, value);
+
+    let mut key_value = Vec::new();
+    proto::string(&mut key_value, 1, key);
+    proto::bytes(&mut key_value, 2, &any_value);
+
+    proto::bytes(buffer, number, &key_value);
+}
+
+/// Encodes an `ExportMetricsServiceRequest` with one resource per site and device.
+/// Counters become cumulative monotonic sums since `start`, everything else gauges.
+/// The start is that of the process, even for counters loaded from their files.
+fn encode_export_request(
+    families: &[MetricFamily],
+    resource_attributes: &BTreeMap<String, String>,
+    start: u64,
+    time: u64,
+) -> Vec<u8> {
+    // Encoded data points by family index, by resource labels
+    let mut resources: BTreeMap<Vec<(&str, &str)>, DataPoints> = BTreeMap::new();
+
+    for (index, family) in families.iter().enumerate() {
+        for sample in &family.samples {
+            let resource = sample
+                .labels
+                .iter()
+                .filter(|(name, _)| RESOURCE_LABELS.contains(&name.as_str()))
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+
+            let mut point = Vec::new();
+            if family.kind == Kind::Counter {
+                proto::fixed64(&mut point, 2, start);
+            }
+            proto::fixed64(&mut point, 3, time);
+            proto::double(&mut point, 4, sample.value);
+            for (name, value) in &sample.labels {
+                if !RESOURCE_LABELS.contains(&name.as_str()) {
+                    key_value(&mut point, 7, name, value);
+                }
+            }
+
+            let points = resources
+                .entry(resource)
+                .or_default()
+                .entry(index)
+                .or_default();
+            proto::bytes(points, 1, &point);
+        }
+    }
+
+    let mut request = Vec::new();
+
+    for (labels, metrics) in resources {
+        let mut resource = Vec::new();
+        key_value(&mut resource, 1, "service.name", "anker-solix-exporter");
+        key_value(
+            &mut resource,
+            1,
+            "service.version",
+            env!("CARGO_PKG_VERSION"),
+        );
+        for (key, value) in resource_attributes {
+            key_value(&mut resource, 1, key, value);
+        }
+        for (key, value) in labels {
+            key_value(&mut resource, 1, key, value);
+        }
+
+        let mut scope = Vec::new();
+        proto::string(&mut scope, 1, "anker-solix-exporter");
+        proto::string(&mut scope, 2, env!("CARGO_PKG_VERSION"));
+
+        let mut scope_metrics = Vec::new();
+        proto::bytes(&mut scope_metrics, 1, &scope);
+
+        for (index, mut data) in metrics {
+            let family = &families[index];
+
+            let mut metric = Vec::new();
+            proto::string(&mut metric, 1, &family.name);
+            proto::string(&mut metric, 2, &family.help);
+            proto::string(&mut metric, 3, unit(family.unit.as_deref()));
+
+            match family.kind {
+                Kind::Counter => {
+                    // Cumulative aggregation temporality, monotonic
+                    proto::uint(&mut data, 2, 2);
+                    proto::uint(&mut data, 3, 1);
+                    proto::bytes(&mut metric, 7, &data);
+                }
+                Kind::Gauge | Kind::Unknown => proto::bytes(&mut metric, 5, &data),
+            }
+
+            proto::bytes(&mut scope_metrics, 2, &metric);
+        }
+
+        let mut resource_metrics = Vec::new();
+        proto::bytes(&mut resource_metrics, 1, &resource);
+        proto::bytes(&mut resource_metrics, 2, &scope_metrics);
+
+        proto::bytes(&mut request, 1, &resource_metrics);
+    }
+
+    request
+}
+
+enum Transport {
+    Http(ureq::Agent),
+    Grpc(GrpcClient),
+}
+
+struct OtlpExporter {
+    config: OtlpConfig,
+    transport: Transport,
+    metrics: Arc<Metrics>,
+    start: u64,
+}
+
+impl OtlpExporter {
+    fn url(&self) -> String {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+
+        match self.config.protocol {
+            Protocol::HttpProtobuf => format!("{endpoint}/v1/metrics"),
+            Protocol::Grpc => format!("{endpoint}{GRPC_EXPORT_PATH}"),
+        }
+    }
+
+    fn export(&self) -> Result<(), Error> {
+        let families = exposition::parse(&self.metrics.gather());
+        if families.iter().all(|family| family.samples.is_empty()) {
+            return Ok(());
+        }
+
+        let body = encode_export_request(
+            &families,
+            &self.config.resource_attributes,
+            self.start,
+            now_nanos(),
+        );
+
+        match &self.transport {
+            Transport::Http(agent) => {
+                let mut request = agent
+                    .post(&self.url())
+                    .header("Content-Type", "application/x-protobuf")
+                    .header("User-Agent", "anker-solix-exporter");
+                for (name, value) in &self.config.headers {
+                    request = request.header(name, value);
+                }
+
+                super::check_response(self.config.auth.apply(request).send(&body))
+            }
+            Transport::Grpc(client) => {
+                // gRPC metadata keys are lowercase
+                let authorization = self.config.auth.header();
+                let headers: Vec<(String, &str)> = self
+                    .config
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.as_str()))
+                    .chain(
+                        authorization
+                            .as_deref()
+                            .map(|value| ("authorization".to_string(), value)),
+                    )
+                    .collect();
+                let headers: Vec<(&str, &str)> = headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), *value))
+                    .collect();
+
+                client.unary(GRPC_EXPORT_PATH, &headers, &body).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Periodically exports the registry to an OTLP/HTTP or OTLP/gRPC endpoint until
+/// `shutdown` is set, then exports a last time. Failed exports are not retried, as
+/// cumulative values are complete with the next export.
+pub fn spawn(
+    config: OtlpConfig,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Error> {
+    let interval = Duration::from_secs(config.interval);
+    let timeout = Duration::from_secs(config.timeout);
+
+    let transport = match config.protocol {
+        Protocol::HttpProtobuf => Transport::Http(super::agent(timeout)),
+        Protocol::Grpc => Transport::Grpc(GrpcClient::new(&config.endpoint, timeout)?),
+    };
+
+    let exporter = OtlpExporter {
+        config,
+        transport,
+        metrics,
+        start: now_nanos(),
+    };
+
+    log::info!("Exporting OTLP metrics to {}", exporter.url());
+
+    Ok(super::every(interval, shutdown, move || {
+        if let Err(err) = exporter.export() {
+            log::error!("Failed to export OTLP metrics: {err}");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Field {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let (byte, rest) = data.split_first().unwrap();
+            *data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn decode(mut data: &[u8]) -> Vec<(u64, Field)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut data)),
+                1 => {
+                    let (value, rest) = data.split_at(8);
+                    data = rest;
+                    Field::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => {
+                    let length = varint(&mut data) as usize;
+                    let (value, rest) = data.split_at(length);
+                    data = rest;
+                    Field::Bytes(value.to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    /// The embedded messages of field `number`
+    fn messages(fields: &[(u64, Field)], number: u64) -> Vec<Vec<(u64, Field)>> {
+        fields
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, field)| match field {
+                Field::Bytes(data) => decode(data),
+                field => panic!("expected a message, got {field:?}"),
+            })
+            .collect()
+    }
+
+    fn string(fields: &[(u64, Field)], number: u64) -> String {
+        match fields.iter().find(|(n, _)| *n == number) {
+            Some((_, Field::Bytes(data))) => String::from_utf8(data.clone()).unwrap(),
+            field => panic!("expected a string, got {field:?}"),
+        }
+    }
+
+    fn field(fields: &[(u64, Field)], number: u64) -> Option<&Field> {
+        fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, field)| field)
+    }
+
+    /// Key values of field `number` with string values
+    fn attributes(fields: &[(u64, Field)], number: u64) -> Vec<(String, String)> {
+        messages(fields, number)
+            .iter()
+            .map(|key_value| (string(key_value, 1), string(&messages(key_value, 2)[0], 1)))
+            .collect()
+    }
+
+    const OPEN_METRICS: &str = "\
+# HELP anker_solix_energy_wh Energy.
+# TYPE anker_solix_energy_wh counter
+# UNIT anker_solix_energy_wh wh
+anker_solix_energy_wh_total{site_id=\"s1\",flow=\"grid_import\"} 1.5
+# HELP anker_solix_battery_soc_percent Battery.
+# TYPE anker_solix_battery_soc_percent gauge
+# UNIT anker_solix_battery_soc_percent percent
+anker_solix_battery_soc_percent{site_id=\"s1\",device_sn=\"d1\"} 50
+# EOF
+";
+
+    #[test]
+    fn encodes_sums_and_gauges_per_resource() {
+        let families = exposition::parse(OPEN_METRICS);
+        let resource_attributes = BTreeMap::from([("host.name".into(), "pi".into())]);
+        let request = decode(&encode_export_request(
+            &families,
+            &resource_attributes,
+            100,
+            200,
+        ));
+
+        let resource_metrics = messages(&request, 1);
+        assert_eq!(resource_metrics.len(), 2);
+
+        // Resources are ordered by their labels, the site before its device
+        let site = &resource_metrics[0];
+        let resource = &messages(site, 1)[0];
+        assert_eq!(
+            attributes(resource, 1)[2..],
+            [
+                ("host.name".into(), "pi".into()),
+                ("site_id".into(), "s1".into())
+            ]
+        );
+
+        let scope_metrics = &messages(site, 2)[0];
+        let metric = &messages(scope_metrics, 2)[0];
+        assert_eq!(string(metric, 1), "anker_solix_energy_wh");
+        assert_eq!(string(metric, 3), "W.h");
+        let sum = &messages(metric, 7)[0];
+        // Cumulative and monotonic
+        assert_eq!(field(sum, 2), Some(&Field::Varint(2)));
+        assert_eq!(field(sum, 3), Some(&Field::Varint(1)));
+        let point = &messages(sum, 1)[0];
+        assert_eq!(field(point, 2), Some(&Field::Fixed64(100)));
+        assert_eq!(field(point, 3), Some(&Field::Fixed64(200)));
+        assert_eq!(field(point, 4), Some(&Field::Fixed64(1.5f64.to_bits())));
+        assert_eq!(
+            attributes(point, 7),
+            [("flow".into(), "grid_import".into())]
+        );
+
+        let device = &resource_metrics[1];
+        let resource = &messages(device, 1)[0];
+        assert!(attributes(resource, 1).contains(&("device_sn".into(), "d1".into())));
+
+        let metric = &messages(&messages(device, 2)[0], 2)[0];
+        assert_eq!(string(metric, 1), "anker_solix_battery_soc_percent");
+        assert_eq!(string(metric, 3), "%");
+        assert!(field(metric, 7).is_none());
+        let gauge = &messages(metric, 5)[0];
+        let point = &messages(gauge, 1)[0];
+        // Gauges have no start time
+        assert_eq!(field(point, 2), None);
+        assert_eq!(field(point, 4), Some(&Field::Fixed64(50f64.to_bits())));
+        assert!(attributes(point, 7).is_empty());
+    }
+}
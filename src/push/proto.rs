@@ -0,0 +1,38 @@
+//! Minimal protobuf encoding, enough for the write requests of the push targets.
+
+pub fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn key(buffer: &mut Vec<u8>, number: u64, wire_type: u64) {
+    varint(buffer, number << 3 | wire_type);
+}
+
+pub fn uint(buffer: &mut Vec<u8>, number: u64, value: u64) {
+    key(buffer, number, 0);
+    varint(buffer, value);
+}
+
+pub fn fixed64(buffer: &mut Vec<u8>, number: u64, value: u64) {
+    key(buffer, number, 1);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn double(buffer: &mut Vec<u8>, number: u64, value: f64) {
+    key(buffer, number, 1);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn bytes(buffer: &mut Vec<u8>, number: u64, data: &[u8]) {
+    key(buffer, number, 2);
+    varint(buffer, data.len() as u64);
+    buffer.extend_from_slice(data);
+}
+
+pub fn string(buffer: &mut Vec<u8>, number: u64, value: &str) {
+    bytes(buffer, number, value.as_bytes());
+}
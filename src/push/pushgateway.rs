@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use base64::Engine;
+use serde::Deserialize;
+
+use super::{Auth, Error};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PushgatewayConfig {
+    url: String,
+    #[serde(default = "default_job")]
+    job: String,
+    #[serde(default)]
+    grouping: BTreeMap<String, String>,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+fn default_job() -> String {
+    "anker_solix_exporter".to_string()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// Path segment of a grouping label value, base64 encoded unless it only consists
+/// of `[A-Za-z0-9_.-]`. Empty values and the dot segments `.` and `..` are encoded
+/// too, as they would not survive in a URL path.
+fn segment(name: &str, value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    match !plain || matches!(value, "" | "." | "..") {
+        true => {
+            let encoded = base64::engine::general_purpose::URL_SAFE.encode(value);
+            // An empty value is encoded as a single padding character
+            let encoded = if encoded.is_empty() {
+                "=".into()
+            } else {
+                encoded
+            };
+
+            format!("{name}@base64/{encoded}")
+        }
+        false => format!("{name}/{value}"),
+    }
+}
+
+impl PushgatewayConfig {
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    fn group_url(&self) -> String {
+        let mut url = format!(
+            "{}/metrics/{}",
+            self.url.trim_end_matches('/'),
+            segment("job", &self.job)
+        );
+
+        for (name, value) in &self.grouping {
+            url.push('/');
+            url.push_str(&segment(name, value));
+        }
+
+        url
+    }
+
+    /// Replaces all metrics of the group with `body` in the Prometheus text format
+    pub fn push(&self, body: String) -> Result<(), Error> {
+        let request = super::agent(Duration::from_secs(self.timeout))
+            .put(&self.group_url())
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .header("User-Agent", "anker-solix-exporter");
+
+        super::check_response(self.auth.apply(request).send(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_plain_values() {
+        assert_eq!(segment("job", "anker_solix-1.0"), "job/anker_solix-1.0");
+    }
+
+    #[test]
+    fn encodes_other_values() {
+        for (value, encoded) in [
+            ("", "instance@base64/="),
+            ("..", "instance@base64/Li4="),
+            ("a/b", "instance@base64/YS9i"),
+            ("100%", "instance@base64/MTAwJQ=="),
+            ("a?b", "instance@base64/YT9i"),
+            ("a#b", "instance@base64/YSNi"),
+            ("a b", "instance@base64/YSBi"),
+            ("Küche", "instance@base64/S8O8Y2hl"),
+            ("??>", "instance@base64/Pz8-"),
+        ] {
+            assert_eq!(segment("instance", value), encoded, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn builds_the_group_url() {
+        let config: PushgatewayConfig = serde_json::from_value(serde_json::json!({
+            "url": "http://pushgateway:9091/",
+            "grouping": { "instance": "home", "site": "Garten Haus" },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            config.group_url(),
+            "http://pushgateway:9091/metrics/job/anker_solix_exporter/instance/home/site@base64/R2FydGVuIEhhdXM="
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::{proto, Auth, Error};
+use crate::exposition::{self, MetricFamily};
+use crate::Metrics;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RemoteWriteConfig {
+    url: String,
+    #[serde(default = "default_interval")]
+    interval: u64,
+    #[serde(default = "default_queue_size")]
+    queue_size: usize,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_queue_size() -> usize {
+    120
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// Encodes a `prometheus.WriteRequest` protobuf message:
+///
+/// ```proto
+/// message WriteRequest { repeated TimeSeries timeseries = 1; }
+/// message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+/// message Label { string name = 1; string value = 2; }
+/// message Sample { double value = 1; int64 timestamp = 2; }
+/// ```
+fn encode_write_request(families: &[MetricFamily], timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+
+    for sample in families.iter().flat_map(|family| &family.samples) {
+        let mut labels: Vec<(&str, &str)> = sample
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        labels.push(("__name__", &sample.name));
+        labels.sort();
+
+        let mut series = Vec::new();
+
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            proto::string(&mut label, 1, name);
+            proto::string(&mut label, 2, value);
+            proto::bytes(&mut series, 1, &label);
+        }
+
+        let mut point = Vec::new();
+        proto::double(&mut point, 1, sample.value);
+        proto::uint(&mut point, 2, timestamp as u64);
+        proto::bytes(&mut series, 2, &point);
+
+        proto::bytes(&mut request, 1, &series);
+    }
+
+    request
+}
+
+struct RemoteWriter {
+    config: RemoteWriteConfig,
+    agent: ureq::Agent,
+    metrics: Arc<Metrics>,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl RemoteWriter {
+    fn enqueue(&mut self) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+
+        let families = exposition::parse(&self.metrics.gather());
+        if families.iter().all(|family| family.samples.is_empty()) {
+            return;
+        }
+
+        self.push_request(&encode_write_request(&families, timestamp));
+    }
+
+    /// Queues a compressed request, dropping the oldest one when the queue is full
+    fn push_request(&mut self, request: &[u8]) {
+        if self.queue.len() >= self.config.queue_size {
+            log::warn!("Remote write queue is full, dropping the oldest request");
+            self.queue.pop_front();
+        }
+
+        match snap::raw::Encoder::new().compress_vec(request) {
+            Ok(compressed) => self.queue.push_back(compressed),
+            Err(err) => log::error!("Failed to compress remote write request: {err}"),
+        }
+    }
+
+    fn send(&self, body: &[u8]) -> Result<(), Error> {
+        let request = self
+            .agent
+            .post(&self.config.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("User-Agent", "anker-solix-exporter")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+
+        super::check_response(self.config.auth.apply(request).send(body))
+    }
+
+    /// Sends the queued requests oldest first, keeping them on retryable errors.
+    fn flush(&mut self) {
+        while let Some(body) = self.queue.front() {
+            match self.send(body) {
+                Ok(()) => {
+                    self.queue.pop_front();
+                }
+                Err(err) if err.is_retryable() => {
+                    log::warn!(
+                        "Failed to remote write, retrying {} queued requests later: {err}",
+                        self.queue.len()
+                    );
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Failed to remote write, dropping request: {err}");
+                    self.queue.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Periodically pushes the registry to a remote write endpoint until `shutdown`
+/// is set, then pushes a last time.
+pub fn spawn(
+    config: RemoteWriteConfig,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let interval = Duration::from_secs(config.interval);
+
+    let mut writer = RemoteWriter {
+        agent: super::agent(Duration::from_secs(config.timeout)),
+        config,
+        metrics,
+        queue: VecDeque::new(),
+    };
+
+    log::info!("Remote writing to {}", writer.config.url);
+
+    super::every(interval, shutdown, move || {
+        writer.enqueue();
+        writer.flush();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::exposition::{Kind, Sample};
+
+    fn family(name: &str, labels: &[(&str, &str)], value: f64) -> MetricFamily {
+        MetricFamily {
+            name: name.into(),
+            help: String::new(),
+            unit: None,
+            kind: Kind::Gauge,
+            samples: vec![Sample {
+                name: name.into(),
+                labels: labels
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                value,
+            }],
+        }
+    }
+
+    /// `up{job="x"} 1` at 1000 ms
+    fn expected_request() -> Vec<u8> {
+        [
+            &[0x0a, 0x28][..],
+            &[0x0a, 0x0e, 0x0a, 0x08],
+            b"__name__",
+            &[0x12, 0x02],
+            b"up",
+            &[0x0a, 0x08, 0x0a, 0x03],
+            b"job",
+            &[0x12, 0x01],
+            b"x",
+            &[
+                0x12, 0x0c, 0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0x10, 0xe8, 0x07,
+            ],
+        ]
+        .concat()
+    }
+
+    fn writer(url: &str, queue_size: usize) -> RemoteWriter {
+        RemoteWriter {
+            config: RemoteWriteConfig {
+                url: url.into(),
+                interval: default_interval(),
+                queue_size,
+                timeout: 1,
+                auth: Auth::default(),
+            },
+            agent: super::super::agent(Duration::from_secs(1)),
+            metrics: Arc::new(Metrics::new(false)),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Headers and body of a received request
+    type Received = (Vec<(String, String)>, Vec<u8>);
+
+    /// Receiver answering with the given statuses in order, forwarding the headers
+    /// and bodies of the requests
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<Received>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", server.server_addr());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for status in statuses {
+                let mut request = server.recv().unwrap();
+                let headers = request
+                    .headers()
+                    .iter()
+                    .map(|header| (header.field.to_string(), header.value.to_string()))
+                    .collect();
+                let mut body = Vec::new();
+                request.as_reader().read_to_end(&mut body).unwrap();
+
+                sender.send((headers, body)).unwrap();
+                request.respond(tiny_http::Response::empty(status)).unwrap();
+            }
+        });
+
+        (url, receiver)
+    }
+
+    #[test]
+    fn encodes_write_request() {
+        let families = [family("up", &[("job", "x")], 1.0)];
+
+        assert_eq!(encode_write_request(&families, 1000), expected_request());
+    }
+
+    #[test]
+    fn sorts_labels_by_name() {
+        let families = [family("up", &[("b", "2"), ("a", "1")], 1.0)];
+        let request = encode_write_request(&families, 0);
+
+        let position = |needle: &[u8]| {
+            request
+                .windows(needle.len())
+                .position(|window| window == needle)
+                .unwrap()
+        };
+        assert!(position(b"__name__") < position(b"\x0a\x01a"));
+        assert!(position(b"\x0a\x01a") < position(b"\x0a\x01b"));
+    }
+
+    #[test]
+    fn compresses_with_snappy() {
+        let mut writer = writer("http://127.0.0.1:9/", 1);
+        writer.push_request(&expected_request());
+
+        let expected = [&[0x2a, 0xa4][..], &expected_request()].concat();
+        assert_eq!(writer.queue[0], expected);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut writer = writer("http://127.0.0.1:9/", 2);
+        for request in [b"first", b"other", b"third"] {
+            writer.push_request(request);
+        }
+
+        let decompress = |body: &Vec<u8>| snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        let queued: Vec<_> = writer.queue.iter().map(decompress).collect();
+        assert_eq!(queued, [b"other".to_vec(), b"third".to_vec()]);
+    }
+
+    #[test]
+    fn retries_after_failure() {
+        let (url, received) = receiver(vec![503, 204, 400]);
+        let mut writer = writer(&url, 10);
+        writer.push_request(b"first");
+
+        writer.flush();
+        assert_eq!(writer.queue.len(), 1);
+
+        writer.flush();
+        assert!(writer.queue.is_empty());
+
+        // Client errors are not retried
+        writer.push_request(b"second");
+        writer.flush();
+        assert!(writer.queue.is_empty());
+
+        let bodies: Vec<_> = received.iter().map(|(_, body)| body).collect();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[test]
+    fn pushes_to_receiver() {
+        let (url, received) = receiver(vec![204]);
+        let mut writer = writer(&url, 10);
+        writer.metrics.count_realtime_message("site", "SN1");
+
+        writer.enqueue();
+        writer.flush();
+        assert!(writer.queue.is_empty());
+
+        let (headers, body) = received.recv().unwrap();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("Content-Encoding"), Some("snappy"));
+        assert_eq!(header("Content-Type"), Some("application/x-protobuf"));
+
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let contains = |needle: &[u8]| request.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"anker_solix_realtime_messages_total"));
+        assert!(contains(b"SN1"));
+    }
+}
@@ -0,0 +1,156 @@
+use serde::Deserialize;
+
+/// Selects the numeric leaves of the raw cloud data to export, by their dotted
+/// path like `solarbank_info.solarbank_list.0.battery_power`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RawMetricsConfig {
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// Matches a path against a glob: `*` within a path segment, `**` across
+/// segments and `?` a single character. `**.` also matches no segment at all, so
+/// `**.create_time` matches `create_time` as well.
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', b'.', rest @ ..] if matches(rest, path) => true,
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'.')
+            .any(|i| matches(rest, &path[i..])),
+        [b'?', rest @ ..] => {
+            matches!(path, [char, tail @ ..] if *char != b'.' && matches(rest, tail))
+        }
+        [char, rest @ ..] => {
+            matches!(path, [other, tail @ ..] if char == other && matches(rest, tail))
+        }
+    }
+}
+
+fn walk(value: &serde_json::Value, path: &mut String, leaves: &mut Vec<(String, f64)>) {
+    let number = match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                descend(path, key, |path| walk(value, path, leaves));
+            }
+            return;
+        }
+        serde_json::Value::Array(list) => {
+            for (index, value) in list.iter().enumerate() {
+                descend(path, &index.to_string(), |path| walk(value, path, leaves));
+            }
+            return;
+        }
+        serde_json::Value::Bool(_) | serde_json::Value::Null => None,
+    };
+
+    if let Some(number) = number.filter(|number: &f64| number.is_finite()) {
+        leaves.push((path.clone(), number));
+    }
+}
+
+/// Appends a segment to the path for the duration of `f`
+fn descend(path: &mut String, segment: &str, f: impl FnOnce(&mut String)) {
+    let len = path.len();
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(segment);
+
+    f(path);
+    path.truncate(len);
+}
+
+impl RawMetricsConfig {
+    fn selects(&self, path: &str) -> bool {
+        let matching = |pattern: &String| matches(pattern.as_bytes(), path.as_bytes());
+
+        self.include.iter().any(matching) && !self.exclude.iter().any(matching)
+    }
+
+    /// Numeric and numeric string leaves selected by the patterns, with their path
+    pub fn leaves(&self, value: &serde_json::Value) -> Vec<(String, f64)> {
+        let mut leaves = Vec::new();
+        walk(value, &mut String::new(), &mut leaves);
+
+        leaves.retain(|(path, _)| self.selects(path));
+        leaves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, path: &str) -> bool {
+        matches(pattern.as_bytes(), path.as_bytes())
+    }
+
+    fn config(include: &[&str], exclude: &[&str]) -> RawMetricsConfig {
+        serde_json::from_value(serde_json::json!({ "include": include, "exclude": exclude }))
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_within_a_segment() {
+        assert!(glob("home_*", "home_load_power"));
+        assert!(glob("*.battery_power", "info.battery_power"));
+        assert!(!glob("*", "info.battery_power"));
+        assert!(!glob("info.*", "info.list.0"));
+        assert!(glob("info.?", "info.0"));
+        assert!(!glob("info?0", "info.0"));
+    }
+
+    #[test]
+    fn matches_any_number_of_segments() {
+        assert!(glob("info.**", "info.list.0.battery_power"));
+        assert!(glob("**.battery_power", "info.list.0.battery_power"));
+        assert!(glob("info.**.battery_power", "info.list.0.battery_power"));
+        // Zero segments
+        assert!(glob("info.**.battery_power", "info.battery_power"));
+        assert!(glob("**.create_time", "create_time"));
+        assert!(glob("**", "home_load_power"));
+        assert!(!glob("grid.**", "info.grid"));
+    }
+
+    #[test]
+    fn excludes_take_precedence() {
+        let config = config(&["info.**"], &["**.create_time"]);
+
+        assert!(config.selects("info.power"));
+        assert!(!config.selects("info.create_time"));
+        assert!(!config.selects("home_load_power"));
+    }
+
+    #[test]
+    fn exports_numeric_leaves_with_array_indices() {
+        let config = config(&["**"], &[]);
+        let value = serde_json::json!({
+            "number": 1.5,
+            "numeric": " 12 ",
+            "text": "on",
+            "empty": "",
+            "flag": true,
+            "null": null,
+            "nan": "NaN",
+            "infinite": "inf",
+            "list": [{ "power": "100" }, { "power": 200 }],
+        });
+
+        let mut leaves = config.leaves(&value);
+        leaves.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            leaves,
+            [
+                ("list.0.power".to_string(), 100.0),
+                ("list.1.power".to_string(), 200.0),
+                ("number".to_string(), 1.5),
+                ("numeric".to_string(), 12.0),
+            ]
+        );
+    }
+}
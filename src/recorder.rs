@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+
+use crate::energy::battery_discharge;
+use crate::snapshot::Snapshot;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RecorderConfig {
+    path: PathBuf,
+    #[serde(default)]
+    retention: Retention,
+}
+
+impl RecorderConfig {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Days to keep each tier, forever if unset
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Retention {
+    #[serde(default = "default_raw")]
+    raw: Option<u64>,
+    #[serde(default = "default_five_minutes")]
+    five_minutes: Option<u64>,
+    #[serde(default = "default_hourly")]
+    hourly: Option<u64>,
+    #[serde(default)]
+    daily: Option<u64>,
+}
+
+fn default_raw() -> Option<u64> {
+    Some(2)
+}
+
+fn default_five_minutes() -> Option<u64> {
+    Some(30)
+}
+
+fn default_hourly() -> Option<u64> {
+    Some(365)
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            raw: default_raw(),
+            five_minutes: default_five_minutes(),
+            hourly: default_hourly(),
+            daily: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier {
+    Raw,
+    FiveMinutes,
+    Hourly,
+    Daily,
+}
+
+impl Tier {
+    const ALL: [Tier; 4] = [Tier::Raw, Tier::FiveMinutes, Tier::Hourly, Tier::Daily];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tier::Raw => "raw",
+            Tier::FiveMinutes => "5m",
+            Tier::Hourly => "1h",
+            Tier::Daily => "1d",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|tier| tier.name() == name)
+    }
+
+    /// Width of the buckets in seconds, raw samples are not bucketed
+    fn bucket(&self) -> Option<u64> {
+        match self {
+            Tier::Raw => None,
+            Tier::FiveMinutes => Some(300),
+            Tier::Hourly => Some(3600),
+            Tier::Daily => Some(86400),
+        }
+    }
+
+    /// Seconds between deleting expired rows, the bucket width or five minutes
+    fn prune_interval(&self) -> u64 {
+        self.bucket().unwrap_or(300)
+    }
+
+    fn retention(&self, retention: &Retention) -> Option<u64> {
+        match self {
+            Tier::Raw => retention.raw,
+            Tier::FiveMinutes => retention.five_minutes,
+            Tier::Hourly => retention.hourly,
+            Tier::Daily => retention.daily,
+        }
+    }
+}
+
+pub const SITE_COLUMNS: [&str; 8] = [
+    "home_load_power",
+    "other_load_power",
+    "grid_to_home_power",
+    "photovoltaic_to_grid_power",
+    "home_charging_power",
+    "photovoltaic_power",
+    "charging_power",
+    "output_power",
+];
+
+pub const DEVICE_COLUMNS: [&str; 5] = [
+    "battery_soc",
+    "charging_power",
+    "discharging_power",
+    "output_power",
+    "photovoltaic_power",
+];
+
+/// Values are in the order of the table's columns. Samples are averaged per
+/// bucket in the downsampled tiers.
+#[derive(Debug)]
+pub struct Row {
+    pub timestamp: u64,
+    pub site_id: String,
+    pub device_sn: Option<String>,
+    pub samples: u64,
+    pub values: Vec<Option<f64>>,
+}
+
+/// Records every snapshot into SQLite, one table for sites and one for devices.
+/// Each snapshot is stored as is in the raw tier and averaged into the buckets of
+/// the downsampled tiers right away, so no separate compaction is needed.
+pub struct Recorder {
+    connection: Connection,
+    retention: Retention,
+    /// Buckets are aligned to the local hours and days
+    timezone: Tz,
+    /// Start of the interval expired rows were last deleted in, by tier name
+    pruned: HashMap<&'static str, u64>,
+}
+
+impl Recorder {
+    pub fn open(config: &RecorderConfig, timezone: Tz) -> Result<Self, Error> {
+        let connection = Connection::open(&config.path)?;
+
+        let site_columns: String = SITE_COLUMNS.iter().map(|c| format!("{c} REAL, ")).collect();
+        let device_columns: String = DEVICE_COLUMNS
+            .iter()
+            .map(|c| format!("{c} REAL, "))
+            .collect();
+
+        connection.execute_batch(&format!(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS site_samples (
+                tier TEXT NOT NULL, site_id TEXT NOT NULL, timestamp INTEGER NOT NULL,
+                {site_columns}samples INTEGER NOT NULL,
+                PRIMARY KEY (tier, site_id, timestamp)
+            );
+            CREATE TABLE IF NOT EXISTS device_samples (
+                tier TEXT NOT NULL, site_id TEXT NOT NULL, device_sn TEXT NOT NULL,
+                timestamp INTEGER NOT NULL, {device_columns}samples INTEGER NOT NULL,
+                PRIMARY KEY (tier, site_id, device_sn, timestamp)
+            );"
+        ))?;
+
+        Ok(Self {
+            connection,
+            retention: config.retention.clone(),
+            timezone,
+            pruned: HashMap::new(),
+        })
+    }
+
+    pub fn record(&mut self, site_id: &str, snapshot: &Snapshot) -> Result<(), Error> {
+        let scene_data = &snapshot.scen_info;
+        let (grid, home, info) = (
+            scene_data.grid_info.as_ref(),
+            scene_data.home_info.as_ref(),
+            scene_data.solarbank_info.as_ref(),
+        );
+
+        // Missing sections, e.g. without a Solarbank, are stored as NULL
+        let site_values = [
+            Some(scene_data.home_load_power),
+            Some(scene_data.other_loads_power),
+            grid.map(|grid| grid.grid_to_home_power),
+            grid.map(|grid| grid.photovoltaic_to_grid_power),
+            home.map(|home| home.charging_power),
+            info.map(|info| info.total_photovoltaic_power),
+            info.map(|info| info.total_charging_power),
+            info.map(|info| info.total_output_power),
+        ];
+
+        let transaction = self.connection.transaction()?;
+
+        for tier in Tier::ALL {
+            let timestamp = bucket_start(tier, snapshot.fetched_at, self.timezone);
+
+            upsert(
+                &transaction,
+                "site_samples",
+                tier,
+                timestamp,
+                &[("site_id", site_id)],
+                &zip(&SITE_COLUMNS, &site_values),
+            )?;
+
+            for solarbank in scene_data.solarbanks() {
+                let device_values = [
+                    Some(solarbank.battery_power as f64),
+                    Some(solarbank.charging_power),
+                    Some(solarbank.battery_discharge_power.unwrap_or_else(|| {
+                        battery_discharge(
+                            solarbank.photovoltaic_power,
+                            solarbank.charging_power,
+                            solarbank.output_power,
+                        )
+                    })),
+                    Some(solarbank.output_power),
+                    Some(solarbank.photovoltaic_power),
+                ];
+
+                upsert(
+                    &transaction,
+                    "device_samples",
+                    tier,
+                    timestamp,
+                    &[("site_id", site_id), ("device_sn", &solarbank.device_sn)],
+                    &zip(&DEVICE_COLUMNS, &device_values),
+                )?;
+            }
+
+            let interval = snapshot.fetched_at - snapshot.fetched_at % tier.prune_interval();
+            if let Some(days) = tier.retention(&self.retention)
+                && self.pruned.get(tier.name()) != Some(&interval)
+            {
+                let cutoff = snapshot.fetched_at.saturating_sub(days * 86400) as i64;
+
+                for table in ["site_samples", "device_samples"] {
+                    transaction.execute(
+                        &format!("DELETE FROM {table} WHERE tier = ?1 AND timestamp < ?2"),
+                        params![tier.name(), cutoff],
+                    )?;
+                }
+            }
+        }
+
+        transaction.commit()?;
+
+        for tier in Tier::ALL {
+            let interval = snapshot.fetched_at - snapshot.fetched_at % tier.prune_interval();
+            self.pruned.insert(tier.name(), interval);
+        }
+
+        Ok(())
+    }
+
+    /// Rows of a tier within `from..to` (unix seconds), of devices instead of sites
+    /// if `devices` is set
+    pub fn query(
+        &self,
+        tier: Tier,
+        from: u64,
+        to: u64,
+        site_id: Option<&str>,
+        devices: bool,
+    ) -> Result<Vec<Row>, Error> {
+        let (table, columns) = match devices {
+            true => ("device_samples", &DEVICE_COLUMNS[..]),
+            false => ("site_samples", &SITE_COLUMNS[..]),
+        };
+        let device_sn = if devices { "device_sn" } else { "NULL" };
+
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT timestamp, site_id, {device_sn}, samples, {} FROM {table}
+            WHERE tier = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            AND (?4 IS NULL OR site_id = ?4)
+            ORDER BY timestamp, site_id, {device_sn}",
+            columns.join(", ")
+        ))?;
+
+        let rows = statement.query_map(
+            params![tier.name(), from as i64, to as i64, site_id],
+            |row| {
+                let values = (0..columns.len())
+                    .map(|index| row.get(4 + index))
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Row {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    site_id: row.get(1)?,
+                    device_sn: row.get(2)?,
+                    samples: row.get::<_, i64>(3)? as u64,
+                    values,
+                })
+            },
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+fn zip<'a>(columns: &[&'a str], values: &[Option<f64>]) -> Vec<(&'a str, Option<f64>)> {
+    columns
+        .iter()
+        .copied()
+        .zip(values.iter().copied())
+        .collect()
+}
+
+/// Start of the bucket containing `timestamp`, in the local time of `timezone`.
+/// Days start at local midnight, shorter buckets are aligned to the local offset,
+/// which matters for offsets of a fraction of an hour.
+fn bucket_start(tier: Tier, timestamp: u64, timezone: Tz) -> u64 {
+    let Some(bucket) = tier.bucket() else {
+        return timestamp;
+    };
+    let Some(time) = DateTime::from_timestamp(timestamp as i64, 0) else {
+        return timestamp;
+    };
+    let local = time.with_timezone(&timezone);
+
+    if tier == Tier::Daily {
+        // Midnight may be skipped by a DST transition, then the day starts later
+        let midnight = local.date_naive().and_time(Default::default());
+        let start = (0..3)
+            .filter_map(|hour| {
+                let start = midnight + chrono::Duration::hours(hour);
+                timezone.from_local_datetime(&start).earliest()
+            })
+            .next();
+
+        return start.map_or(timestamp, |start| start.timestamp() as u64);
+    }
+
+    let offset = local.offset().fix().local_minus_utc() as i64;
+    let local_seconds = timestamp as i64 + offset;
+
+    (local_seconds - local_seconds.rem_euclid(bucket as i64) - offset) as u64
+}
+
+/// Inserts a sample or folds it into the running average of its bucket
+fn upsert(
+    connection: &Connection,
+    table: &str,
+    tier: Tier,
+    timestamp: u64,
+    keys: &[(&str, &str)],
+    values: &[(&str, Option<f64>)],
+) -> Result<(), Error> {
+    let columns: Vec<&str> = keys
+        .iter()
+        .map(|(column, _)| *column)
+        .chain(values.iter().map(|(column, _)| *column))
+        .collect();
+    let placeholders: Vec<String> = (0..columns.len())
+        .map(|index| format!("?{}", index + 3))
+        .collect();
+    let averages: Vec<String> = values
+        .iter()
+        .map(|(c, _)| {
+            // NULL while either value is missing, then the other one
+            format!(
+                "{c} = COALESCE(({c} * samples + excluded.{c}) / (samples + 1), {c}, excluded.{c})"
+            )
+        })
+        .collect();
+
+    let sql = format!(
+        "INSERT INTO {table} (tier, timestamp, {}, samples) VALUES (?1, ?2, {}, 1)
+        ON CONFLICT DO UPDATE SET {}, samples = samples + 1",
+        columns.join(", "),
+        placeholders.join(", "),
+        averages.join(", "),
+    );
+
+    let tier = tier.name();
+    let timestamp = timestamp as i64;
+    let params: Vec<&dyn rusqlite::ToSql> = [&tier as &dyn rusqlite::ToSql, &timestamp]
+        .into_iter()
+        .chain(keys.iter().map(|(_, value)| value as &dyn rusqlite::ToSql))
+        .chain(
+            values
+                .iter()
+                .map(|(_, value)| value as &dyn rusqlite::ToSql),
+        )
+        .collect();
+
+    connection.execute(&sql, params.as_slice())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(time: &str) -> u64 {
+        DateTime::parse_from_rfc3339(time).unwrap().timestamp() as u64
+    }
+
+    fn recorder(retention: serde_json::Value) -> Recorder {
+        let config: RecorderConfig = serde_json::from_value(serde_json::json!({
+            "path": ":memory:",
+            "retention": retention,
+        }))
+        .unwrap();
+
+        Recorder::open(&config, "UTC".parse().unwrap()).unwrap()
+    }
+
+    /// A Solarbank discharging 200 W into a home load of `load`, reporting the
+    /// discharge power if given
+    fn snapshot(fetched_at: u64, load: f64, discharge: Option<f64>) -> Snapshot {
+        let scen_info = serde_json::from_value(serde_json::json!({
+            "home_load_power": load.to_string(),
+            "solarbank_info": {
+                "solar_power_1": "0", "solar_power_2": "0",
+                "solar_power_3": "0", "solar_power_4": "0",
+                "to_home_load": "200", "total_battery_power": "0.5",
+                "total_charging_power": "0", "total_output_power": "200",
+                "total_photovoltaic_power": "0", "power_unit": "W",
+                "solarbank_list": [{
+                    "device_sn": "SB1", "battery_power": "50", "charging_power": "0",
+                    "output_power": "200", "photovoltaic_power": "0", "power_unit": "W",
+                    "battery_discharge_power": discharge,
+                }],
+            },
+        }))
+        .unwrap();
+
+        Snapshot {
+            fetched_at,
+            scen_info,
+        }
+    }
+
+    fn column(row: &Row, columns: &[&str], name: &str) -> Option<f64> {
+        let index = columns.iter().position(|column| *column == name).unwrap();
+        row.values[index]
+    }
+
+    #[test]
+    fn averages_within_a_bucket() {
+        let mut recorder = recorder(serde_json::json!({}));
+        recorder
+            .record("site", &snapshot(600, 100.0, None))
+            .unwrap();
+        recorder
+            .record("site", &snapshot(660, 300.0, None))
+            .unwrap();
+        recorder
+            .record("site", &snapshot(900, 600.0, None))
+            .unwrap();
+
+        let raw = recorder.query(Tier::Raw, 0, 3600, None, false).unwrap();
+        assert_eq!(raw.len(), 3);
+
+        let rows = recorder
+            .query(Tier::FiveMinutes, 0, 3600, None, false)
+            .unwrap();
+        let averages: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                let load = column(row, &SITE_COLUMNS, "home_load_power");
+                (row.timestamp, row.samples, load)
+            })
+            .collect();
+        assert_eq!(averages, [(600, 2, Some(200.0)), (900, 1, Some(600.0))]);
+
+        // Without grid info
+        assert_eq!(column(&rows[0], &SITE_COLUMNS, "grid_to_home_power"), None);
+
+        let hourly = recorder
+            .query(Tier::Hourly, 0, 3600, Some("site"), false)
+            .unwrap();
+        assert_eq!(hourly[0].samples, 3);
+        assert_eq!(
+            column(&hourly[0], &SITE_COLUMNS, "home_load_power"),
+            Some(1000.0 / 3.0)
+        );
+    }
+
+    #[test]
+    fn queries_from_inclusive_to_exclusive() {
+        let mut recorder = recorder(serde_json::json!({}));
+        for fetched_at in [100, 200, 300] {
+            recorder
+                .record("site", &snapshot(fetched_at, 100.0, None))
+                .unwrap();
+        }
+
+        let rows = recorder.query(Tier::Raw, 200, 300, None, false).unwrap();
+        let timestamps: Vec<_> = rows.iter().map(|row| row.timestamp).collect();
+        assert_eq!(timestamps, [200]);
+
+        assert!(recorder
+            .query(Tier::Raw, 0, 400, Some("other"), false)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn prunes_each_tier() {
+        let mut recorder = recorder(serde_json::json!({
+            "raw": 1, "five_minutes": 2, "hourly": null,
+        }));
+        let day = 86400;
+        let count = |recorder: &Recorder, tier| {
+            let rows = recorder.query(tier, 0, 10 * day, None, true).unwrap();
+            rows.len()
+        };
+
+        recorder.record("site", &snapshot(0, 100.0, None)).unwrap();
+        recorder
+            .record("site", &snapshot(day + day / 2, 100.0, None))
+            .unwrap();
+        assert_eq!(count(&recorder, Tier::Raw), 1);
+        assert_eq!(count(&recorder, Tier::FiveMinutes), 2);
+
+        recorder
+            .record("site", &snapshot(3 * day, 100.0, None))
+            .unwrap();
+        assert_eq!(count(&recorder, Tier::Raw), 1);
+        assert_eq!(count(&recorder, Tier::FiveMinutes), 2);
+        assert_eq!(count(&recorder, Tier::Hourly), 3);
+        assert_eq!(count(&recorder, Tier::Daily), 3);
+    }
+
+    #[test]
+    fn prefers_the_reported_discharge_power() {
+        let mut recorder = recorder(serde_json::json!({}));
+        recorder
+            .record("site", &snapshot(0, 200.0, Some(180.0)))
+            .unwrap();
+        recorder.record("site", &snapshot(1, 200.0, None)).unwrap();
+
+        let rows = recorder.query(Tier::Raw, 0, 2, None, true).unwrap();
+        let discharge: Vec<_> = rows
+            .iter()
+            .map(|row| column(row, &DEVICE_COLUMNS, "discharging_power"))
+            .collect();
+        assert_eq!(discharge, [Some(180.0), Some(200.0)]);
+    }
+
+    #[test]
+    fn days_start_at_local_midnight() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+
+        assert_eq!(
+            bucket_start(Tier::Daily, timestamp("2024-05-01T23:30:00Z"), berlin),
+            timestamp("2024-05-02T00:00:00+02:00")
+        );
+        assert_eq!(
+            bucket_start(Tier::Daily, timestamp("2024-05-01T21:30:00Z"), berlin),
+            timestamp("2024-05-01T00:00:00+02:00")
+        );
+    }
+
+    #[test]
+    fn days_start_after_skipped_midnight() {
+        // Clocks went from 00:00 to 01:00 on 2023-03-26 in Beirut
+        let beirut: Tz = "Asia/Beirut".parse().unwrap();
+
+        assert_eq!(
+            bucket_start(Tier::Daily, timestamp("2023-03-26T12:00:00+03:00"), beirut),
+            timestamp("2023-03-26T01:00:00+03:00")
+        );
+    }
+
+    #[test]
+    fn hours_follow_fractional_offsets() {
+        let kathmandu: Tz = "Asia/Kathmandu".parse().unwrap();
+
+        assert_eq!(
+            bucket_start(
+                Tier::Hourly,
+                timestamp("2024-05-01T10:20:00+05:45"),
+                kathmandu
+            ),
+            timestamp("2024-05-01T10:00:00+05:45")
+        );
+        assert_eq!(
+            bucket_start(
+                Tier::FiveMinutes,
+                timestamp("2024-05-01T10:24:59+05:45"),
+                kathmandu
+            ),
+            timestamp("2024-05-01T10:20:00+05:45")
+        );
+    }
+
+    #[test]
+    fn keeps_raw_timestamps() {
+        let utc: Tz = "UTC".parse().unwrap();
+
+        assert_eq!(bucket_start(Tier::Raw, 1234, utc), 1234);
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+
+use crate::snapshot::Snapshot;
+use crate::solix::data;
+
+#[derive(Serialize)]
+struct Value<T> {
+    value: T,
+    unit: String,
+}
+
+impl<T> Value<T> {
+    fn new(value: T, unit: &str) -> Self {
+        Self {
+            value,
+            unit: unit.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SiteSummary<'a> {
+    site_id: &'a str,
+    site_name: &'a str,
+    updated_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Grid {
+    grid_to_home_power: Value<f64>,
+    photovoltaic_to_grid_power: Value<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grid_to_battery_power: Option<Value<f64>>,
+}
+
+#[derive(Serialize)]
+struct Smartmeter<'a> {
+    device_sn: &'a str,
+    grid_to_home_power: Option<Value<f64>>,
+    photovoltaic_to_grid_power: Option<Value<f64>>,
+}
+
+#[derive(Serialize)]
+struct Statistics {
+    total_energy: Option<Value<f64>>,
+    total_co2: Option<Value<f64>>,
+    total_money: Option<Value<f64>>,
+}
+
+#[derive(Serialize)]
+struct Solarbank<'a> {
+    device_sn: &'a str,
+    battery_soc: Value<u32>,
+    charging_power: Value<f64>,
+    output_power: Value<f64>,
+    photovoltaic_power: Value<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    solar_power: Vec<Option<Value<f64>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ac_power: Option<Value<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    battery_discharge_power: Option<Value<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grid_to_battery_power: Option<Value<f64>>,
+}
+
+#[derive(Serialize)]
+struct SolarbankTotals<'a> {
+    solar_power: [Value<f64>; 4],
+    to_home_load: Value<f64>,
+    total_battery_power: Value<f64>,
+    total_charging_power: Value<f64>,
+    total_output_power: Value<f64>,
+    total_photovoltaic_power: Value<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ac_power: Option<Value<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    battery_discharge_power: Option<Value<f64>>,
+    devices: Vec<Solarbank<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct SiteDetail<'a> {
+    site_id: &'a str,
+    site_name: &'a str,
+    updated_at: u64,
+    age: u64,
+    home_load_power: Value<f64>,
+    other_loads_power: Value<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    home_charging_power: Option<Value<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grid: Option<Grid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    solarbank: Option<SolarbankTotals<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smartmeter: Option<Smartmeter<'a>>,
+    statistics: Statistics,
+}
+
+fn watts(value: Option<f64>, unit: &str) -> Option<Value<f64>> {
+    value.map(|value| Value::new(value, unit))
+}
+
+/// Power per MPPT input, empty for devices without
+fn solar_power(solarbank: &data::Solarbank) -> Vec<Option<Value<f64>>> {
+    let inputs = [
+        solarbank.solar_power_1,
+        solarbank.solar_power_2,
+        solarbank.solar_power_3,
+        solarbank.solar_power_4,
+    ];
+
+    match inputs.iter().any(Option::is_some) {
+        true => inputs
+            .into_iter()
+            .map(|value| watts(value, &solarbank.power_unit))
+            .collect(),
+        false => Vec::new(),
+    }
+}
+
+fn solarbank_totals(info: &data::SolarbankInfo) -> SolarbankTotals<'_> {
+    let unit = info.power_unit.as_str();
+
+    SolarbankTotals {
+        solar_power: [
+            Value::new(info.solar_power_1, unit),
+            Value::new(info.solar_power_2, unit),
+            Value::new(info.solar_power_3, unit),
+            Value::new(info.solar_power_4, unit),
+        ],
+        to_home_load: Value::new(info.to_home_load, unit),
+        total_battery_power: Value::new(info.total_battery_power, "ratio"),
+        total_charging_power: Value::new(info.total_charging_power, unit),
+        total_output_power: Value::new(info.total_output_power, unit),
+        total_photovoltaic_power: Value::new(info.total_photovoltaic_power, unit),
+        ac_power: watts(info.ac_power, unit),
+        battery_discharge_power: watts(info.battery_discharge_power, unit),
+        devices: info
+            .solarbank_list
+            .iter()
+            .map(|solarbank| Solarbank {
+                device_sn: &solarbank.device_sn,
+                solar_power: solar_power(solarbank),
+                ac_power: watts(solarbank.ac_power, &solarbank.power_unit),
+                battery_discharge_power: watts(
+                    solarbank.battery_discharge_power,
+                    &solarbank.power_unit,
+                ),
+                grid_to_battery_power: watts(
+                    solarbank.grid_to_battery_power,
+                    &solarbank.power_unit,
+                ),
+                battery_soc: Value::new(solarbank.battery_power, "%"),
+                charging_power: Value::new(solarbank.charging_power, &solarbank.power_unit),
+                output_power: Value::new(solarbank.output_power, &solarbank.power_unit),
+                photovoltaic_power: Value::new(solarbank.photovoltaic_power, &solarbank.power_unit),
+            })
+            .collect(),
+    }
+}
+
+pub fn site_summary<'a>(site: &'a data::SiteList, snapshot: Option<&Snapshot>) -> SiteSummary<'a> {
+    SiteSummary {
+        site_id: &site.site_id,
+        site_name: &site.site_name,
+        updated_at: snapshot.map(|s| s.fetched_at),
+    }
+}
+
+pub fn site_detail<'a>(site: &'a data::SiteList, snapshot: &'a Snapshot) -> SiteDetail<'a> {
+    let data = &snapshot.scen_info;
+    let statistic = |index: usize| {
+        data.statistics
+            .get(index)
+            .map(|s| Value::new(s.total, &s.unit))
+    };
+
+    SiteDetail {
+        site_id: &site.site_id,
+        site_name: &site.site_name,
+        updated_at: snapshot.fetched_at,
+        age: snapshot.age(),
+        home_load_power: Value::new(data.home_load_power, "W"),
+        other_loads_power: Value::new(data.other_loads_power, "W"),
+        home_charging_power: data
+            .home_info
+            .as_ref()
+            .map(|home| Value::new(home.charging_power, &home.power_unit)),
+        grid: data.grid_info.as_ref().map(|grid| Grid {
+            grid_to_home_power: Value::new(grid.grid_to_home_power, "W"),
+            photovoltaic_to_grid_power: Value::new(grid.photovoltaic_to_grid_power, "W"),
+            grid_to_battery_power: watts(grid.grid_to_battery_power, "W"),
+        }),
+        solarbank: data
+            .solarbank_info
+            .as_ref()
+            .map(|info| solarbank_totals(info)),
+        smartmeter: data.smartmeter_info.as_ref().map(|smartmeter| Smartmeter {
+            device_sn: &smartmeter.device_sn,
+            grid_to_home_power: watts(smartmeter.grid_to_home_power, "W"),
+            photovoltaic_to_grid_power: watts(smartmeter.photovoltaic_to_grid_power, "W"),
+        }),
+        statistics: Statistics {
+            total_energy: statistic(0),
+            total_co2: statistic(1),
+            total_money: statistic(2),
+        },
+    }
+}
@@ -0,0 +1,448 @@
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::solix::{self, data, Credentials, SolixApi};
+
+/// Highest output power in W the Solarbank accepts for a slot
+pub const MAX_POWER: u32 = 800;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Usage(String),
+    #[error("Invalid schedule: {0}")]
+    Invalid(String),
+    #[error("Failed to read schedule file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse schedule file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Failed to access the schedule: {0}")]
+    Api(#[from] solix::Error),
+}
+
+/// Declarative schedule, as read by `schedule set`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFile {
+    slots: Vec<SlotConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SlotConfig {
+    start_time: String,
+    end_time: String,
+    #[serde(default = "default_turn_on")]
+    turn_on: bool,
+    /// Output power in W
+    power: u32,
+    #[serde(default = "default_charge_priority")]
+    charge_priority: u32,
+}
+
+fn default_turn_on() -> bool {
+    true
+}
+
+fn default_charge_priority() -> u32 {
+    80
+}
+
+/// Minutes since midnight of a `HH:MM` time, `24:00` being the end of the day
+pub fn minutes(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+
+    match (hours, minutes) {
+        (0..=23, 0..=59) | (24, 0) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+/// Index of the slot covering `minute` of the day. Slots include their start, but
+/// not their end.
+pub fn active_index(schedule: &data::Schedule, minute: u32) -> Option<usize> {
+    schedule.ranges.iter().position(|slot| {
+        match (minutes(&slot.start_time), minutes(&slot.end_time)) {
+            (Some(start), Some(end)) => start <= minute && minute < end,
+            _ => false,
+        }
+    })
+}
+
+pub fn active_slot(schedule: &data::Schedule, minute: u32) -> Option<&data::ScheduleSlot> {
+    active_index(schedule, minute).map(|index| &schedule.ranges[index])
+}
+
+impl ScheduleFile {
+    /// Checks times, power and overlaps, returning the slots sorted by start
+    fn validate(mut self) -> Result<Vec<SlotConfig>, Error> {
+        if self.slots.is_empty() {
+            return Err(Error::Invalid("No slots".into()));
+        }
+
+        for slot in &self.slots {
+            let range = slot.range();
+            let (Some(start), Some(end)) = (minutes(&slot.start_time), minutes(&slot.end_time))
+            else {
+                return Err(Error::Invalid(format!(
+                    "Slot {range} has a time outside of 00:00-24:00"
+                )));
+            };
+            if start >= end {
+                return Err(Error::Invalid(format!(
+                    "Slot {range} ends before it starts"
+                )));
+            }
+            if slot.power > MAX_POWER {
+                return Err(Error::Invalid(format!(
+                    "Slot {range} exceeds {MAX_POWER} W with {} W",
+                    slot.power
+                )));
+            }
+            if slot.charge_priority > 100 {
+                return Err(Error::Invalid(format!(
+                    "Slot {range} has a charge priority above 100%"
+                )));
+            }
+        }
+
+        self.slots
+            .sort_by_key(|slot| minutes(&slot.start_time).unwrap_or_default());
+        for pair in self.slots.windows(2) {
+            if minutes(&pair[0].end_time) > minutes(&pair[1].start_time) {
+                return Err(Error::Invalid(format!(
+                    "Slots {} and {} overlap",
+                    pair[0].range(),
+                    pair[1].range()
+                )));
+            }
+        }
+
+        Ok(self.slots)
+    }
+}
+
+impl SlotConfig {
+    fn range(&self) -> String {
+        format!("{}-{}", self.start_time, self.end_time)
+    }
+}
+
+/// Builds the schedule to write from the validated slots. Fields not covered by
+/// the file, like the names of the appliance loads, are taken from the current
+/// schedule, the unknown fields of a slot only from the slot with the same window.
+fn apply(current: &data::Schedule, slots: &[SlotConfig]) -> data::Schedule {
+    let template = current
+        .ranges
+        .iter()
+        .find_map(|slot| slot.appliance_loads.first())
+        .cloned()
+        .unwrap_or_else(|| data::ApplianceLoad {
+            id: 0,
+            name: String::new(),
+            power: 0,
+            number: 1,
+            other: serde_json::Map::new(),
+        });
+
+    let ranges = slots
+        .iter()
+        .enumerate()
+        .map(|(index, slot)| data::ScheduleSlot {
+            id: index as u32,
+            start_time: slot.start_time.clone(),
+            end_time: slot.end_time.clone(),
+            turn_on: slot.turn_on,
+            appliance_loads: vec![data::ApplianceLoad {
+                power: slot.power,
+                ..template.clone()
+            }],
+            charge_priority: slot.charge_priority,
+            other: current
+                .ranges
+                .iter()
+                .find(|current| {
+                    current.start_time == slot.start_time && current.end_time == slot.end_time
+                })
+                .map(|current| current.other.clone())
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    data::Schedule {
+        ranges,
+        other: current.other.clone(),
+    }
+}
+
+fn describe(slot: &data::ScheduleSlot) -> String {
+    format!(
+        "{}-{} {} {} W, charge priority {}%",
+        slot.start_time,
+        slot.end_time,
+        if slot.turn_on { "on" } else { "off" },
+        slot.power(),
+        slot.charge_priority
+    )
+}
+
+/// Lines of the slots only in the current (`-`) or only in the new schedule (`+`)
+fn diff(current: &data::Schedule, new: &data::Schedule) -> Vec<String> {
+    let current: Vec<String> = current.ranges.iter().map(describe).collect();
+    let new: Vec<String> = new.ranges.iter().map(describe).collect();
+
+    let removed = current
+        .iter()
+        .filter(|slot| !new.contains(slot))
+        .map(|slot| format!("- {slot}"));
+    let added = new
+        .iter()
+        .filter(|slot| !current.contains(slot))
+        .map(|slot| format!("+ {slot}"));
+
+    removed.chain(added).collect()
+}
+
+struct Options {
+    file: PathBuf,
+    site_id: Option<String>,
+    dry_run: bool,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, Error> {
+    let mut args = args.iter();
+    match args.next().map(String::as_str) {
+        Some("set") => {}
+        Some(other) => return Err(Error::Usage(format!("Unknown action {other:?}"))),
+        None => return Err(Error::Usage("Missing action, expected set".into())),
+    }
+
+    let mut file = None;
+    let mut site_id = None;
+    let mut dry_run = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| Error::Usage(format!("Missing value for {arg}")))
+        };
+
+        match arg.as_str() {
+            "--file" => file = Some(PathBuf::from(value()?)),
+            "--site" => site_id = Some(value()?.clone()),
+            "--dry-run" => dry_run = true,
+            other => return Err(Error::Usage(format!("Unknown argument {other:?}"))),
+        }
+    }
+
+    let Some(file) = file else {
+        return Err(Error::Usage("--file is required".into()));
+    };
+
+    Ok(Options {
+        file,
+        site_id,
+        dry_run,
+    })
+}
+
+/// Writes the schedule of a file to a site, printing the changed slots to stdout.
+/// `args` are the arguments after the subcommand and config.
+pub fn run(
+    solix: &SolixApi,
+    creds: &Credentials,
+    sites: &[data::SiteList],
+    args: &[String],
+) -> Result<(), Error> {
+    let options = parse_options(args)?;
+
+    let file: ScheduleFile = serde_json::from_str(&fs::read_to_string(&options.file)?)?;
+    let slots = file.validate()?;
+
+    let site_id = match (&options.site_id, sites) {
+        (Some(site_id), _) => site_id.clone(),
+        (None, [site]) => site.site_id.clone(),
+        (None, _) => {
+            return Err(Error::Usage(format!(
+                "{} sites found, select one with --site",
+                sites.len()
+            )))
+        }
+    };
+
+    let current: data::Schedule =
+        solix.get_site_device_param(creds, &site_id, data::Schedule::PARAM_TYPE)?;
+    let new = apply(&current, &slots);
+
+    let changes = diff(&current, &new);
+    if changes.is_empty() {
+        println!("Schedule of site {site_id} is up to date");
+        return Ok(());
+    }
+    for line in &changes {
+        println!("{line}");
+    }
+
+    if options.dry_run {
+        println!("Dry run, schedule of site {site_id} not changed");
+        return Ok(());
+    }
+
+    solix.set_site_device_param(creds, &site_id, data::Schedule::PARAM_TYPE, &new)?;
+    println!("Schedule of site {site_id} updated");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_unknown_fields_of_the_same_window() {
+        let current: data::Schedule = serde_json::from_value(serde_json::json!({
+            "ranges": [
+                {
+                    "start_time": "00:00",
+                    "end_time": "08:00",
+                    "turn_on": true,
+                    "appliance_loads": [],
+                    "charge_priority": 80,
+                    "night": true,
+                },
+                {
+                    "start_time": "08:00",
+                    "end_time": "24:00",
+                    "turn_on": true,
+                    "appliance_loads": [],
+                    "charge_priority": 80,
+                    "day": true,
+                },
+            ],
+        }))
+        .unwrap();
+        let file: ScheduleFile = serde_json::from_value(serde_json::json!({
+            "slots": [
+                { "start_time": "00:00", "end_time": "06:00", "power": 100 },
+                { "start_time": "06:00", "end_time": "08:00", "power": 200 },
+                { "start_time": "08:00", "end_time": "24:00", "power": 300 },
+            ],
+        }))
+        .unwrap();
+
+        let new = apply(&current, &file.validate().unwrap());
+
+        assert!(new.ranges[0].other.is_empty());
+        assert!(new.ranges[1].other.is_empty());
+        assert_eq!(new.ranges[2].other, current.ranges[1].other);
+        assert_eq!(new.ranges[2].power(), 300);
+    }
+
+    fn validate(slots: serde_json::Value) -> Result<Vec<SlotConfig>, String> {
+        let file: ScheduleFile =
+            serde_json::from_value(serde_json::json!({ "slots": slots })).unwrap();
+        file.validate().map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn validates_slots() {
+        for (slots, error) in [
+            (serde_json::json!([]), "No slots"),
+            (
+                serde_json::json!([
+                    { "start_time": "00:00", "end_time": "12:00", "power": 100 },
+                    { "start_time": "11:00", "end_time": "24:00", "power": 100 },
+                ]),
+                "Slots 00:00-12:00 and 11:00-24:00 overlap",
+            ),
+            (
+                serde_json::json!([{ "start_time": "00:00", "end_time": "24:00", "power": 801 }]),
+                "Slot 00:00-24:00 exceeds 800 W with 801 W",
+            ),
+            (
+                serde_json::json!([{ "start_time": "00:00", "end_time": "24:30", "power": 0 }]),
+                "Slot 00:00-24:30 has a time outside of 00:00-24:00",
+            ),
+            (
+                serde_json::json!([{ "start_time": "7:00", "end_time": "08:00", "power": 0 }]),
+                "Slot 7:00-08:00 has a time outside of 00:00-24:00",
+            ),
+            (
+                serde_json::json!([{ "start_time": "08:00", "end_time": "08:00", "power": 0 }]),
+                "Slot 08:00-08:00 ends before it starts",
+            ),
+            (
+                serde_json::json!([{ "start_time": "22:00", "end_time": "06:00", "power": 0 }]),
+                "Slot 22:00-06:00 ends before it starts",
+            ),
+            (
+                serde_json::json!([{
+                    "start_time": "00:00", "end_time": "24:00", "power": 0, "charge_priority": 101,
+                }]),
+                "Slot 00:00-24:00 has a charge priority above 100%",
+            ),
+        ] {
+            assert_eq!(
+                validate(slots).unwrap_err(),
+                format!("Invalid schedule: {error}")
+            );
+        }
+    }
+
+    #[test]
+    fn sorts_valid_slots() {
+        let slots = validate(serde_json::json!([
+            { "start_time": "12:00", "end_time": "24:00", "power": 800 },
+            { "start_time": "00:00", "end_time": "12:00", "power": 0 },
+        ]))
+        .unwrap();
+
+        let ranges: Vec<_> = slots.iter().map(SlotConfig::range).collect();
+        assert_eq!(ranges, ["00:00-12:00", "12:00-24:00"]);
+    }
+
+    #[test]
+    fn lists_changed_slots() {
+        let current: data::Schedule = serde_json::from_value(serde_json::json!({
+            "ranges": [
+                {
+                    "start_time": "00:00",
+                    "end_time": "12:00",
+                    "turn_on": true,
+                    "appliance_loads": [{ "power": 100 }],
+                    "charge_priority": 80,
+                },
+                {
+                    "start_time": "12:00",
+                    "end_time": "24:00",
+                    "turn_on": true,
+                    "appliance_loads": [{ "power": 200 }],
+                    "charge_priority": 80,
+                },
+            ],
+        }))
+        .unwrap();
+        let slots = validate(serde_json::json!([
+            { "start_time": "00:00", "end_time": "12:00", "power": 100 },
+            { "start_time": "12:00", "end_time": "24:00", "power": 300, "turn_on": false },
+        ]))
+        .unwrap();
+
+        let new = apply(&current, &slots);
+
+        assert_eq!(
+            diff(&current, &new),
+            [
+                "- 12:00-24:00 on 200 W, charge priority 80%",
+                "+ 12:00-24:00 off 300 W, charge priority 80%",
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+    }
+}
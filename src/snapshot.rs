@@ -0,0 +1,28 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::solix::data;
+
+pub struct Snapshot {
+    pub fetched_at: u64,
+    pub scen_info: data::ScenInfo,
+}
+
+impl Snapshot {
+    pub fn new(scen_info: data::ScenInfo) -> Self {
+        Self {
+            fetched_at: now(),
+            scen_info,
+        }
+    }
+
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.fetched_at)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -31,6 +31,7 @@ pub enum Response<T> {
 }
 
 pub struct SolixApi {
+    url: String,
     country: String,
     timezone: String,
     shared_secret: p256::ecdh::SharedSecret,
@@ -38,7 +39,11 @@ pub struct SolixApi {
 }
 
 impl SolixApi {
-    pub fn new(country: impl Into<String>, timezone: impl Into<String>) -> Self {
+    pub fn new(
+        url: impl Into<String>,
+        country: impl Into<String>,
+        timezone: impl Into<String>,
+    ) -> Self {
         let ecdh_secret = p256::ecdh::EphemeralSecret::random(&mut OsRng);
 
         let server_pub_key_bytes =
@@ -50,6 +55,7 @@ impl SolixApi {
         let public_key = hex::encode(ecdh_secret.public_key().to_sec1_bytes());
 
         SolixApi {
+            url: url.into(),
             country: country.into(),
             timezone: timezone.into(),
             shared_secret,
@@ -79,7 +85,7 @@ impl SolixApi {
     where
         T: DeserializeOwned,
     {
-        let mut request = ureq::post(&format!("https://ankerpower-api-eu.anker.com{endpoint}"))
+        let mut request = ureq::post(&format!("{}{endpoint}", self.url))
             .header("Country", &self.country)
             .header("Timezone", &self.timezone)
             .header("Model-Type", "DESKTOP")
@@ -128,14 +134,15 @@ impl SolixApi {
         }
     }
 
+    /// The scen info as returned, to be parsed into [`data::ScenInfo`]
     pub fn get_scen_info(
         &self,
         creds: &Credentials,
         site_id: &str,
-    ) -> Result<data::ScenInfo, Error> {
+    ) -> Result<serde_json::Value, Error> {
         let data = serde_json::json!({ "site_id": site_id });
 
-        match self.fetch::<data::ScenInfo>(
+        match self.fetch::<serde_json::Value>(
             "/power_service/v1/site/get_scen_info",
             Some(&data),
             Some(creds),
@@ -146,6 +153,107 @@ impl SolixApi {
         }
     }
 
+    pub fn get_site_device_param<T>(
+        &self,
+        creds: &Credentials,
+        site_id: &str,
+        param_type: &str,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let data = serde_json::json!({ "site_id": site_id, "param_type": param_type });
+
+        match self.fetch::<data::SiteDeviceParam<T>>(
+            "/power_service/v1/site/get_site_device_param",
+            Some(&data),
+            Some(creds),
+        ) {
+            Ok(Response::Data { data, .. }) => Ok(data.param_data),
+            Ok(Response::NoData { msg, code, .. }) => Err(Error::Api(code, msg)),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn set_site_device_param<T>(
+        &self,
+        creds: &Credentials,
+        site_id: &str,
+        param_type: &str,
+        param_data: &T,
+    ) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let param_data = serde_json::to_string(param_data).expect("Failed to encode param data");
+        let data = serde_json::json!({
+            "site_id": site_id,
+            "param_type": param_type,
+            "cmd": 17,
+            "param_data": param_data
+        });
+
+        match self.fetch::<serde_json::Value>(
+            "/power_service/v1/site/set_site_device_param",
+            Some(&data),
+            Some(creds),
+        ) {
+            Ok(Response::Data { code: 0, .. } | Response::NoData { code: 0, .. }) => Ok(()),
+            Ok(Response::Data { msg, code, .. } | Response::NoData { msg, code, .. }) => {
+                Err(Error::Api(code, msg))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_mqtt_info(&self, creds: &Credentials) -> Result<data::MqttInfo, Error> {
+        match self.fetch::<data::MqttInfo>(
+            "/app/devicemanage/get_user_mqtt_info",
+            None::<()>,
+            Some(creds),
+        ) {
+            Ok(Response::Data { data, .. }) => Ok(data),
+            Ok(Response::NoData { msg, code, .. }) => Err(Error::Api(code, msg)),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_ota_info(
+        &self,
+        creds: &Credentials,
+        device_sn: &str,
+    ) -> Result<data::OtaInfo, Error> {
+        let data = serde_json::json!({ "solar_bank_sn": device_sn, "solar_sn": "" });
+
+        match self.fetch::<data::OtaInfo>(
+            "/power_service/v1/app/compatible/get_ota_info",
+            Some(&data),
+            Some(creds),
+        ) {
+            Ok(Response::Data { data, .. }) => Ok(data),
+            Ok(Response::NoData { msg, code, .. }) => Err(Error::Api(code, msg)),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_site_price(
+        &self,
+        creds: &Credentials,
+        site_id: &str,
+    ) -> Result<data::SitePrice, Error> {
+        let data = serde_json::json!({ "site_id": site_id });
+
+        match self.fetch::<data::SitePrice>(
+            "/power_service/v1/site/get_site_price",
+            Some(&data),
+            Some(creds),
+        ) {
+            Ok(Response::Data { data, .. }) => Ok(data),
+            Ok(Response::NoData { msg, code, .. }) => Err(Error::Api(code, msg)),
+            Err(err) => Err(err),
+        }
+    }
+
     pub fn get_site_homepage(&self, creds: &Credentials) -> Result<data::SiteHomepage, Error> {
         match self.fetch::<data::SiteHomepage>(
             "/power_service/v1/site/get_site_homepage",
@@ -56,19 +56,22 @@ impl Credentials {
     }
 
     pub fn save(self, path: &Path) -> Self {
-        if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                if let Err(err) = std::fs::create_dir_all(parent) {
-                    log::warn!(
-                        "Failed to create directory for credentials file ({parent:?}): {err:?}"
-                    );
-                }
-            }
+        if let Some(parent) = path.parent()
+            && !parent.exists()
+            && let Err(err) = std::fs::create_dir_all(parent)
+        {
+            log::warn!("Failed to create directory for credentials file ({parent:?}): {err:?}");
         }
 
+        // Written to a temporary file first, so an interrupted write never leaves a
+        // truncated cache behind
+        let tmp = path.with_extension("tmp");
+
         match serde_json::to_string(&self) {
             Ok(creds) => {
-                if let Err(err) = std::fs::write(path, creds) {
+                if let Err(err) =
+                    std::fs::write(&tmp, creds).and_then(|_| std::fs::rename(&tmp, path))
+                {
                     log::warn!(
                         "Failed to write credentials to file ({path:?}): {err:?}"
                     );
@@ -1,5 +1,42 @@
-use serde::Deserialize;
-use serde_with::{serde_as, DisplayFromStr};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use serde_with::json::JsonString;
+use serde_with::{serde_as, DefaultOnNull, DeserializeAs, DisplayFromStr};
+
+/// Optional values that the cloud sends as numbers, numeric strings, empty strings
+/// or `null` depending on the device. Anything unparsable is treated as missing.
+pub struct Lenient;
+
+impl<'de> DeserializeAs<'de, Option<f64>> for Lenient {
+    fn deserialize_as<D>(deserializer: D) -> Result<Option<f64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Number(number) => number.as_f64(),
+            Value::String(text) => text.trim().parse().ok(),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> DeserializeAs<'de, Option<bool>> for Lenient {
+    fn deserialize_as<D>(deserializer: D) -> Result<Option<bool>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Bool(value) => Some(value),
+            Value::Number(number) => number.as_f64().map(|value| value != 0.0),
+            Value::String(text) => match text.trim() {
+                "1" | "true" | "on" => Some(true),
+                "0" | "false" | "off" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
 
 #[derive(Deserialize, Debug)]
 pub struct Login {
@@ -8,44 +45,101 @@ pub struct Login {
     pub user_id: String,
 }
 
+/// Client certificate and broker of the MQTT connection used by the app
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttInfo {
+    pub user_id: String,
+    pub app_name: String,
+    pub certificate_id: String,
+    pub endpoint_addr: String,
+    pub certificate_pem: String,
+    pub private_key: String,
+    pub aws_root_ca1_pem: String,
+}
+
 #[serde_as]
 #[derive(Deserialize, Debug)]
 pub struct Solarbank {
     #[serde_as(as = "DisplayFromStr")]
     pub battery_power: u32,
     #[serde_as(as = "DisplayFromStr")]
-    pub charging_power: u32,
+    pub charging_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub output_power: u32,
+    pub output_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub photovoltaic_power: u32,
+    pub photovoltaic_power: f64,
     pub power_unit: String,
     pub device_sn: String,
+    #[serde(default)]
+    pub device_pn: Option<String>,
+    /// Firmware version, empty if unknown
+    #[serde(default)]
+    pub main_version: Option<String>,
+    // Solarbank 2 only
+    /// Input power per MPPT
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub solar_power_1: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub solar_power_2: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub solar_power_3: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub solar_power_4: Option<f64>,
+    /// Output of the AC socket
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub ac_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub battery_discharge_power: Option<f64>,
+    /// AC charging from the grid
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub grid_to_battery_power: Option<f64>,
+}
+
+impl Solarbank {
+    pub fn firmware_version(&self) -> Option<&str> {
+        self.main_version
+            .as_deref()
+            .filter(|version| !version.is_empty())
+    }
 }
 
 #[serde_as]
 #[derive(Deserialize, Debug)]
 pub struct SolarbankInfo {
     #[serde_as(as = "DisplayFromStr")]
-    pub solar_power_1: u32,
+    pub solar_power_1: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub solar_power_2: u32,
+    pub solar_power_2: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub solar_power_3: u32,
+    pub solar_power_3: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub solar_power_4: u32,
+    pub solar_power_4: f64,
     pub solarbank_list: Vec<Solarbank>,
     #[serde_as(as = "DisplayFromStr")]
-    pub to_home_load: u32,
+    pub to_home_load: f64,
     #[serde_as(as = "DisplayFromStr")]
     pub total_battery_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub total_charging_power: u32,
+    pub total_charging_power: f64,
     #[serde_as(as = "DisplayFromStr")]
     pub total_output_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub total_photovoltaic_power: u32,
+    pub total_photovoltaic_power: f64,
     pub power_unit: String,
+    // Solarbank 2 only
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub ac_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub battery_discharge_power: Option<f64>,
 }
 
 #[serde_as]
@@ -62,9 +156,13 @@ pub struct Statistic {
 #[derive(Deserialize, Debug)]
 pub struct GridInfo {
     #[serde_as(as = "DisplayFromStr")]
-    pub grid_to_home_power: u32,
+    pub grid_to_home_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub photovoltaic_to_grid_power: u32,
+    pub photovoltaic_to_grid_power: f64,
+    /// Solarbank 2 AC only, in W
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub grid_to_battery_power: Option<f64>,
 }
 
 #[serde_as]
@@ -78,29 +176,290 @@ pub struct HomeInfo {
 #[serde_as]
 #[derive(Deserialize, Debug)]
 pub struct ScenInfo {
-    pub grid_info: GridInfo,
-    pub home_info: HomeInfo,
-    pub solarbank_info: SolarbankInfo,
+    // Missing for sites without a Solarbank, e.g. with power stations only
+    #[serde(default)]
+    pub grid_info: Option<GridInfo>,
+    #[serde(default)]
+    pub home_info: Option<HomeInfo>,
+    #[serde(default)]
+    pub solarbank_info: Option<SolarbankInfo>,
+    #[serde_as(as = "DefaultOnNull")]
+    #[serde(default)]
     pub statistics: Vec<Statistic>,
     #[serde_as(as = "DisplayFromStr")]
-    pub home_load_power: u32,
+    #[serde(default)]
+    pub home_load_power: f64,
     #[serde_as(as = "DisplayFromStr")]
-    pub other_loads_power: u32,
+    #[serde(default)]
+    pub other_loads_power: f64,
+    #[serde(default)]
+    pub smartmeter_info: Option<SmartmeterInfo>,
+    #[serde(default)]
+    pub smart_plug_info: Option<SmartPlugInfo>,
+}
+
+impl ScenInfo {
+    /// The Solarbanks of the site, none without Solarbank info
+    pub fn solarbanks(&self) -> &[Solarbank] {
+        self.solarbank_info
+            .as_ref()
+            .map_or(&[], |info| info.solarbank_list.as_slice())
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug, Default)]
+pub struct SmartPlugInfo {
+    #[serde_as(as = "DefaultOnNull")]
+    #[serde(default)]
+    pub smartplug_list: Vec<SmartPlug>,
 }
 
+/// Anker smart plug, powers in W
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SmartPlug {
+    pub device_sn: String,
+    #[serde(default)]
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub device_pn: Option<String>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub plug_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub switch_status: Option<bool>,
+    /// Energy since midnight in kWh
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub energy_today: Option<f64>,
+}
+
+/// Anker Smart Meter paired with a Solarbank 2, powers in W
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SmartmeterInfo {
+    #[serde(default)]
+    pub device_sn: String,
+    #[serde(default)]
+    pub device_pn: Option<String>,
+    /// Import
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub grid_to_home_power: Option<f64>,
+    /// Export
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub photovoltaic_to_grid_power: Option<f64>,
+}
+
+#[serde_as]
 #[derive(Deserialize, Debug)]
 pub struct SiteHomepage {
     // pub powerpanel_list: Vec<serde_json::Value>,
-    // pub pps_list: Vec<serde_json::Value>,
+    #[serde_as(as = "DefaultOnNull")]
+    #[serde(default)]
+    pub pps_list: Vec<Pps>,
     // pub solar_list: Vec<serde_json::Value>,
     // pub solarbank_list: Vec<serde_json::Value>,
     pub site_list: Vec<SiteList>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Firmware update state of a device, by component
+#[serde_as]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OtaInfo {
+    #[serde_as(as = "DefaultOnNull")]
+    #[serde(default)]
+    pub ota_children: Vec<OtaComponent>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OtaComponent {
+    #[serde(default)]
+    pub device_type: String,
+    /// Version offered as update, empty without one
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub force_upgrade: bool,
+}
+
+impl OtaInfo {
+    /// The firmware version offered for the device, if any
+    pub fn available_version(&self) -> Option<&str> {
+        self.ota_children
+            .iter()
+            .map(|component| component.version.as_str())
+            .find(|version| !version.is_empty())
+    }
+
+    pub fn update_available(&self, current_version: Option<&str>) -> bool {
+        self.available_version()
+            .is_some_and(|version| Some(version) != current_version)
+    }
+}
+
+/// Portable power station, powers in W
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Pps {
+    pub device_sn: String,
+    #[serde(default)]
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub device_pn: Option<String>,
+    /// State of charge in %
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub battery_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub ac_input_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub dc_input_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub ac_output_power: Option<f64>,
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub dc_output_power: Option<f64>,
+    /// Battery temperature in °C
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub temperature: Option<f64>,
+}
+
+/// Electricity price stored for the site in the Anker app
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+pub struct SitePrice {
+    /// Price per kWh
+    #[serde_as(as = "Lenient")]
+    #[serde(default)]
+    pub price: Option<f64>,
+    /// Currency symbol, e.g. `€`
+    #[serde(default)]
+    pub site_price_unit: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct SiteList {
-    // ms_type: serde_json::Number,
-    // power_site_type: serde_json::Number,
+    #[serde(default)]
+    pub ms_type: Option<serde_json::Number>,
+    #[serde(default)]
+    pub power_site_type: Option<serde_json::Number>,
     pub site_id: String,
     pub site_name: String,
 }
+
+/// Device parameters of a site, `param_data` being a JSON encoded string
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SiteDeviceParam<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    #[serde_as(as = "JsonString")]
+    pub param_data: T,
+}
+
+/// Home load output schedule of the Solarbank (`param_type` 4). Unknown fields are
+/// kept, so that a schedule can be written back unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub ranges: Vec<ScheduleSlot>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Schedule {
+    pub const PARAM_TYPE: &str = "4";
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleSlot {
+    #[serde(default)]
+    pub id: u32,
+    /// `HH:MM`, local time of the site
+    pub start_time: String,
+    /// `HH:MM`, up to `24:00`
+    pub end_time: String,
+    pub turn_on: bool,
+    pub appliance_loads: Vec<ApplianceLoad>,
+    /// Battery state of charge in % below which solar power charges the battery first
+    pub charge_priority: u32,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ScheduleSlot {
+    /// Target output power in W
+    pub fn power(&self) -> u32 {
+        self.appliance_loads.iter().map(|load| load.power).sum()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApplianceLoad {
+    #[serde(default)]
+    pub id: u32,
+    #[serde(default)]
+    pub name: String,
+    pub power: u32,
+    #[serde(default)]
+    pub number: u32,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[serde_as]
+    #[derive(Deserialize)]
+    struct Values {
+        #[serde_as(as = "Lenient")]
+        number: Option<f64>,
+        #[serde_as(as = "Lenient")]
+        flag: Option<bool>,
+    }
+
+    fn lenient(value: Value) -> (Option<f64>, Option<bool>) {
+        let values: Values =
+            serde_json::from_value(serde_json::json!({ "number": value, "flag": value })).unwrap();
+        (values.number, values.flag)
+    }
+
+    #[test]
+    fn parses_lenient_values() {
+        assert_eq!(lenient(serde_json::json!("")), (None, None));
+        assert_eq!(lenient(serde_json::json!("12.5")), (Some(12.5), None));
+        assert_eq!(lenient(serde_json::json!(" 1 ")), (Some(1.0), Some(true)));
+        assert_eq!(lenient(serde_json::json!(0)), (Some(0.0), Some(false)));
+        assert_eq!(lenient(serde_json::json!(null)), (None, None));
+        assert_eq!(lenient(serde_json::json!("on")), (None, Some(true)));
+        assert_eq!(lenient(serde_json::json!(false)), (None, Some(false)));
+    }
+
+    #[test]
+    fn deserializes_sites_with_power_stations_only() {
+        let scen_info: ScenInfo = serde_json::from_value(serde_json::json!({
+            "home_info": null,
+            "solarbank_info": null,
+            "statistics": null,
+            "home_load_power": "0",
+            "other_loads_power": "0",
+        }))
+        .unwrap();
+
+        assert!(scen_info.grid_info.is_none());
+        assert!(scen_info.home_info.is_none());
+        assert!(scen_info.solarbank_info.is_none());
+        assert!(scen_info.solarbanks().is_empty());
+        assert!(scen_info.statistics.is_empty());
+    }
+}
@@ -0,0 +1,490 @@
+pub mod source;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::energy::Flow;
+use crate::schedule;
+use crate::solix::data;
+use source::{PriceFormat, PriceSourceConfig};
+
+/// Local tariff, replacing the price stored in the Anker app
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TariffConfig {
+    currency: String,
+    /// Import price per kWh outside of the windows, the site price if unset
+    #[serde(default)]
+    price: Option<f64>,
+    /// Time-of-use prices in the configured timezone
+    #[serde(default)]
+    windows: Vec<PriceWindow>,
+    /// Compensation per exported kWh
+    #[serde(default)]
+    feed_in: Option<f64>,
+    /// Dynamic grid prices, taking precedence over the other import prices
+    #[serde(default)]
+    source: Option<PriceSourceConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PriceWindow {
+    /// `HH:MM`, windows ending before they start span midnight
+    start: String,
+    end: String,
+    price: f64,
+}
+
+impl PriceWindow {
+    fn contains(&self, minute: u32) -> bool {
+        match (schedule::minutes(&self.start), schedule::minutes(&self.end)) {
+            (Some(start), Some(end)) if start <= end => start <= minute && minute < end,
+            (Some(start), Some(end)) => start <= minute || minute < end,
+            _ => false,
+        }
+    }
+}
+
+impl TariffConfig {
+    /// Checks the window times, as they are only evaluated later, and that aWATTar
+    /// prices are used with their currency
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(source) = &self.source
+            && source.format() == PriceFormat::Awattar
+            && self.currency != "EUR"
+        {
+            return Err(format!(
+                "aWATTar prices are in EUR, but the tariff currency is {}",
+                self.currency
+            ));
+        }
+
+        for window in &self.windows {
+            if schedule::minutes(&window.start).is_none()
+                || schedule::minutes(&window.end).is_none()
+            {
+                return Err(format!(
+                    "Invalid tariff window {}-{}, expected HH:MM",
+                    window.start, window.end
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn source(&self) -> Option<&PriceSourceConfig> {
+        self.source.as_ref()
+    }
+}
+
+/// Prices per kWh in effect at some time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    pub currency: String,
+    pub import: Option<f64>,
+    pub feed_in: Option<f64>,
+    /// Dynamic price of the grid, as fetched from the price source
+    pub grid: Option<f64>,
+}
+
+/// The prices at `minute` of the local day. The local tariff takes precedence over
+/// the site price, which is used with its own currency without a local tariff.
+/// `grid` is the current price of the price source, if any.
+pub fn price(
+    config: Option<&TariffConfig>,
+    site_price: Option<&data::SitePrice>,
+    minute: Option<u32>,
+    grid: Option<f64>,
+) -> Option<Price> {
+    let site_import = site_price.and_then(|site_price| site_price.price);
+
+    let Some(config) = config else {
+        let site_price = site_price?;
+        return Some(Price {
+            currency: site_price.site_price_unit.clone(),
+            import: Some(site_import?),
+            feed_in: None,
+            grid: None,
+        });
+    };
+
+    let window =
+        minute.and_then(|minute| config.windows.iter().find(|window| window.contains(minute)));
+
+    Some(Price {
+        currency: config.currency.clone(),
+        import: grid
+            .or(window.map(|window| window.price))
+            .or(config.price)
+            .or(site_import),
+        feed_in: config.feed_in,
+        grid,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Account {
+    /// Grid import avoided by solar and battery
+    Savings,
+    FeedInRevenue,
+    /// Grid energy valued at the dynamic grid price
+    GridImportCost,
+    GridExportCost,
+    /// Amounts accrued at negative prices, as positive values, so that all
+    /// counters only increase
+    SavingsNegative,
+    FeedInRevenueNegative,
+    GridImportCostNegative,
+    GridExportCostNegative,
+}
+
+impl Account {
+    /// The account of the amounts at negative prices
+    fn negative(self) -> Self {
+        match self {
+            Account::Savings => Account::SavingsNegative,
+            Account::FeedInRevenue => Account::FeedInRevenueNegative,
+            Account::GridImportCost => Account::GridImportCostNegative,
+            Account::GridExportCost => Account::GridExportCostNegative,
+            negative => negative,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Key {
+    pub site_id: String,
+    pub currency: String,
+    pub account: Account,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    key: Key,
+    total: f64,
+}
+
+/// Money counters, accrued from the energy integrated between two readings at the
+/// price in effect. Persisted like the energy counters, by currency. Amounts at
+/// negative prices are accrued separately, see [`Account::negative`].
+pub struct Savings {
+    path: PathBuf,
+    totals: HashMap<Key, f64>,
+}
+
+impl Savings {
+    pub fn load(path: &Path) -> Self {
+        let totals = match std::fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str::<Vec<Entry>>(&content) {
+                Ok(entries) => {
+                    log::info!("Loaded savings counters from file");
+                    let mut totals = HashMap::new();
+                    for Entry { key, total } in entries {
+                        // Written before negative amounts were accrued separately
+                        let (key, total) = match total < 0.0 {
+                            true => (
+                                Key {
+                                    account: key.account.negative(),
+                                    ..key
+                                },
+                                -total,
+                            ),
+                            false => (key, total),
+                        };
+                        *totals.entry(key).or_default() += total;
+                    }
+                    totals
+                }
+                Err(err) => {
+                    log::warn!("Failed to parse savings counters from file ({path:?}): {err:?}");
+                    HashMap::new()
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                log::warn!("Failed to read savings counters from file ({path:?}): {err:?}");
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            totals,
+        }
+    }
+
+    pub fn save(&self) {
+        if self.totals.is_empty() {
+            return;
+        }
+
+        let entries: Vec<_> = self
+            .totals
+            .iter()
+            .map(|(key, total)| Entry {
+                key: key.clone(),
+                total: *total,
+            })
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+
+        match serde_json::to_string(&entries) {
+            Ok(json) => {
+                if let Err(err) =
+                    std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, &self.path))
+                {
+                    log::warn!(
+                        "Failed to write savings counters ({:?}): {err:?}",
+                        self.path
+                    );
+                }
+            }
+            Err(err) => log::warn!("Failed to serialize savings counters: {err:?}"),
+        }
+    }
+
+    pub fn totals(&self) -> impl Iterator<Item = (&Key, f64)> {
+        self.totals.iter().map(|(key, total)| (key, *total))
+    }
+
+    /// Adds the value of the energy in Wh integrated since the previous reading
+    pub fn accrue(&mut self, site_id: &str, price: &Price, wh: &HashMap<Flow, f64>) {
+        let energy = |flow| wh.get(&flow).copied().unwrap_or_default();
+        let self_consumed = (energy(Flow::HomeLoad) - energy(Flow::GridImport)).max(0.0);
+
+        let import = energy(Flow::GridImport) / 1000.0;
+        let export = energy(Flow::GridExport) / 1000.0;
+
+        for (account, kwh, unit_price) in [
+            (Account::Savings, self_consumed / 1000.0, price.import),
+            (Account::FeedInRevenue, export, price.feed_in),
+            (Account::GridImportCost, import, price.grid),
+            (Account::GridExportCost, export, price.grid),
+        ] {
+            let Some(unit_price) = unit_price else {
+                continue;
+            };
+            let account = match unit_price < 0.0 {
+                true => account.negative(),
+                false => account,
+            };
+
+            let key = Key {
+                site_id: site_id.into(),
+                currency: price.currency.clone(),
+                account,
+            };
+            *self.totals.entry(key).or_default() += kwh * unit_price.abs();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tariff(currency: &str, format: &str) -> TariffConfig {
+        serde_json::from_value(serde_json::json!({
+            "currency": currency,
+            "source": { "url": "http://prices", "format": format },
+        }))
+        .unwrap()
+    }
+
+    fn local_tariff() -> TariffConfig {
+        serde_json::from_value(serde_json::json!({
+            "currency": "EUR",
+            "price": 0.30,
+            "feed_in": 0.08,
+            "windows": [
+                { "start": "22:00", "end": "06:00", "price": 0.20 },
+                { "start": "18:00", "end": "24:00", "price": 0.40 },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn site_price(price: Option<f64>) -> data::SitePrice {
+        data::SitePrice {
+            price,
+            site_price_unit: "€".into(),
+        }
+    }
+
+    fn window(start: &str, end: &str) -> PriceWindow {
+        PriceWindow {
+            start: start.into(),
+            end: end.into(),
+            price: 0.0,
+        }
+    }
+
+    #[test]
+    fn spans_midnight_and_ends_at_24() {
+        let night = window("22:00", "06:00");
+        assert!(night.contains(22 * 60));
+        assert!(night.contains(0));
+        assert!(night.contains(6 * 60 - 1));
+        assert!(!night.contains(6 * 60));
+        assert!(!night.contains(22 * 60 - 1));
+
+        let evening = window("18:00", "24:00");
+        assert!(evening.contains(24 * 60 - 1));
+        assert!(!evening.contains(0));
+        assert!(!window("18:00", "6pm").contains(19 * 60));
+    }
+
+    #[test]
+    fn prefers_grid_then_window_then_local_then_site_price() {
+        let config = local_tariff();
+        let site = site_price(Some(0.35));
+        let import = |minute, grid| {
+            price(Some(&config), Some(&site), minute, grid)
+                .unwrap()
+                .import
+        };
+
+        assert_eq!(import(Some(23 * 60), Some(0.12)), Some(0.12));
+        assert_eq!(import(Some(23 * 60), None), Some(0.20));
+        assert_eq!(import(Some(19 * 60), None), Some(0.40));
+        assert_eq!(import(Some(12 * 60), None), Some(0.30));
+        assert_eq!(import(None, None), Some(0.30));
+
+        let without_price: TariffConfig =
+            serde_json::from_value(serde_json::json!({ "currency": "EUR" })).unwrap();
+        let price = price(Some(&without_price), Some(&site), Some(12 * 60), None).unwrap();
+        assert_eq!(
+            price,
+            Price {
+                currency: "EUR".into(),
+                import: Some(0.35),
+                feed_in: None,
+                grid: None,
+            }
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_site_price() {
+        assert_eq!(
+            price(None, Some(&site_price(Some(0.35))), Some(0), Some(0.12)),
+            Some(Price {
+                currency: "€".into(),
+                import: Some(0.35),
+                feed_in: None,
+                grid: None,
+            })
+        );
+        assert_eq!(price(None, Some(&site_price(None)), Some(0), None), None);
+        assert_eq!(price(None, None, Some(0), None), None);
+    }
+
+    #[test]
+    fn accrues_self_consumption_and_feed_in() {
+        let mut savings = Savings::load(Path::new("missing.json"));
+        let price = Price {
+            currency: "EUR".into(),
+            import: Some(0.30),
+            feed_in: Some(0.08),
+            grid: None,
+        };
+        // 3 kWh load of which 1 kWh from the grid, 0.5 kWh exported
+        let wh = HashMap::from([
+            (Flow::HomeLoad, 3000.0),
+            (Flow::GridImport, 1000.0),
+            (Flow::GridExport, 500.0),
+        ]);
+        savings.accrue("site", &price, &wh);
+        savings.accrue("site", &price, &wh);
+
+        let mut totals: Vec<_> = savings
+            .totals()
+            .map(|(key, total)| (key.account, (total * 1000.0).round() / 1000.0))
+            .collect();
+        totals.sort_by_key(|(account, _)| *account as u8);
+        assert_eq!(
+            totals,
+            [(Account::Savings, 1.2), (Account::FeedInRevenue, 0.08)]
+        );
+    }
+
+    #[test]
+    fn accrues_negative_prices_separately() {
+        let mut savings = Savings::load(Path::new("missing.json"));
+        let price = |grid| Price {
+            currency: "EUR".into(),
+            import: Some(grid),
+            feed_in: None,
+            grid: Some(grid),
+        };
+        let wh = HashMap::from([
+            (Flow::HomeLoad, 2000.0),
+            (Flow::GridImport, 1000.0),
+            (Flow::GridExport, 1000.0),
+        ]);
+        savings.accrue("site", &price(0.25), &wh);
+        savings.accrue("site", &price(-0.05), &wh);
+
+        let mut totals: Vec<_> = savings
+            .totals()
+            .map(|(key, total)| (key.account, (total * 1000.0).round() / 1000.0))
+            .collect();
+        totals.sort_by_key(|(account, _)| *account as u8);
+        assert_eq!(
+            totals,
+            [
+                (Account::Savings, 0.25),
+                (Account::GridImportCost, 0.25),
+                (Account::GridExportCost, 0.25),
+                (Account::SavingsNegative, 0.05),
+                (Account::GridImportCostNegative, 0.05),
+                (Account::GridExportCostNegative, 0.05),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_negative_totals_on_load() {
+        let path = std::env::temp_dir().join(format!("savings-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[
+                {"site_id": "site", "currency": "EUR", "account": "grid_import_cost", "total": -1.5},
+                {"site_id": "site", "currency": "EUR", "account": "grid_import_cost_negative", "total": 0.5},
+                {"site_id": "site", "currency": "EUR", "account": "savings", "total": 2.0}
+            ]"#,
+        )
+        .unwrap();
+
+        let savings = Savings::load(&path);
+        let mut totals: Vec<_> = savings
+            .totals()
+            .map(|(key, total)| (key.account, total))
+            .collect();
+        totals.sort_by_key(|(account, _)| *account as u8);
+        assert_eq!(
+            totals,
+            [
+                (Account::Savings, 2.0),
+                (Account::GridImportCostNegative, 2.0)
+            ]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn requires_eur_for_awattar() {
+        assert!(tariff("EUR", "awattar").validate().is_ok());
+        assert!(tariff("CHF", "generic").validate().is_ok());
+        assert_eq!(
+            tariff("CHF", "awattar").validate(),
+            Err("aWATTar prices are in EUR, but the tariff currency is CHF".into())
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+use serde::Deserialize;
+
+use crate::push::{self, Auth};
+
+/// Seconds before retrying a failed fetch or one not covering the current time
+const RETRY_INTERVAL: u64 = 300;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Request(#[from] push::Error),
+    #[error("Invalid prices: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid price time {0:?}")]
+    Time(String),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceFormat {
+    /// `{"prices": [{"start": "<RFC 3339>", "end": "<RFC 3339>", "price": <per kWh>}]}`
+    #[default]
+    Generic,
+    /// The market data of `https://api.awattar.de/v1/marketdata`, in EUR/MWh
+    Awattar,
+}
+
+/// Hourly prices fetched over HTTP, e.g. from a dynamic tariff
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PriceSourceConfig {
+    url: String,
+    #[serde(default)]
+    format: PriceFormat,
+    /// Seconds between fetches
+    #[serde(default = "default_refresh")]
+    refresh: u64,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Multiplies the fetched price, e.g. `1.19` for VAT
+    #[serde(default = "default_factor")]
+    factor: f64,
+    /// Added per kWh after the factor, e.g. grid fees
+    #[serde(default)]
+    markup: f64,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+impl PriceSourceConfig {
+    pub fn format(&self) -> PriceFormat {
+        self.format
+    }
+}
+
+fn default_refresh() -> u64 {
+    3600
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct GenericPrices {
+    prices: Vec<GenericPrice>,
+}
+
+#[derive(Deserialize)]
+struct GenericPrice {
+    start: String,
+    end: String,
+    price: f64,
+}
+
+#[derive(Deserialize)]
+struct AwattarPrices {
+    data: Vec<AwattarPrice>,
+}
+
+#[derive(Deserialize)]
+struct AwattarPrice {
+    /// Milliseconds since the epoch
+    start_timestamp: i64,
+    end_timestamp: i64,
+    /// EUR/MWh
+    marketprice: f64,
+}
+
+/// Price per kWh from `start` until `end`, in seconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Slot {
+    start: i64,
+    end: i64,
+    price: f64,
+}
+
+fn timestamp(time: &str) -> Result<i64, Error> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.timestamp())
+        .map_err(|_| Error::Time(time.to_string()))
+}
+
+fn parse(format: PriceFormat, body: &[u8]) -> Result<Vec<Slot>, Error> {
+    match format {
+        PriceFormat::Generic => serde_json::from_slice::<GenericPrices>(body)?
+            .prices
+            .into_iter()
+            .map(|price| {
+                Ok(Slot {
+                    start: timestamp(&price.start)?,
+                    end: timestamp(&price.end)?,
+                    price: price.price,
+                })
+            })
+            .collect(),
+        PriceFormat::Awattar => Ok(serde_json::from_slice::<AwattarPrices>(body)?
+            .data
+            .into_iter()
+            .map(|price| Slot {
+                start: price.start_timestamp / 1000,
+                end: price.end_timestamp / 1000,
+                price: price.marketprice / 1000.0,
+            })
+            .collect()),
+    }
+}
+
+/// Fetches the prices on its own thread, as the price source may be slow, keeping
+/// the previous ones while fetching fails
+struct Fetcher {
+    config: PriceSourceConfig,
+    agent: ureq::Agent,
+    slots: Arc<RwLock<Vec<Slot>>>,
+    fetched_at: Option<Instant>,
+}
+
+impl Fetcher {
+    fn fetch(&self) -> Result<Vec<Slot>, Error> {
+        let response = self
+            .config
+            .auth
+            .apply(self.agent.get(&self.config.url))
+            .call();
+        let mut response = response.map_err(|err| push::Error::Request(Box::new(err)))?;
+        let status = response.status().as_u16();
+        let body = response
+            .body_mut()
+            .read_to_vec()
+            .map_err(|err| push::Error::Request(Box::new(err)))?;
+
+        if !(200..300).contains(&status) {
+            let body = String::from_utf8_lossy(&body).into_owned();
+            return Err(push::Error::Status(status, body).into());
+        }
+
+        parse(self.config.format, &body)
+    }
+
+    /// Fetches the prices when outdated or not covering the current time
+    fn fetch_if_due(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        let covered = slot(&self.slots.read().unwrap(), now).is_some();
+        let due = self.fetched_at.is_none_or(|fetched_at| {
+            let elapsed = fetched_at.elapsed().as_secs();
+            elapsed >= self.config.refresh || (!covered && elapsed >= RETRY_INTERVAL)
+        });
+
+        if !due {
+            return;
+        }
+
+        self.fetched_at = Some(Instant::now());
+        match self.fetch() {
+            Ok(slots) => {
+                log::info!("Fetched {} prices from {}", slots.len(), self.config.url);
+                *self.slots.write().unwrap() = slots;
+            }
+            Err(err) => log::warn!("Failed to fetch prices, keeping previous ones: {err}"),
+        }
+    }
+}
+
+fn slot(slots: &[Slot], now: i64) -> Option<&Slot> {
+    slots
+        .iter()
+        .find(|slot| slot.start <= now && now < slot.end)
+}
+
+/// Prices of the source, fetched in the background until dropped
+pub struct PriceSource {
+    config: PriceSourceConfig,
+    slots: Arc<RwLock<Vec<Slot>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PriceSource {
+    pub fn new(config: &PriceSourceConfig) -> Self {
+        let slots = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut fetcher = Fetcher {
+            config: config.clone(),
+            agent: push::agent(Duration::from_secs(config.timeout)),
+            slots: Arc::clone(&slots),
+            fetched_at: None,
+        };
+        let stopped = Arc::clone(&stop);
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                fetcher.fetch_if_due();
+                thread::sleep(Duration::from_millis(200));
+            }
+        });
+
+        Self {
+            config: config.clone(),
+            slots,
+            stop,
+        }
+    }
+
+    /// Price per kWh at `now` in seconds since the epoch, from the prices fetched so
+    /// far
+    pub fn price_at(&self, now: i64) -> Option<f64> {
+        slot(&self.slots.read().unwrap(), now)
+            .map(|slot| slot.price * self.config.factor + self.config.markup)
+    }
+}
+
+impl Drop for PriceSource {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetches_prices_in_background() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/prices", server.server_addr().to_ip().unwrap());
+        let now = chrono::Utc::now().timestamp();
+        let prices = serde_json::json!({
+            "data": [{
+                "start_timestamp": (now - 60) * 1000,
+                "end_timestamp": (now + 3600) * 1000,
+                "marketprice": 100.0,
+            }],
+        });
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = tiny_http::Response::from_string(prices.to_string());
+                request.respond(response).unwrap();
+            }
+        });
+
+        let config = serde_json::json!({ "url": url, "format": "awattar", "factor": 2.0 });
+        let source = PriceSource::new(&serde_json::from_value(config).unwrap());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while source.price_at(now).is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        assert_eq!(source.price_at(now), Some(0.2));
+        assert_eq!(source.price_at(now + 7200), None);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::solix::data;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Unknown unit {0:?}")]
+pub struct UnknownUnit(String);
+
+fn factor(unit: &str, factors: &[(&str, f64)]) -> Result<f64, UnknownUnit> {
+    factors
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit.trim()))
+        .map(|(_, factor)| *factor)
+        .ok_or_else(|| UnknownUnit(unit.into()))
+}
+
+fn watts(unit: &str) -> Result<f64, UnknownUnit> {
+    factor(unit, &[("W", 1.0), ("kW", 1e3), ("MW", 1e6)])
+}
+
+fn watt_hours(unit: &str) -> Result<f64, UnknownUnit> {
+    factor(unit, &[("Wh", 1.0), ("kWh", 1e3), ("MWh", 1e6)])
+}
+
+fn grams(unit: &str) -> Result<f64, UnknownUnit> {
+    factor(unit, &[("g", 1.0), ("kg", 1e3), ("t", 1e6)])
+}
+
+/// Converts all power values to W, the total energy to Wh and the total CO2 to g.
+/// Nothing is converted if any unit is unknown. Grid, smart meter, smart plug and
+/// load powers carry no unit and are always W, the energy of smart plugs is kWh.
+pub fn normalize(scene_data: &mut data::ScenInfo) -> Result<(), UnknownUnit> {
+    let home = match &scene_data.home_info {
+        Some(home) => watts(&home.power_unit)?,
+        None => 1.0,
+    };
+    let solarbank_info = match &scene_data.solarbank_info {
+        Some(info) => watts(&info.power_unit)?,
+        None => 1.0,
+    };
+    let solarbanks = scene_data
+        .solarbanks()
+        .iter()
+        .map(|solarbank| watts(&solarbank.power_unit))
+        .collect::<Result<Vec<_>, _>>()?;
+    let energy = match scene_data.statistics.first() {
+        Some(statistic) => watt_hours(&statistic.unit)?,
+        None => 1.0,
+    };
+    let co2 = match scene_data.statistics.get(1) {
+        Some(statistic) => grams(&statistic.unit)?,
+        None => 1.0,
+    };
+
+    if let Some(home_info) = &mut scene_data.home_info {
+        home_info.charging_power *= home;
+        home_info.power_unit = "W".into();
+    }
+
+    if let Some(info) = &mut scene_data.solarbank_info {
+        normalize_solarbanks(info, solarbank_info, solarbanks);
+    }
+
+    let plugs = scene_data
+        .smart_plug_info
+        .iter_mut()
+        .flat_map(|info| &mut info.smartplug_list);
+    for plug in plugs {
+        if let Some(energy) = &mut plug.energy_today {
+            *energy *= 1e3;
+        }
+    }
+
+    if let Some(statistic) = scene_data.statistics.get_mut(0) {
+        statistic.total *= energy;
+        statistic.unit = "Wh".into();
+    }
+    if let Some(statistic) = scene_data.statistics.get_mut(1) {
+        statistic.total *= co2;
+        statistic.unit = "g".into();
+    }
+
+    Ok(())
+}
+
+fn normalize_solarbanks(info: &mut data::SolarbankInfo, factor: f64, device_factors: Vec<f64>) {
+    for value in [
+        &mut info.solar_power_1,
+        &mut info.solar_power_2,
+        &mut info.solar_power_3,
+        &mut info.solar_power_4,
+        &mut info.to_home_load,
+        &mut info.total_charging_power,
+        &mut info.total_output_power,
+        &mut info.total_photovoltaic_power,
+    ] {
+        *value *= factor;
+    }
+    for value in [&mut info.ac_power, &mut info.battery_discharge_power]
+        .into_iter()
+        .flatten()
+    {
+        *value *= factor;
+    }
+    info.power_unit = "W".into();
+
+    for (solarbank, factor) in info.solarbank_list.iter_mut().zip(device_factors) {
+        solarbank.charging_power *= factor;
+        solarbank.output_power *= factor;
+        solarbank.photovoltaic_power *= factor;
+        for value in [
+            &mut solarbank.solar_power_1,
+            &mut solarbank.solar_power_2,
+            &mut solarbank.solar_power_3,
+            &mut solarbank.solar_power_4,
+            &mut solarbank.ac_power,
+            &mut solarbank.battery_discharge_power,
+            &mut solarbank.grid_to_battery_power,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *value *= factor;
+        }
+        solarbank.power_unit = "W".into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene_data(power_unit: &str, energy_unit: &str, co2_unit: &str) -> data::ScenInfo {
+        serde_json::from_value(serde_json::json!({
+            "home_info": { "charging_power": "0.2", "power_unit": power_unit },
+            "solarbank_info": {
+                "solar_power_1": "0.1", "solar_power_2": "0",
+                "solar_power_3": "0", "solar_power_4": "0",
+                "to_home_load": "0.3", "total_battery_power": "0.5",
+                "total_charging_power": "0.2", "total_output_power": "0.3",
+                "total_photovoltaic_power": "0.5", "power_unit": power_unit,
+                "battery_discharge_power": "0.1",
+                "solarbank_list": [{
+                    "device_sn": "SB1", "battery_power": "50", "charging_power": "0.2",
+                    "output_power": "0.3", "photovoltaic_power": "0.5",
+                    "power_unit": power_unit, "solar_power_1": "0.1",
+                }],
+            },
+            "statistics": [
+                { "total": "1.5", "type": "1", "unit": energy_unit },
+                { "total": "2.5", "type": "2", "unit": co2_unit },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn converts_to_base_units() {
+        let mut scene_data = scene_data("kW", "kWh", "kg");
+        normalize(&mut scene_data).unwrap();
+
+        let home = scene_data.home_info.as_ref().unwrap();
+        assert_eq!(
+            (home.charging_power, home.power_unit.as_str()),
+            (200.0, "W")
+        );
+
+        let info = scene_data.solarbank_info.as_ref().unwrap();
+        assert_eq!(info.total_photovoltaic_power, 500.0);
+        assert_eq!(info.battery_discharge_power, Some(100.0));
+        assert_eq!(info.power_unit, "W");
+
+        let solarbank = &info.solarbank_list[0];
+        assert_eq!(solarbank.output_power, 300.0);
+        assert_eq!(solarbank.solar_power_1, Some(100.0));
+        assert_eq!(solarbank.battery_power, 50);
+        assert_eq!(solarbank.power_unit, "W");
+
+        let statistics: Vec<_> = scene_data
+            .statistics
+            .iter()
+            .map(|s| (s.total, s.unit.as_str()))
+            .collect();
+        assert_eq!(statistics, [(1500.0, "Wh"), (2500.0, "g")]);
+    }
+
+    #[test]
+    fn keeps_base_units() {
+        let mut scene_data = scene_data("W", "Wh", "g");
+        normalize(&mut scene_data).unwrap();
+
+        let info = scene_data.solarbank_info.as_ref().unwrap();
+        assert_eq!(info.total_photovoltaic_power, 0.5);
+        assert_eq!(scene_data.statistics[0].total, 1.5);
+    }
+
+    #[test]
+    fn rejects_unknown_units() {
+        let mut scene_data = scene_data("kW", "kWh", "lb");
+
+        assert_eq!(
+            normalize(&mut scene_data).unwrap_err().to_string(),
+            "Unknown unit \"lb\""
+        );
+        // Nothing is converted
+        let info = scene_data.solarbank_info.as_ref().unwrap();
+        assert_eq!(info.total_photovoltaic_power, 0.5);
+        assert_eq!(info.power_unit, "kW");
+    }
+}
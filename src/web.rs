@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tiny_http::{Header, Request, Response, ResponseBox, Server, SslConfig};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to load web config: {0}")]
+    Config(Box<figment::Error>),
+    #[error("Failed to read TLS file ({0:?}): {1}")]
+    Tls(PathBuf, std::io::Error),
+    #[error("Failed to start server: {0}")]
+    Server(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Subset of the Prometheus `web-config.yml` format, extended by `bearer_token`.
+#[derive(Deserialize, Debug, Default)]
+pub struct WebConfig {
+    #[serde(default)]
+    tls_server_config: Option<TlsServerConfig>,
+    #[serde(default)]
+    basic_auth_users: HashMap<String, String>,
+    #[serde(default)]
+    bearer_token: Option<String>,
+    /// Successfully verified basic auth credentials, as bcrypt is slow by design.
+    /// Hashed together with the bcrypt hash, so they don't outlive a changed user.
+    #[serde(skip)]
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TlsServerConfig {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+}
+
+impl WebConfig {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut config: WebConfig = Figment::new()
+            .merge(Yaml::file_exact(path))
+            .extract()
+            .map_err(|err| Error::Config(Box::new(err)))?;
+
+        // Like Prometheus, relative paths are resolved against the config file
+        if let (Some(tls), Some(dir)) = (&mut config.tls_server_config, path.parent()) {
+            tls.cert_file = dir.join(&tls.cert_file);
+            tls.key_file = dir.join(&tls.key_file);
+        }
+
+        Ok(config)
+    }
+
+    fn watched_files(&self) -> Vec<&Path> {
+        match &self.tls_server_config {
+            Some(tls) => vec![&tls.cert_file, &tls.key_file],
+            None => Vec::new(),
+        }
+    }
+
+    fn start(&self, address: SocketAddr) -> Result<Server, Error> {
+        let server = match &self.tls_server_config {
+            Some(tls) => {
+                let read = |path: &Path| fs::read(path).map_err(|err| Error::Tls(path.into(), err));
+                let ssl = SslConfig {
+                    certificate: read(&tls.cert_file)?,
+                    private_key: read(&tls.key_file)?,
+                };
+
+                Server::https(address, ssl)
+            }
+            None => Server::http(address),
+        };
+
+        server.map_err(Error::Server)
+    }
+
+    pub fn authorize(&self, request: &Request) -> bool {
+        self.authorize_header(header(request, "Authorization"))
+    }
+
+    /// Checks the value of the `Authorization` header, if any
+    fn authorize_header(&self, authorization: Option<&str>) -> bool {
+        if self.basic_auth_users.is_empty() && self.bearer_token.is_none() {
+            return true;
+        }
+
+        let Some((scheme, credentials)) =
+            authorization.and_then(|authorization| authorization.trim().split_once(' '))
+        else {
+            return false;
+        };
+        let credentials = credentials.trim_start();
+
+        // Schemes are case-insensitive, see RFC 7235
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return self.bearer_token.as_deref().is_some_and(|expected| {
+                constant_time_eq(expected.as_bytes(), credentials.as_bytes())
+            });
+        }
+
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return false;
+        }
+
+        let Some((username, password)) = base64::engine::general_purpose::STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(u, p)| (u.to_string(), p.to_string()))
+            })
+        else {
+            return false;
+        };
+
+        let Some(hash) = self.basic_auth_users.get(&username) else {
+            return false;
+        };
+
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(&username)
+            .chain_update([0])
+            .chain_update(&password)
+            .chain_update([0])
+            .chain_update(hash)
+            .finalize()
+            .into();
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+
+        let valid = bcrypt::verify(password, hash).unwrap_or_else(|err| {
+            log::warn!("Invalid bcrypt hash for user {username}: {err}");
+            false
+        });
+        if valid {
+            self.verified.lock().unwrap().insert(key);
+        }
+
+        valid
+    }
+}
+
+pub fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// HTTP(S) server which restarts itself when the web config or TLS files change.
+pub struct WebServer {
+    address: SocketAddr,
+    config_file: Option<PathBuf>,
+    config: WebConfig,
+    server: Option<Server>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl WebServer {
+    pub fn new(address: SocketAddr, config_file: Option<&Path>) -> Result<Self, Error> {
+        let config = match config_file {
+            Some(path) => WebConfig::load(path)?,
+            None => WebConfig::default(),
+        };
+
+        let server = config.start(address)?;
+
+        let mut web = Self {
+            address,
+            config_file: config_file.map(Path::to_path_buf),
+            config,
+            server: Some(server),
+            modified: Vec::new(),
+        };
+        web.modified = web.modified_times();
+
+        log::info!("Listening on {}://{address}", web.scheme());
+
+        Ok(web)
+    }
+
+    fn scheme(&self) -> &'static str {
+        match self.config.tls_server_config {
+            Some(_) => "https",
+            None => "http",
+        }
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        self.config_file
+            .iter()
+            .map(|path| path.as_path())
+            .chain(self.config.watched_files())
+            .map(modified)
+            .collect()
+    }
+
+    /// Reloads the web config and restarts the server if any watched file changed.
+    /// On failure the previous config is used again.
+    pub fn reload_if_changed(&mut self) {
+        let modified = self.modified_times();
+        if modified == self.modified && self.server.is_some() {
+            return;
+        }
+        self.modified = modified;
+
+        log::info!("Web config or TLS files changed, reloading");
+
+        let config = match &self.config_file {
+            Some(path) => match WebConfig::load(path) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::error!("{err}");
+                    return;
+                }
+            },
+            None => WebConfig::default(),
+        };
+
+        // The listener has to be released before binding the same address again, as
+        // tiny_http can't swap the TLS config of a running server. Connections are
+        // refused until the new one is bound, usually well below a second.
+        self.server = None;
+
+        match self.restart(&config) {
+            Ok(server) => {
+                self.server = Some(server);
+                self.config = config;
+                self.modified = self.modified_times();
+
+                log::info!("Listening on {}://{}", self.scheme(), self.address);
+            }
+            Err(err) => {
+                log::error!("{err}, keeping previous config");
+                match self.restart(&self.config) {
+                    Ok(server) => self.server = Some(server),
+                    Err(err) => log::error!("Failed to restart server: {err}"),
+                }
+            }
+        }
+    }
+
+    /// The old listener is released by its accept thread asynchronously, so binding
+    /// is retried for a short while.
+    fn restart(&self, config: &WebConfig) -> Result<Server, Error> {
+        let mut attempts = 0;
+
+        loop {
+            match config.start(self.address) {
+                Err(Error::Server(_)) if attempts < 20 => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn recv(&self, timeout: Duration) -> Option<Request> {
+        let Some(server) = &self.server else {
+            std::thread::sleep(timeout);
+            return None;
+        };
+
+        match server.recv_timeout(timeout) {
+            Ok(request) => request,
+            Err(err) => {
+                log::error!("Failed to receive request: {err}");
+                None
+            }
+        }
+    }
+
+    pub fn authorize(&self, request: &Request) -> bool {
+        self.config.authorize(request)
+    }
+}
+
+pub fn unauthorized() -> ResponseBox {
+    let header =
+        Header::from_bytes("WWW-Authenticate", "Basic realm=\"anker-solix-exporter\"").unwrap();
+
+    Response::empty(401).with_header(header).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WebConfig {
+        WebConfig {
+            basic_auth_users: HashMap::from([(
+                "prom".to_string(),
+                bcrypt::hash("secret", 4).unwrap(),
+            )]),
+            bearer_token: Some("token".into()),
+            ..WebConfig::default()
+        }
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn checks_basic_credentials() {
+        let config = config();
+
+        assert!(config.authorize_header(Some(&basic("prom:secret"))));
+        assert!(!config.authorize_header(Some(&basic("prom:wrong"))));
+        assert!(!config.authorize_header(Some(&basic("other:secret"))));
+        assert!(!config.authorize_header(Some(&basic("prom"))));
+        assert!(!config.authorize_header(Some("Basic !!!")));
+        assert!(!config.authorize_header(None));
+    }
+
+    #[test]
+    fn checks_bearer_token() {
+        let config = config();
+
+        assert!(config.authorize_header(Some("Bearer token")));
+        assert!(!config.authorize_header(Some("Bearer other")));
+        assert!(!config.authorize_header(Some("Bearer")));
+        assert!(!config.authorize_header(Some("Digest token")));
+    }
+
+    #[test]
+    fn accepts_any_scheme_case() {
+        let config = config();
+
+        assert!(config.authorize_header(Some(&basic("prom:secret").replace("Basic", "basic"))));
+        assert!(config.authorize_header(Some("BEARER token")));
+    }
+
+    #[test]
+    fn caches_only_the_verified_password() {
+        let config = config();
+
+        assert!(config.authorize_header(Some(&basic("prom:secret"))));
+        assert_eq!(config.verified.lock().unwrap().len(), 1);
+        assert!(config.authorize_header(Some(&basic("prom:secret"))));
+        assert!(!config.authorize_header(Some(&basic("prom:secret2"))));
+        assert_eq!(config.verified.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn allows_everything_without_auth() {
+        let config = WebConfig::default();
+
+        assert!(config.authorize_header(None));
+        assert!(config.authorize_header(Some("Bearer anything")));
+    }
+}
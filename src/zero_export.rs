@@ -0,0 +1,399 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, Transport};
+use serde::Deserialize;
+
+use crate::push::{self, Auth};
+use crate::schedule;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Request(#[from] push::Error),
+    #[error("Invalid meter response: {0}")]
+    Value(String),
+    #[error("No meter reading for {0} seconds")]
+    Stale(u64),
+}
+
+/// Source of the grid power, positive when importing
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum MeterConfig {
+    /// Polled on every control step, e.g. `http://shelly/status`
+    Http {
+        url: String,
+        /// JSON pointer to the power, e.g. `/total_power`, the whole body if empty
+        #[serde(default)]
+        pointer: String,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+        #[serde(flatten)]
+        auth: Auth,
+    },
+    /// Subscribed to, the latest message being the reading
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+        #[serde(default)]
+        pointer: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        tls: bool,
+        /// Seconds after which the latest message is considered outdated
+        #[serde(default = "default_max_age")]
+        max_age: u64,
+    },
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_max_age() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ZeroExportConfig {
+    meter: MeterConfig,
+    /// Required if the account has more than one site
+    #[serde(default)]
+    site_id: Option<String>,
+    /// The meter reports export as positive power
+    #[serde(default)]
+    invert: bool,
+    /// Seconds between control steps
+    #[serde(default = "default_interval")]
+    interval: u64,
+    /// Grid power in W to settle at, slightly positive to avoid any export
+    #[serde(default)]
+    target: f64,
+    /// Deviations in W from the target that are tolerated
+    #[serde(default = "default_deadband")]
+    deadband: f64,
+    /// Minimum seconds between writes to the Anker cloud
+    #[serde(default = "default_min_write_interval")]
+    min_write_interval: u64,
+    #[serde(default = "default_min_power")]
+    min_power: u32,
+    #[serde(default = "default_max_power")]
+    max_power: u32,
+    /// Output power in W while the meter fails, `min_power` by default
+    #[serde(default)]
+    fallback_power: Option<u32>,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_deadband() -> f64 {
+    25.0
+}
+
+fn default_min_write_interval() -> u64 {
+    60
+}
+
+fn default_min_power() -> u32 {
+    100
+}
+
+fn default_max_power() -> u32 {
+    800
+}
+
+/// Latest MQTT reading with its arrival
+type Reading = Arc<Mutex<Option<(Instant, f64)>>>;
+
+enum Meter {
+    Http(ureq::Agent),
+    Mqtt(Reading),
+}
+
+/// Extracts the power from a meter response, accepting numbers and numeric strings
+fn parse(body: &[u8], pointer: &str) -> Result<f64, Error> {
+    let json: serde_json::Value =
+        serde_json::from_slice(body).map_err(|err| Error::Value(err.to_string()))?;
+
+    match json.pointer(pointer) {
+        Some(serde_json::Value::Number(number)) => number.as_f64(),
+        Some(serde_json::Value::String(text)) => text.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| Error::Value(format!("No number at {pointer:?}")))
+}
+
+fn subscribe(config: &MeterConfig) -> Reading {
+    let reading = Reading::default();
+    let MeterConfig::Mqtt {
+        host,
+        port,
+        topic,
+        pointer,
+        username,
+        password,
+        tls,
+        ..
+    } = config.clone()
+    else {
+        return reading;
+    };
+
+    let mut options = MqttOptions::new(
+        format!("anker-solix-exporter-{}", std::process::id()),
+        host,
+        port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = username {
+        options.set_credentials(username, password.unwrap_or_default());
+    }
+    if tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+
+    let (client, mut connection) = Client::new(options, 10);
+    let latest = Arc::clone(&reading);
+
+    thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                // Subscriptions don't survive reconnects with a clean session
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if let Err(err) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+                        log::warn!("Failed to subscribe to {topic}: {err}");
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match parse(&publish.payload, &pointer) {
+                        Ok(power) => *latest.lock().unwrap() = Some((Instant::now(), power)),
+                        Err(err) => log::warn!("Ignoring meter message: {err}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Meter connection failed: {err}");
+                    thread::sleep(Duration::from_secs(5));
+                }
+            }
+        }
+    });
+
+    reading
+}
+
+/// Adjusts the Solarbank output so that the grid power measured by an external
+/// meter settles at the target. Writes are limited by the deadband and the minimum
+/// interval, as every write goes through the Anker cloud.
+pub struct Controller {
+    config: ZeroExportConfig,
+    meter: Meter,
+    written_at: Option<Instant>,
+    failing: bool,
+}
+
+impl Controller {
+    pub fn new(config: &ZeroExportConfig) -> Self {
+        let meter = match &config.meter {
+            MeterConfig::Http { timeout, .. } => {
+                Meter::Http(push::agent(Duration::from_secs(*timeout)))
+            }
+            MeterConfig::Mqtt { .. } => Meter::Mqtt(subscribe(&config.meter)),
+        };
+
+        Self {
+            config: config.clone(),
+            meter,
+            written_at: None,
+            failing: false,
+        }
+    }
+
+    pub fn site_id(&self) -> Option<&str> {
+        self.config.site_id.as_deref()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval.max(1))
+    }
+
+    /// Grid power in W, positive when importing
+    pub fn read_meter(&self) -> Result<f64, Error> {
+        let power = match (&self.meter, &self.config.meter) {
+            (
+                Meter::Http(agent),
+                MeterConfig::Http {
+                    url, pointer, auth, ..
+                },
+            ) => {
+                let response = auth.apply(agent.get(url)).call();
+                let mut response = response.map_err(|err| push::Error::Request(Box::new(err)))?;
+                let status = response.status().as_u16();
+                let body = response
+                    .body_mut()
+                    .read_to_vec()
+                    .map_err(|err| push::Error::Request(Box::new(err)))?;
+
+                if !(200..300).contains(&status) {
+                    let body = String::from_utf8_lossy(&body).into_owned();
+                    return Err(push::Error::Status(status, body).into());
+                }
+
+                parse(&body, pointer)?
+            }
+            (Meter::Mqtt(reading), MeterConfig::Mqtt { max_age, .. }) => {
+                match *reading.lock().unwrap() {
+                    Some((at, power)) if at.elapsed().as_secs() <= *max_age => power,
+                    Some((at, _)) => return Err(Error::Stale(at.elapsed().as_secs())),
+                    None => return Err(Error::Stale(*max_age)),
+                }
+            }
+            _ => unreachable!("Meter does not match its config"),
+        };
+
+        Ok(match self.config.invert {
+            true => -power,
+            false => power,
+        })
+    }
+
+    /// The output power to write given the grid power and the current output, or
+    /// `None` to keep the current output
+    pub fn decide(&mut self, grid: Result<f64, Error>, current: u32, now: Instant) -> Option<u32> {
+        let max = self.config.max_power.min(schedule::MAX_POWER);
+        let min = self.config.min_power.min(max);
+
+        let wanted = match grid {
+            Ok(grid) => {
+                if self.failing {
+                    log::info!("Meter recovered, resuming zero export control");
+                    self.failing = false;
+                }
+
+                // Only deviations beyond the deadband are corrected
+                let deviation = grid - self.config.target;
+                if deviation.abs() <= self.config.deadband {
+                    return None;
+                }
+
+                (current as f64 + deviation)
+                    .round()
+                    .clamp(min as f64, max as f64) as u32
+            }
+            Err(err) => {
+                let fallback = self.config.fallback_power.unwrap_or(min).min(max);
+                if !self.failing {
+                    log::warn!("Failed to read meter, falling back to {fallback} W: {err}");
+                    self.failing = true;
+                }
+
+                fallback
+            }
+        };
+
+        if wanted == current {
+            return None;
+        }
+
+        if let Some(written_at) = self.written_at
+            && now.duration_since(written_at).as_secs() < self.config.min_write_interval
+        {
+            log::debug!("Not changing output to {wanted} W within the minimum write interval");
+            return None;
+        }
+
+        Some(wanted)
+    }
+
+    /// Records a successful write for the minimum write interval
+    pub fn written(&mut self, now: Instant) {
+        self.written_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_config(config: serde_json::Value) -> Controller {
+        let mut config = config;
+        config["meter"] = serde_json::json!({ "source": "http", "url": "http://meter" });
+
+        Controller::new(&serde_json::from_value(config).unwrap())
+    }
+
+    #[test]
+    fn keeps_output_within_deadband() {
+        let mut controller = with_config(serde_json::json!({ "target": 10, "deadband": 25 }));
+        let now = Instant::now();
+
+        assert_eq!(controller.decide(Ok(35.0), 300, now), None);
+        assert_eq!(controller.decide(Ok(-15.0), 300, now), None);
+        assert_eq!(controller.decide(Ok(36.0), 300, now), Some(326));
+        assert_eq!(controller.decide(Ok(-16.0), 300, now), Some(274));
+    }
+
+    #[test]
+    fn clamps_output() {
+        let mut controller = with_config(serde_json::json!({ "min_power": 0, "max_power": 1000 }));
+        let now = Instant::now();
+
+        assert_eq!(controller.decide(Ok(-500.0), 200, now), Some(0));
+        assert_eq!(controller.decide(Ok(900.0), 200, now), Some(800));
+        assert_eq!(controller.decide(Ok(900.0), 800, now), None);
+
+        let mut limited = with_config(serde_json::json!({ "min_power": 150, "max_power": 600 }));
+        assert_eq!(limited.decide(Ok(-500.0), 200, now), Some(150));
+        assert_eq!(limited.decide(Ok(900.0), 200, now), Some(600));
+    }
+
+    #[test]
+    fn waits_for_min_write_interval() {
+        let mut controller = with_config(serde_json::json!({ "min_write_interval": 60 }));
+        let now = Instant::now();
+
+        assert_eq!(controller.decide(Ok(100.0), 300, now), Some(400));
+        controller.written(now);
+
+        let later = now + Duration::from_secs(59);
+        assert_eq!(controller.decide(Ok(100.0), 400, later), None);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(controller.decide(Ok(100.0), 400, later), Some(500));
+    }
+
+    #[test]
+    fn falls_back_while_meter_is_stale() {
+        let mut controller = with_config(serde_json::json!({ "fallback_power": 200 }));
+        let now = Instant::now();
+
+        assert_eq!(
+            controller.decide(Err(Error::Stale(60)), 400, now),
+            Some(200)
+        );
+        assert_eq!(controller.decide(Err(Error::Stale(70)), 200, now), None);
+        assert!(controller.failing);
+
+        assert_eq!(controller.decide(Ok(100.0), 200, now), Some(300));
+        assert!(!controller.failing);
+
+        let mut controller = with_config(serde_json::json!({ "min_power": 120 }));
+        assert_eq!(
+            controller.decide(Err(Error::Stale(60)), 400, now),
+            Some(120)
+        );
+    }
+}